@@ -0,0 +1,27 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use deez_ns::buffer::DnsBuffer;
+
+// the first byte picks where in the buffer the name starts, so pointers can go either way
+fuzz_target!(|data: &[u8]| {
+    let (start, rest) = match data.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    let mut buf = DnsBuffer::from_bytes(rest);
+    buf.pos = (*start as usize).min(rest.len());
+
+    let name = match buf.get_domain() {
+        Ok(name) => name,
+        Err(_) => return,
+    };
+    assert!(name.len() <= 253, "decoded name is {} bytes", name.len());
+    assert!(buf.pos <= rest.len());
+
+    let mut out = DnsBuffer::from_bytes(&[]);
+    if out.write_plain_domain(&name).is_ok() {
+        out.pos = 0;
+        out.get_domain().expect("written name doesnt parse");
+    }
+});
@@ -0,0 +1,21 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use deez_ns::buffer::DnsBuffer;
+use deez_ns::packet::DnsPacket;
+
+// anything that parses has to write back out, and what gets written has to parse again
+fuzz_target!(|data: &[u8]| {
+    let pack = match DnsPacket::from_buf(&mut DnsBuffer::from_bytes(data)) {
+        Ok(pack) => pack,
+        Err(_) => return,
+    };
+
+    let mut buf = DnsBuffer::from_bytes(&[]);
+    if pack.write(&mut buf).is_err() {
+        return;
+    }
+    buf.buf.truncate(buf.pos);
+    buf.pos = 0;
+    DnsPacket::from_buf(&mut buf).expect("written packet doesnt parse");
+});
@@ -1,33 +1,81 @@
-use anyhow;
+use std::collections::HashMap;
+use crate::error::{ParseError, Result};
+
+/// Highest offset a compression pointer can reach (14 bits)
+pub const MAX_JUMP_OFFSET: usize = 0x3FFF;
+/// Plain UDP message limit, without EDNS
+pub const UDP_MAX_SIZE: usize = 512;
+/// Largest message that fits the 16 bit length of a TCP frame
+pub const MAX_SIZE: usize = 65535;
+/// Compression pointers followed in one name before it counts as a loop
+pub const MAX_JUMPS: usize = 32;
 
 #[derive(Debug)]
 pub struct DnsBuffer {
-    pub buf: [u8; 512],
+    pub buf: Vec<u8>,
     pub pos: usize,
+    pub max_size: usize, // writes past this fail, buf grows up to it
+    pub canonical: bool, // names go out lowercase and never compressed, what signatures are made over
+}
+
+impl Default for DnsBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl DnsBuffer {
     pub fn new() -> DnsBuffer {
+        DnsBuffer::with_size(UDP_MAX_SIZE)
+    }
+
+    /// Zeroed buffer of `size` bytes, also used as the max size for writing
+    pub fn with_size(size: usize) -> DnsBuffer {
+        let size = size.min(MAX_SIZE);
         DnsBuffer {
-            buf: [0; 512],
+            buf: vec![0; size],
             pos: 0,
+            max_size: size,
+            canonical: false,
+        }
+    }
+
+    /// Buffer holding exactly `bytes`, writes can grow it up to MAX_SIZE
+    pub fn from_bytes(bytes: &[u8]) -> DnsBuffer {
+        DnsBuffer {
+            buf: bytes.to_vec(),
+            pos: 0,
+            max_size: MAX_SIZE,
+            canonical: false,
+        }
+    }
+
+    /// Empty buffer writing the canonical form of RFC 4034 6.2
+    pub fn canonical() -> DnsBuffer {
+        DnsBuffer {
+            canonical: true,
+            ..DnsBuffer::from_bytes(&[])
         }
     }
 
-    pub fn step(&mut self, steps: usize) -> anyhow::Result<()> {
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size.min(MAX_SIZE);
+    }
+
+    pub fn step(&mut self, steps: usize) -> Result<()> {
         self.pos += steps;
         Ok(())
     }
 
 
-    pub fn seek(&mut self, pos: usize) -> anyhow::Result<()> {
+    pub fn seek(&mut self, pos: usize) -> Result<()> {
         self.pos = pos;
         Ok(())
     }
 
-    pub fn read(&mut self) -> anyhow::Result<u8> {
-        if self.pos >= 512 {
-            return Err(anyhow::anyhow!("read error: End of buffer"));
+    pub fn read(&mut self) -> Result<u8> {
+        if self.pos >= self.buf.len() {
+            return Err(ParseError::EndOfBuffer { offset: self.pos });
         }
         let res = self.buf[self.pos];
         self.pos += 1;
@@ -35,122 +83,180 @@ impl DnsBuffer {
         Ok(res)
     }
 
-    pub fn get(&mut self, pos: usize) -> anyhow::Result<u8> {
-        if pos >= 512 {
-            return Err(anyhow::anyhow!("get error: End of buffer"));
+    pub fn get(&mut self, pos: usize) -> Result<u8> {
+        if pos >= self.buf.len() {
+            return Err(ParseError::EndOfBuffer { offset: pos });
         }
         Ok(self.buf[pos])
     }
 
     /// Get a range of bytes
-    pub fn get_range(&mut self, start: usize, len: usize) -> anyhow::Result<&[u8]> {
-        if start + len >= 512 {
-            return Err(anyhow::anyhow!("get_range error: End of buffer"));
+    pub fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
+        if start.saturating_add(len) > self.buf.len() {
+            return Err(ParseError::EndOfBuffer { offset: self.buf.len().max(start) });
         }
-        Ok(&self.buf[start..start + len as usize])
+        Ok(&self.buf[start..start + len])
     }
 
-    pub fn read_u16(&mut self) -> anyhow::Result<u16> {
+    pub fn read_u16(&mut self) -> Result<u16> {
         let res = ((self.read()? as u16) << 8) | (self.read()? as u16);
 
         Ok(res)
     }
 
-    pub fn read_u32(&mut self) -> anyhow::Result<u32> {
+    pub fn read_u32(&mut self) -> Result<u32> {
         let res = ((self.read()? as u32) << 24)
             | ((self.read()? as u32) << 16)
             | ((self.read()? as u32) << 8)
-            | ((self.read()? as u32) << 0);
+            | (self.read()? as u32);
 
         Ok(res)
     }
 
-    pub fn write(&mut self, byte: u8) -> anyhow::Result<()> {
-        if self.pos >= 512 {
-            return Err(anyhow::anyhow!("write error: end of buffer"))
+    pub fn write(&mut self, byte: u8) -> Result<()> {
+        if self.pos >= self.max_size {
+            return Err(ParseError::BufferFull { offset: self.pos });
+        }
+        if self.pos >= self.buf.len() {
+            self.buf.resize(self.pos + 1, 0);
         }
         self.buf[self.pos] = byte;
         self.pos += 1;
         Ok(())
     }
 
-    pub fn write_u16(&mut self, byte: u16) -> anyhow::Result<()> {
-        if self.pos >= 512 {
-            return Err(anyhow::anyhow!("write_u16 error: end of buffer"))
-        }
+    pub fn write_u16(&mut self, byte: u16) -> Result<()> {
         self.write((byte >> 8) as u8)?;
         self.write((byte & 0xFF) as u8)?;
         Ok(())
     }
-    pub fn write_u32(&mut self, byte: u32) -> anyhow::Result<()> {
-        if self.pos >= 512 {
-            return Err(anyhow::anyhow!("write_u32 error: end of buffer"))
-        }
+    pub fn write_u32(&mut self, byte: u32) -> Result<()> {
         // most of the 0xFF are for the pretty, think the first and secnd are necessary
         self.write(((byte >> 24) & 0xFF) as u8)?;
         self.write(((byte >> 16) & 0xFF) as u8)?;
         self.write(((byte >> 8) & 0xFF) as u8)?;
-        self.write(((byte >> 0) & 0xFF) as u8)?;
+        self.write((byte & 0xFF) as u8)?;
         Ok(())
     }
 
-    #[allow(unused_variables, unused_mut)]
-    pub fn get_domain(&mut self) -> anyhow::Result<String> {
+    /// Reads the name at `pos`, following compression pointers, and moves past it. Names come
+    /// back lowercased and dot separated, "" for the root
+    pub fn get_domain(&mut self) -> Result<String> {
+        let start = self.pos;
         let mut local_pos = self.pos;
 
         // preventing jump looping
-        let max_jumps = 5;
         let mut jump_counter = 0;
         let mut jumped = false;
 
         let mut domain_buffer = String::new();
 
         loop {
-            if jump_counter > max_jumps {
-                return Err(anyhow::anyhow!("Reaced max jump limit"));
-            }
             let len = self.get(local_pos)?;
+            // 01 and 10 in the top bits arent label types anybody uses (RFC 6891 9)
+            if (len & 0xC0) == 0x40 || (len & 0xC0) == 0x80 {
+                return Err(ParseError::BadLabelType { offset: local_pos, label: len });
+            }
             // jump requested
             if (len & 0xC0) == 0xC0 {
-                self.seek(local_pos + 2)?;
-
-                let b2 = self.get(local_pos + 1)? as u16;            
-                let offset = (((len as u16) ^ 0xC0) << 8) | b2;     
-                local_pos = offset as usize;
+                if jump_counter >= MAX_JUMPS {
+                    return Err(ParseError::PointerLoop { offset: start, jumps: MAX_JUMPS });
+                }
+                let b2 = self.get(local_pos + 1)? as u16;
+                let offset = ((((len as u16) ^ 0xC0) << 8) | b2) as usize;
+                if offset >= self.buf.len() {
+                    return Err(ParseError::BadPointer { offset: local_pos, target: offset });
+                }
 
+                if !jumped {
+                    self.seek(local_pos + 2)?;
+                }
+                local_pos = offset;
                 jumped = true;
                 jump_counter += 1;
                 continue;
-            } else {
-                local_pos += 1;
-
-                if len == 0 {
-                    break;
-                }
+            }
 
-                if !domain_buffer.is_empty() {
-                    domain_buffer.push('.');
+            local_pos += 1;
+            if len == 0 {
+                // the root name is just this byte, so pos still has to move past it
+                if !jumped {
+                    self.seek(local_pos)?;
                 }
+                break;
+            }
 
-                domain_buffer.push_str(
-                    &String::from_utf8_lossy(
-                        self.get_range(local_pos, len as usize)?
-                        ).to_lowercase()
-                    );
-
-                local_pos += len as usize;
+            if !domain_buffer.is_empty() {
+                domain_buffer.push('.');
             }
-            
-            // if no jump was perfomed, this makes sure local aligns with real pos
-            // if a jump was perfomed, then local shouldn't align with real pos and we dont try do
-            // to it
-            if !jumped {
-                self.seek(local_pos + 1)?;
+            domain_buffer.push_str(
+                &String::from_utf8_lossy(self.get_range(local_pos, len as usize)?).to_lowercase()
+            );
+            local_pos += len as usize;
+
+            // 255 on the wire, which is 2 bytes more than the dotted form
+            if domain_buffer.len() > 253 {
+                return Err(ParseError::NameTooLong { offset: start });
             }
         }
         Ok(domain_buffer)
     }
 
+    /// Writes a domain name, compressing it against the names in `jumps` unless the buffer is canonical.
+    /// Every suffix written uncompressed gets its offset recorded in `jumps` so later
+    /// names can point back at it
+    pub fn write_domain(&mut self, domain: &str, jumps: &mut HashMap<String, u16>) -> Result<()> {
+        let lowercase;
+        let mut nothing = HashMap::new();
+        let (domain, jumps) = if self.canonical {
+            lowercase = domain.to_ascii_lowercase();
+            (lowercase.as_str(), &mut nothing)
+        } else {
+            (domain, jumps)
+        };
+        let labels: Vec<&str> = domain.split('.').filter(|l| !l.is_empty()).collect();
+        // same limit get_domain holds names to, a name read in with odd bytes can come out longer
+        if labels.iter().map(|l| l.len() + 1).sum::<usize>() > 254 {
+            return Err(ParseError::NameTooLong { offset: self.pos });
+        }
+
+        for i in 0..labels.len() {
+            let suffix = labels[i..].join(".").to_lowercase();
+            if let Some(offset) = jumps.get(&suffix) {
+                self.write_u16(0xC000 | *offset)?;
+                return Ok(());
+            }
+
+            // pointers only have 14 bits, anything further in cant be jumped to
+            if self.pos <= MAX_JUMP_OFFSET {
+                jumps.insert(suffix, self.pos as u16);
+            }
+
+            let len = labels[i].len();
+            if len > 63 {
+                return Err(ParseError::LabelTooLong { offset: self.pos, len });
+            }
+            self.write(len as u8)?;
+            for byte in labels[i].as_bytes() {
+                self.write(*byte)?;
+            }
+        }
+        self.write(0)?;
+        Ok(())
+    }
 
+    /// Writes a domain name without any compression, for the places where it isnt allowed
+    pub fn write_plain_domain(&mut self, domain: &str) -> Result<()> {
+        self.write_domain(domain, &mut HashMap::new())
+    }
 
+    /// Overwrites a u16 at a position already written, used to patch lengths in after the fact
+    pub fn set_u16(&mut self, pos: usize, val: u16) -> Result<()> {
+        if pos + 1 >= self.buf.len() {
+            return Err(ParseError::EndOfBuffer { offset: pos });
+        }
+        self.buf[pos] = (val >> 8) as u8;
+        self.buf[pos + 1] = (val & 0xFF) as u8;
+        Ok(())
+    }
 }
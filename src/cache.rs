@@ -0,0 +1,356 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use crate::{buffer::DnsBuffer, header::ResultCode, packet::DnsPacket, record::{DnsRecord, RDataType}, server::MAX_CNAME_DEPTH};
+
+/// Negative answers are never kept longer than this, whatever the SOA says (RFC 2308 section 5)
+pub const MAX_NEGATIVE_TTL: u32 = 3 * 60 * 60;
+
+// NXDOMAIN is about the name and not one type, it gets cached under this type. 0 is reserved so
+// no real RRset can end up there
+const NXDOMAIN_TYPE: u16 = 0;
+
+/// A cached RRset is found by name, type and class
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub name: String, // always lowercase
+    pub rtype: u16,
+    pub class: u16,
+}
+
+impl CacheKey {
+    pub fn new(name: &str, rtype: u16, class: u16) -> CacheKey {
+        CacheKey {
+            name: name.to_lowercase(),
+            rtype,
+            class,
+        }
+    }
+
+    pub fn for_record(rec: &DnsRecord) -> Option<CacheKey> {
+        Some(CacheKey::new(rec.domain.name()?, rec.rtype.to_num(), rec.rclass.to_num()))
+    }
+}
+
+/// What the cache has to say about a question
+#[derive(Debug, Clone)]
+pub struct CachedAnswer {
+    pub rescode: ResultCode,
+    pub answers: Vec<DnsRecord>,     // the cname chain followed, then the records asked for
+    pub authorities: Vec<DnsRecord>, // the SOA when the answer is negative, and the NSEC proofs if it had any
+    pub authed_data: bool, // everything in it was validated
+}
+
+#[derive(Debug)]
+enum Data {
+    Records(Vec<DnsRecord>), // the set and then the RRSIGs over it
+    Negative(ResultCode, Vec<DnsRecord>), // NXDOMAIN or NOERROR with no data, the SOA that came with it and the proofs
+}
+
+#[derive(Debug)]
+struct Entry {
+    data: Data,
+    secure: bool, // came from a response with AD set
+    expires: Instant,
+    size: usize,
+    last_used: u64, // key into Inner::lru
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: HashMap<CacheKey, Entry>,
+    lru: BTreeMap<u64, CacheKey>, // oldest use first
+    tick: u64,
+    bytes: usize,
+}
+
+/// Shared record cache. RRsets keep the ttl they came with and are served with whatever is left
+/// of it. Once the cache gets past `max_bytes` the least recently used sets go first
+#[derive(Debug)]
+pub struct Cache {
+    inner: Mutex<Inner>,
+    max_bytes: usize,
+}
+
+impl Cache {
+    pub fn new(max_bytes: usize) -> Cache {
+        Cache {
+            inner: Mutex::new(Inner::default()),
+            max_bytes,
+        }
+    }
+
+    /// Number of RRsets in the cache, expired ones included until something touches them
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner).entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Rough memory use, the wire size of the records plus some overhead per record
+    pub fn bytes(&self) -> usize {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner).bytes
+    }
+
+    /// Stores an RRset, the records are expected to all share the key. The set lives as long as
+    /// the smallest ttl in it, sets with a ttl of 0 arent stored at all
+    pub fn insert(&self, key: CacheKey, records: Vec<DnsRecord>) {
+        self.insert_set(key, records, false);
+    }
+
+    fn insert_set(&self, key: CacheKey, records: Vec<DnsRecord>, secure: bool) {
+        let ttl = records.iter().map(|r| r.ttl.unwrap_or(0)).min().unwrap_or(0);
+        if ttl == 0 {
+            return;
+        }
+        let size = records.iter().map(approx_size).sum::<usize>() + key.name.len();
+        self.store(key, Data::Records(records), secure, ttl, size);
+    }
+
+    /// Caches the negative part of a response, NXDOMAIN or NODATA for the name the cname chain in
+    /// the answers ends at. Only done when the authority section has the SOA, it lives for the
+    /// smaller of the SOA's own ttl and its minimum field (RFC 2308 section 5). The NSEC and
+    /// RRSIG records next to the SOA are kept with it, for clients that want the proof
+    pub fn insert_negative(&self, pack: &DnsPacket) {
+        let question = match pack.questions.first() {
+            Some(q) => q,
+            None => return,
+        };
+        let (qname, qtype, class) = match question.domain.name() {
+            Some(name) => (name, question.rtype.to_num(), question.rclass.to_num()),
+            None => return,
+        };
+        let soa = pack.authorities.iter().find_map(|r| match &r.rtype {
+            RDataType::SOA(Some(soa)) => Some((r, soa)),
+            _ => None,
+        });
+        let (soa_rec, soa) = match soa {
+            Some(soa) => soa,
+            None => return,
+        };
+
+        let name = match pack.unresolved_cname(qname, qtype) {
+            Some(target) => target,
+            None if answered(pack, qname, qtype) => return,
+            None => qname.to_owned(),
+        };
+        let key = match pack.header.rescode {
+            ResultCode::NXDOMAIN => CacheKey::new(&name, NXDOMAIN_TYPE, class),
+            ResultCode::NOERROR => CacheKey::new(&name, qtype, class),
+            _ => return,
+        };
+
+        let ttl = soa_rec.ttl.unwrap_or(0).min(soa.minimum).min(MAX_NEGATIVE_TTL);
+        if ttl == 0 {
+            return;
+        }
+        let mut records = vec![soa_rec.clone()];
+        records.extend(pack.authorities.iter()
+            .filter(|r| matches!(r.rtype, RDataType::RRSIG(_) | RDataType::NSEC(_) | RDataType::NSEC3(_)))
+            .cloned());
+        let size = records.iter().map(approx_size).sum::<usize>() + key.name.len();
+        self.store(key, Data::Negative(pack.header.rescode, records), pack.header.authed_data, ttl, size);
+    }
+
+    fn store(&self, key: CacheKey, data: Data, secure: bool, ttl: u32, size: usize) {
+        if size > self.max_bytes {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner.remove(&key);
+
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.lru.insert(tick, key.clone());
+        inner.bytes += size;
+        inner.entries.insert(key, Entry {
+            data,
+            secure,
+            expires: Instant::now() + Duration::from_secs(ttl as u64),
+            size,
+            last_used: tick,
+        });
+
+        while inner.bytes > self.max_bytes {
+            let oldest = match inner.lru.first_key_value() {
+                Some((_, key)) => key.clone(),
+                None => break,
+            };
+            inner.remove(&oldest);
+        }
+    }
+
+    /// Caches the RRsets in the answer section of `pack` that are about its question, owned by
+    /// the qname or a name its cname chain goes through. Anything else in there wasnt asked for
+    /// and could be anyone's, so it stays out. Sets are validated if AD is set, and RRSIGs go in
+    /// with the set they cover so they come back out with it
+    pub fn insert_answers(&self, pack: &DnsPacket) {
+        let chain = cname_chain(pack);
+        let asked = |rec: &DnsRecord| rec.domain.name().is_some_and(|n| chain.iter().any(|c| c.eq_ignore_ascii_case(n)));
+
+        let mut rrsets: HashMap<CacheKey, Vec<DnsRecord>> = HashMap::new();
+        for rec in pack.answers.iter() {
+            if matches!(rec.rtype, RDataType::OPT(_) | RDataType::RRSIG(_)) || !asked(rec) {
+                continue;
+            }
+            if let Some(key) = CacheKey::for_record(rec) {
+                rrsets.entry(key).or_default().push(rec.clone());
+            }
+        }
+        for rec in pack.answers.iter().filter(|r| asked(r)) {
+            let covered = match (&rec.rtype, rec.domain.name()) {
+                (RDataType::RRSIG(Some(sig)), Some(name)) => CacheKey::new(name, sig.type_covered, rec.rclass.to_num()),
+                _ => continue,
+            };
+            // a signature without its set, like when RRSIGs are asked for, is a set of its own
+            match rrsets.get_mut(&covered) {
+                Some(set) => set.push(rec.clone()),
+                None => rrsets.entry(CacheKey { rtype: rec.rtype.to_num(), ..covered }).or_default().push(rec.clone()),
+            }
+        }
+        for (key, records) in rrsets {
+            self.insert_set(key, records, pack.header.authed_data);
+        }
+    }
+
+    /// The RRset with its ttls counted down to what is left, None if missing, expired or negative
+    pub fn get(&self, key: &CacheKey) -> Option<Vec<DnsRecord>> {
+        match self.get_data(key)?.0 {
+            Data::Records(records) => Some(records),
+            Data::Negative(..) => None,
+        }
+    }
+
+    // the entry with every ttl in it set to what is left, and bumped in the lru
+    fn get_data(&self, key: &CacheKey) -> Option<(Data, bool)> {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+
+        let expires = inner.entries.get(key)?.expires;
+        if expires <= now {
+            inner.remove(key);
+            return None;
+        }
+
+        inner.tick += 1;
+        let tick = inner.tick;
+        let entry = inner.entries.get_mut(key)?;
+        let old_tick = std::mem::replace(&mut entry.last_used, tick);
+        let left = expires.duration_since(now).as_secs().max(1) as u32;
+        let with_ttl = |r: &DnsRecord| DnsRecord { ttl: Some(left), ..r.clone() };
+        let data = match &entry.data {
+            Data::Records(records) => Data::Records(records.iter().map(with_ttl).collect()),
+            Data::Negative(rescode, records) => Data::Negative(*rescode, records.iter().map(with_ttl).collect()),
+        };
+
+        let secure = entry.secure;
+
+        inner.lru.remove(&old_tick);
+        inner.lru.insert(tick, key.clone());
+        Some((data, secure))
+    }
+
+    /// Answers for `name` from the cache alone, following cached cnames along the way. Only
+    /// returns something when the chain ends in records of the asked type, or in a cached
+    /// NXDOMAIN or NODATA for the last name
+    pub fn lookup(&self, name: &str, rtype: u16, class: u16) -> Option<CachedAnswer> {
+        let cname = RDataType::CNAME(None).to_num();
+        let mut chain = Vec::new();
+        let mut name = name.to_owned();
+        let mut secure = true;
+
+        for _ in 0..MAX_CNAME_DEPTH {
+            let found = self.get_data(&CacheKey::new(&name, rtype, class))
+                .or_else(|| self.get_data(&CacheKey::new(&name, NXDOMAIN_TYPE, class)));
+            match found {
+                Some((Data::Records(rrset), set_secure)) => {
+                    chain.extend(rrset);
+                    return Some(CachedAnswer {
+                        rescode: ResultCode::NOERROR,
+                        answers: chain,
+                        authorities: Vec::new(),
+                        authed_data: secure && set_secure,
+                    });
+                }
+                Some((Data::Negative(rescode, records), set_secure)) => {
+                    return Some(CachedAnswer { rescode, answers: chain, authorities: records, authed_data: secure && set_secure });
+                }
+                None => {},
+            }
+
+            let (rrset, set_secure) = match self.get_data(&CacheKey::new(&name, cname, class))? {
+                (Data::Records(rrset), set_secure) => (rrset, set_secure),
+                (Data::Negative(..), _) => return None,
+            };
+            secure &= set_secure;
+            // its RRSIGs come after it, the cname itself is still first
+            name = match rrset.first().map(|r| &r.rtype) {
+                Some(RDataType::CNAME(Some(target))) => target.clone(),
+                _ => return None,
+            };
+            chain.extend(rrset);
+        }
+        None
+    }
+
+    /// Drops everything that already expired
+    pub fn purge_expired(&self) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        let expired: Vec<CacheKey> = inner.entries.iter()
+            .filter(|(_, e)| e.expires <= now)
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in expired {
+            inner.remove(&key);
+        }
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.last_used);
+            self.bytes -= entry.size;
+        }
+    }
+}
+
+// the qname and every name its cname chain in the answers goes through, nothing without a question
+fn cname_chain(pack: &DnsPacket) -> Vec<String> {
+    let mut chain: Vec<String> = match pack.questions.first().and_then(|q| q.domain.name()) {
+        Some(qname) => vec![qname.to_owned()],
+        None => return Vec::new(),
+    };
+    for _ in 0..MAX_CNAME_DEPTH {
+        let name = &chain[chain.len() - 1];
+        let target = pack.answers.iter().find_map(|a| match (a.domain.name(), &a.rtype) {
+            (Some(owner), RDataType::CNAME(Some(target))) if owner.eq_ignore_ascii_case(name) => Some(target),
+            _ => None,
+        });
+        match target {
+            // a loop is as far as it goes
+            Some(target) if !chain.iter().any(|c| c.eq_ignore_ascii_case(target)) => chain.push(target.clone()),
+            _ => break,
+        }
+    }
+    chain
+}
+
+// if the answers have `rtype` records for `name` itself
+fn answered(pack: &DnsPacket, name: &str, rtype: u16) -> bool {
+    pack.answers.iter().any(|a| a.rtype.to_num() == rtype && a.domain.name().is_some_and(|n| n.eq_ignore_ascii_case(name)))
+}
+
+// what a record takes on the wire, plus the struct itself
+fn approx_size(rec: &DnsRecord) -> usize {
+    let mut buf = DnsBuffer::from_bytes(&[]);
+    let wire = match rec.write(&mut buf, &mut HashMap::new()) {
+        Ok(()) => buf.pos,
+        Err(_) => 512,
+    };
+    wire + std::mem::size_of::<DnsRecord>()
+}
@@ -0,0 +1,302 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::{self, Context};
+use serde::Deserialize;
+use crate::{resolver, dnssec::{self, TrustAnchor}, tls::{self, TlsUpstream}, doh::HttpsUpstream};
+
+/// Where answers come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Forward,   // ask the upstreams
+    Recursive, // walk down from the root hints ourselves
+}
+
+impl std::str::FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Mode> {
+        match s {
+            "forward" => Ok(Mode::Forward),
+            "recursive" => Ok(Mode::Recursive),
+            _ => Err(anyhow::anyhow!("has to be forward or recursive")),
+        }
+    }
+}
+
+/// A zone to answer for with authority, loaded from an RFC 1035 master file
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ZoneConfig {
+    pub origin: String,
+    pub file: PathBuf,
+    #[serde(default)]
+    pub keys: Vec<PathBuf>, // PKCS#8 private keys, the zone gets signed when there are any
+}
+
+// ORIGIN=FILE, for the --zone flag
+impl std::str::FromStr for ZoneConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<ZoneConfig> {
+        match s.split_once('=') {
+            Some((origin, file)) if !file.is_empty() => Ok(ZoneConfig {
+                origin: origin.to_owned(),
+                file: PathBuf::from(file),
+                keys: Vec::new(),
+            }),
+            _ => Err(anyhow::anyhow!("has to be ORIGIN=FILE")),
+        }
+    }
+}
+
+/// Everything the server can be told from the outside. Loaded from a toml file, then CLI flags
+/// override whatever they mention. Anything left out keeps the default
+///
+/// ```toml
+/// listen = ["0.0.0.0:53", "[::]:53"]
+/// mode = "forward"
+/// upstreams = ["127.0.0.1:5353", "8.8.8.8:53"]
+/// root_hints = ["198.41.0.4:53"]
+/// upstream_timeout_ms = 2000
+/// upstream_retries = 1
+/// tcp_idle_timeout_ms = 10000
+/// cache_max_bytes = 16777216
+/// workers = 32
+/// max_connections = 512
+/// dnssec = true
+/// trust_anchors = [". 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D"]
+/// tls_listen = ["0.0.0.0:853"]
+/// tls_cert = "tls/cert.pem"
+/// tls_key = "tls/key.pem"
+/// tls_upstreams = ["1.1.1.1:853#one.one.one.one"]
+/// tls_ca_file = "/etc/ssl/certs/ca-certificates.crt"
+/// https_listen = ["0.0.0.0:443"]
+/// https_upstreams = ["8.8.8.8:443#https://dns.google/dns-query"]
+///
+/// [[zones]]
+/// origin = "internal.example"
+/// file = "zones/internal.example.zone"
+/// keys = ["zones/internal.example.key"]
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: Vec<SocketAddr>,    // every address gets both a udp socket and a tcp listener
+    pub mode: Mode,
+    pub upstreams: Vec<SocketAddr>, // tried in order, for Mode::Forward
+    pub root_hints: Vec<SocketAddr>, // where Mode::Recursive starts, the real root servers by default
+    pub upstream_timeout_ms: u64,   // for the first try, retries wait twice as long each round
+    pub upstream_retries: usize,    // extra rounds through the upstreams before giving up with SERVFAIL
+    pub tcp_idle_timeout_ms: u64,
+    pub cache_max_bytes: usize,
+    pub workers: usize, // threads answering udp queries, most of their time goes to waiting on upstreams
+    pub max_connections: usize, // tcp and DoT connections open at once, past this new ones get closed right away
+    pub zones: Vec<ZoneConfig>, // answered from the files, before the cache or upstream are asked
+    pub dnssec: bool, // validate what comes back, bogus answers become SERVFAIL
+    pub trust_anchors: Vec<TrustAnchor>, // where validation starts, the root KSKs by default
+    pub tls_listen: Vec<SocketAddr>, // DoT listeners, only when there is a tls_cert and tls_key
+    pub tls_cert: Option<PathBuf>,   // PEM chain, leaf first
+    pub tls_key: Option<PathBuf>,    // PEM private key
+    pub tls_upstreams: Vec<TlsUpstream>, // asked over DoT, taking turns with the plain upstreams
+    pub tls_ca_file: PathBuf, // what upstream certificates get checked against
+    pub https_listen: Vec<SocketAddr>, // DoH listeners, none unless asked for. They use the tls_cert and tls_key
+    pub https_upstreams: Vec<HttpsUpstream>, // asked over DoH, also taking turns with the rest
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: vec![SocketAddr::from(([0, 0, 0, 0], 3000))],
+            mode: Mode::Forward,
+            upstreams: vec![SocketAddr::from(([8, 8, 8, 8], 53))],
+            root_hints: resolver::root_hints(),
+            upstream_timeout_ms: 2000,
+            upstream_retries: 1,
+            tcp_idle_timeout_ms: 10_000,
+            cache_max_bytes: 16 * 1024 * 1024,
+            workers: 32,
+            max_connections: 512,
+            zones: Vec::new(),
+            dnssec: false,
+            trust_anchors: dnssec::root_anchors(),
+            tls_listen: vec![SocketAddr::from(([0, 0, 0, 0], tls::DOT_PORT))],
+            tls_cert: None,
+            tls_key: None,
+            tls_upstreams: Vec::new(),
+            tls_ca_file: PathBuf::from(tls::SYSTEM_CA_FILE),
+            https_listen: Vec::new(),
+            https_upstreams: Vec::new(),
+        }
+    }
+}
+
+const USAGE: &str = "usage: deez_ns [--config FILE] [--listen ADDR:PORT]... [--mode forward|recursive] \
+[--upstream ADDR:PORT]... [--root-hint ADDR:PORT]... [--upstream-timeout-ms MS] [--upstream-retries N] [--tcp-idle-timeout-ms MS] [--cache-max-bytes BYTES] [--workers N] [--max-connections N] [--zone ORIGIN=FILE]... \
+[--zone-key ORIGIN=KEYFILE]... [--dnssec true|false] [--trust-anchor \"ZONE KEYTAG ALGORITHM DIGESTTYPE DIGEST\"]... \
+[--tls-listen ADDR:PORT]... [--tls-cert FILE] [--tls-key FILE] [--tls-upstream ADDR[:PORT]#NAME]... [--tls-ca-file FILE] \
+[--https-listen ADDR:PORT]... [--https-upstream ADDR[:PORT]#URL]...";
+
+impl ServerConfig {
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<ServerConfig> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("config error: couldnt read {}", path.display()))?;
+        ServerConfig::from_toml(&text)
+            .with_context(|| format!("config error: bad config in {}", path.display()))
+    }
+
+    pub fn from_toml(text: &str) -> anyhow::Result<ServerConfig> {
+        let config: ServerConfig = toml::from_str(text)?;
+        config.check()?;
+        Ok(config)
+    }
+
+    /// Builds the config from the arguments, without the program name. `--config` is read first
+    /// wherever it is, then the other flags go on top. `--listen`, `--upstream`, `--root-hint`, `--zone`, `--trust-anchor`,
+    /// `--tls-listen`, `--tls-upstream`, `--https-listen` and `--https-upstream` can be repeated, and when given they replace
+    /// the lists from the file instead of adding to them. Any kind of upstream replaces all of them. `--zone-key` adds a key to the zone with that origin
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> anyhow::Result<ServerConfig> {
+        let args: Vec<String> = args.into_iter().collect();
+
+        let mut flags = Vec::new();
+        let mut config_path = None;
+        let mut iter = args.iter();
+        while let Some(flag) = iter.next() {
+            let value = iter.next().ok_or_else(|| anyhow::anyhow!("config error: {} needs a value\n{}", flag, USAGE))?;
+            if flag == "--config" {
+                config_path = Some(value);
+            } else {
+                flags.push((flag.as_str(), value.as_str()));
+            }
+        }
+
+        let mut config = match config_path {
+            Some(path) => ServerConfig::from_file(path)?,
+            None => ServerConfig::default(),
+        };
+
+        let mut listen = Vec::new();
+        let mut upstreams = Vec::new();
+        let mut root_hints = Vec::new();
+        let mut zones = Vec::new();
+        let mut trust_anchors = Vec::new();
+        let mut zone_keys = Vec::new();
+        let mut tls_listen = Vec::new();
+        let mut tls_upstreams = Vec::new();
+        let mut https_listen = Vec::new();
+        let mut https_upstreams = Vec::new();
+        for (flag, value) in flags {
+            match flag {
+                "--listen" => listen.push(parse_flag(flag, value)?),
+                "--upstream" => upstreams.push(parse_flag(flag, value)?),
+                "--root-hint" => root_hints.push(parse_flag(flag, value)?),
+                "--zone" => zones.push(parse_flag(flag, value)?),
+                "--trust-anchor" => trust_anchors.push(parse_flag(flag, value)?),
+                "--zone-key" => match value.split_once('=') {
+                    Some((origin, file)) if !file.is_empty() => zone_keys.push((origin, PathBuf::from(file))),
+                    _ => return Err(anyhow::anyhow!("config error: bad value {:?} for {}: has to be ORIGIN=KEYFILE", value, flag)),
+                },
+                "--mode" => config.mode = parse_flag(flag, value)?,
+                "--upstream-timeout-ms" => config.upstream_timeout_ms = parse_flag(flag, value)?,
+                "--upstream-retries" => config.upstream_retries = parse_flag(flag, value)?,
+                "--tcp-idle-timeout-ms" => config.tcp_idle_timeout_ms = parse_flag(flag, value)?,
+                "--cache-max-bytes" => config.cache_max_bytes = parse_flag(flag, value)?,
+                "--workers" => config.workers = parse_flag(flag, value)?,
+                "--max-connections" => config.max_connections = parse_flag(flag, value)?,
+                "--dnssec" => config.dnssec = parse_flag(flag, value)?,
+                "--tls-listen" => tls_listen.push(parse_flag(flag, value)?),
+                "--tls-cert" => config.tls_cert = Some(parse_flag(flag, value)?),
+                "--tls-key" => config.tls_key = Some(parse_flag(flag, value)?),
+                "--tls-upstream" => tls_upstreams.push(parse_flag(flag, value)?),
+                "--tls-ca-file" => config.tls_ca_file = parse_flag(flag, value)?,
+                "--https-listen" => https_listen.push(parse_flag(flag, value)?),
+                "--https-upstream" => https_upstreams.push(parse_flag(flag, value)?),
+                _ => return Err(anyhow::anyhow!("config error: unknown flag {}\n{}", flag, USAGE)),
+            }
+        }
+        if !listen.is_empty() {
+            config.listen = listen;
+        }
+        // plain ones in the file dont get to stay when the flags only ask for tls or https
+        if !upstreams.is_empty() || !tls_upstreams.is_empty() || !https_upstreams.is_empty() {
+            config.upstreams = upstreams;
+            config.tls_upstreams = tls_upstreams;
+            config.https_upstreams = https_upstreams;
+        }
+        if !tls_listen.is_empty() {
+            config.tls_listen = tls_listen;
+        }
+        if !https_listen.is_empty() {
+            config.https_listen = https_listen;
+        }
+        if !root_hints.is_empty() {
+            config.root_hints = root_hints;
+        }
+        if !zones.is_empty() {
+            config.zones = zones;
+        }
+        // keys go with zones, wherever those came from
+        for (origin, file) in zone_keys {
+            let same = |z: &&mut ZoneConfig| z.origin.trim_end_matches('.').eq_ignore_ascii_case(origin.trim_end_matches('.'));
+            match config.zones.iter_mut().find(same) {
+                Some(zone) => zone.keys.push(file),
+                None => return Err(anyhow::anyhow!("config error: --zone-key for {:?}, which isnt a zone", origin)),
+            }
+        }
+        if !trust_anchors.is_empty() {
+            config.trust_anchors = trust_anchors;
+        }
+
+        config.check()?;
+        Ok(config)
+    }
+
+    pub fn upstream_timeout(&self) -> Duration {
+        Duration::from_millis(self.upstream_timeout_ms)
+    }
+
+    pub fn tcp_idle_timeout(&self) -> Duration {
+        Duration::from_millis(self.tcp_idle_timeout_ms)
+    }
+
+    fn check(&self) -> anyhow::Result<()> {
+        if self.listen.is_empty() {
+            return Err(anyhow::anyhow!("config error: nothing to listen on"));
+        }
+        if self.mode == Mode::Forward && self.upstreams.is_empty() && self.tls_upstreams.is_empty() && self.https_upstreams.is_empty() {
+            return Err(anyhow::anyhow!("config error: no upstreams"));
+        }
+        if self.mode == Mode::Recursive && self.root_hints.is_empty() {
+            return Err(anyhow::anyhow!("config error: no root hints"));
+        }
+        // a zero timeout means blocking forever for the std sockets, which is exactly what isnt wanted
+        if self.upstream_timeout_ms == 0 || self.tcp_idle_timeout_ms == 0 {
+            return Err(anyhow::anyhow!("config error: timeouts have to be above 0"));
+        }
+        if self.workers == 0 {
+            return Err(anyhow::anyhow!("config error: needs at least one worker"));
+        }
+        if self.max_connections == 0 {
+            return Err(anyhow::anyhow!("config error: max_connections has to be above 0"));
+        }
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err(anyhow::anyhow!("config error: tls needs both a certificate and a key"));
+        }
+        if !self.https_listen.is_empty() && self.tls_cert.is_none() {
+            return Err(anyhow::anyhow!("config error: https_listen needs a tls_cert and tls_key"));
+        }
+        if self.dnssec && self.trust_anchors.is_empty() {
+            return Err(anyhow::anyhow!("config error: dnssec needs at least one trust anchor"));
+        }
+        Ok(())
+    }
+}
+
+fn parse_flag<T: std::str::FromStr>(flag: &str, value: &str) -> anyhow::Result<T>
+where
+    T::Err: std::fmt::Display,
+{
+    value.parse().map_err(|e| anyhow::anyhow!("config error: bad value {:?} for {}: {}", value, flag, e))
+}
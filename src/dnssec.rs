@@ -0,0 +1,847 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use anyhow;
+use serde::Deserialize;
+use ring::{digest, signature};
+use crate::{buffer::DnsBuffer, packet::DnsPacket, header::ResultCode, encoding, resolver::in_zone,
+    record::{self, DnsRecord, Domain, RClass, RDataType, DnskeyData, DsData, RrsigData, NsecData, Nsec3Data}};
+
+pub const RSASHA256: u8 = 8;
+pub const ECDSAP256SHA256: u8 = 13;
+pub const ECDSAP384SHA384: u8 = 14;
+pub const ED25519: u8 = 15;
+
+pub const DIGEST_SHA1: u8 = 1;
+pub const DIGEST_SHA256: u8 = 2;
+pub const DIGEST_SHA384: u8 = 4;
+
+/// The only NSEC3 hash there is
+pub const NSEC3_SHA1: u8 = 1;
+/// NSEC3 chains with more iterations than this are treated as unsigned (RFC 9276 3.2)
+pub const MAX_NSEC3_ITERATIONS: u16 = 150;
+
+/// DNSKEY flag bits
+pub const ZONE_KEY: u16 = 0x0100;
+pub const SECURE_ENTRY_POINT: u16 = 0x0001;
+
+/// How many zone cuts a chain of trust can go through
+pub const MAX_CHAIN_DEPTH: usize = 16;
+/// Validated keys are kept at most this long, whatever their ttl
+pub const MAX_KEY_TTL: u32 = 24 * 60 * 60;
+/// How many zones the validator remembers the keys of, the ones closest to expiring go first
+pub const MAX_ZONES: usize = 10_000;
+
+const NSEC3_OPT_OUT: u8 = 0x01;
+
+/// A DS we trust without asking anybody, where every chain of trust has to end
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TrustAnchor {
+    pub zone: String, // lowercase without the trailing dot, "" for the root
+    pub ds: DsData,
+}
+
+/// The root KSKs as published by IANA, 2017 and 2024
+pub fn root_anchors() -> Vec<TrustAnchor> {
+    [
+        ". 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D",
+        ". 38696 8 2 683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16",
+    ].iter().map(|a| a.parse().unwrap()).collect()
+}
+
+// ZONE KEYTAG ALGORITHM DIGESTTYPE DIGEST, the DS record without owner ttl and type
+impl std::str::FromStr for TrustAnchor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<TrustAnchor> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        if words.len() < 5 {
+            return Err(anyhow::anyhow!("has to be ZONE KEYTAG ALGORITHM DIGESTTYPE DIGEST"));
+        }
+        let digest = encoding::hex_decode(&words[4..].concat())
+            .ok_or_else(|| anyhow::anyhow!("bad hex in the digest"))?;
+        Ok(TrustAnchor {
+            zone: words[0].trim_end_matches('.').to_lowercase(),
+            ds: DsData {
+                key_tag: words[1].parse()?,
+                algorithm: words[2].parse()?,
+                digest_type: words[3].parse()?,
+                digest,
+            },
+        })
+    }
+}
+
+impl TryFrom<String> for TrustAnchor {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<TrustAnchor> {
+        s.parse()
+    }
+}
+
+/// What validation made of a response (RFC 4035 4.3)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Security {
+    Secure,
+    Insecure, // no chain of trust covers it, so there is nothing to check
+    Bogus(String), // should have been signed and isnt, or the signatures dont check out
+}
+
+impl Security {
+    // the weakest of the two, bogus beats insecure beats secure
+    fn and(self, other: Security) -> Security {
+        match (self, other) {
+            (Security::Bogus(why), _) | (_, Security::Bogus(why)) => Security::Bogus(why),
+            (Security::Insecure, _) | (_, Security::Insecure) => Security::Insecure,
+            _ => Security::Secure,
+        }
+    }
+}
+
+/// How the validator gets DS and DNSKEY sets, it has to ask with the DO bit
+pub type Fetch<'a> = dyn Fn(&DnsRecord) -> anyhow::Result<DnsPacket> + 'a;
+
+#[derive(Debug, Clone)]
+enum ZoneKeys {
+    Secure(Vec<DnskeyData>),
+    Insecure,
+}
+
+/// Validating part of the resolver. It builds chains of trust down from the anchors through DS
+/// and DNSKEY and remembers the zones it already went through
+#[derive(Debug)]
+pub struct Validator {
+    anchors: Vec<TrustAnchor>,
+    zones: Mutex<HashMap<String, (ZoneKeys, Instant)>>,
+}
+
+impl Validator {
+    pub fn new(anchors: Vec<TrustAnchor>) -> Validator {
+        Validator {
+            anchors,
+            zones: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks every RRset of the answer section, and the NSEC or NSEC3 proofs in the authority
+    /// section when the answer is negative or came from a wildcard. `fetch` is used for the DS
+    /// and DNSKEY sets of the zones on the way
+    pub fn validate(&self, res: &DnsPacket, fetch: &Fetch) -> Security {
+        let (qname, qtype) = match res.questions.first().and_then(|q| Some((q.domain.name()?, q.rtype.to_num()))) {
+            Some(q) => q,
+            None => return Security::Insecure,
+        };
+        if !self.anchors.iter().any(|a| in_zone(qname, &a.zone)) {
+            return Security::Insecure;
+        }
+        let now = unix_now();
+
+        let mut security = Security::Secure;
+        let mut wildcards = Vec::new();
+        for (rrset, sigs) in rrsets(&res.answers) {
+            let (checked, expanded) = self.check_rrset(&rrset, &sigs, fetch, now);
+            security = security.and(checked);
+            wildcards.extend(expanded);
+        }
+
+        // the end of the cname chain, where there is either data or a proof there is none
+        let target = res.unresolved_cname(qname, qtype).unwrap_or_else(|| qname.to_owned());
+        let answered = res.answers.iter().any(|a| !matches!(a.rtype, RDataType::RRSIG(_)) && owner_is(a, &target));
+        let negative = res.header.rescode == ResultCode::NXDOMAIN || !answered;
+        if !negative && wildcards.is_empty() {
+            return security;
+        }
+
+        // the proofs are only worth something once their own signatures check out
+        let mut proofs = Vec::new();
+        for (rrset, sigs) in rrsets(&res.authorities) {
+            if !matches!(rrset[0].rtype, RDataType::SOA(_) | RDataType::NSEC(_) | RDataType::NSEC3(_)) {
+                continue;
+            }
+            let (checked, _) = self.check_rrset(&rrset, &sigs, fetch, now);
+            if checked == Security::Secure {
+                proofs.extend(rrset);
+            }
+            security = security.and(checked);
+        }
+        if security != Security::Secure {
+            return security;
+        }
+
+        let denial = Denial::new(&proofs);
+        for (name, closest_encloser) in wildcards {
+            if !denial.no_closer_match(&name, &closest_encloser) {
+                return Security::Bogus(format!("wildcard answer for {} without proof there is nothing closer", name));
+            }
+        }
+        if negative {
+            if denial.is_empty() {
+                return match self.zone_security(&target, fetch) {
+                    Security::Secure => Security::Bogus(format!("negative answer for {} without NSEC or NSEC3", target)),
+                    other => other,
+                };
+            }
+            let nxdomain = res.header.rescode == ResultCode::NXDOMAIN;
+            return denial.denies(&target, qtype, nxdomain);
+        }
+        security
+    }
+
+    // an RRset with its signatures, and where it came from a wildcard the name and the wildcard's
+    // closest encloser. Unsigned sets are fine where the chain of trust says the zone is unsigned
+    fn check_rrset(&self, rrset: &[DnsRecord], sigs: &[RrsigData], fetch: &Fetch, now: u32) -> (Security, Option<(String, String)>) {
+        let owner = rrset[0].domain.name().unwrap_or("").to_lowercase();
+        let what = format!("{} {}", owner, record::type_name(rrset[0].rtype.to_num()));
+
+        let signer = match sigs.iter().find(|s| in_zone(&owner, &s.signer_name)) {
+            Some(sig) => sig.signer_name.to_lowercase(),
+            None => {
+                return match self.zone_security(&owner, fetch) {
+                    Security::Secure => (Security::Bogus(format!("{} isnt signed", what)), None),
+                    other => (other, None),
+                };
+            }
+        };
+        let keys = match self.zone_keys(&signer, fetch, 0) {
+            Ok(ZoneKeys::Secure(keys)) => keys,
+            Ok(ZoneKeys::Insecure) => return (Security::Insecure, None),
+            Err(why) => return (Security::Bogus(why), None),
+        };
+        let sig = match verify_rrset(rrset, sigs, &keys, now) {
+            Ok(sig) => sig,
+            Err(why) => return (Security::Bogus(why), None),
+        };
+
+        // fewer labels in the signature than in the owner means the owner came out of a wildcard
+        let labels: Vec<&str> = owner.split('.').filter(|l| !l.is_empty()).collect();
+        if (sig.labels as usize) < label_count(&owner) {
+            let closest_encloser = labels[labels.len() - sig.labels as usize..].join(".");
+            return (Security::Secure, Some((owner, closest_encloser)));
+        }
+        (Security::Secure, None)
+    }
+
+    // the validated keys of `zone`, going up through the DS sets until an anchor is reached
+    fn zone_keys(&self, zone: &str, fetch: &Fetch, depth: usize) -> Result<ZoneKeys, String> {
+        if let Some(keys) = self.cached(zone) {
+            return Ok(keys);
+        }
+        if depth > MAX_CHAIN_DEPTH {
+            return Err(format!("chain of trust for {:?} is too long", zone));
+        }
+        let now = unix_now();
+
+        let anchored: Vec<DsData> = self.anchors.iter().filter(|a| a.zone == zone).map(|a| a.ds.clone()).collect();
+        let mut ttl = MAX_KEY_TTL;
+        let ds_set = if !anchored.is_empty() {
+            anchored
+        } else {
+            if !self.anchors.iter().any(|a| in_zone(zone, &a.zone)) {
+                return Ok(ZoneKeys::Insecure);
+            }
+            let res = fetch(&question(zone, RDataType::DS(None))).map_err(|e| format!("couldnt get the DS of {:?}: {:#}", zone, e))?;
+            let sets = rrsets(&res.answers);
+            let (ds_records, sigs) = match sets.into_iter().find(|(set, _)| matches!(set[0].rtype, RDataType::DS(_)) && owner_is(&set[0], zone)) {
+                Some(set) => set,
+                None => {
+                    self.no_ds(zone, &res, fetch, depth)?;
+                    self.remember(zone, ZoneKeys::Insecure, min_ttl(&res.authorities));
+                    return Ok(ZoneKeys::Insecure);
+                }
+            };
+
+            let signer = match sigs.iter().find(|s| s.signer_name != zone && in_zone(zone, &s.signer_name)) {
+                Some(sig) => sig.signer_name.to_lowercase(),
+                None => return Err(format!("DS of {:?} isnt signed by its parent", zone)),
+            };
+            let parent_keys = match self.zone_keys(&signer, fetch, depth + 1)? {
+                ZoneKeys::Secure(keys) => keys,
+                ZoneKeys::Insecure => return Ok(ZoneKeys::Insecure),
+            };
+            verify_rrset(&ds_records, &sigs, &parent_keys, now)?;
+            ttl = ttl.min(min_ttl(&ds_records));
+            ds_records.iter().filter_map(|r| match &r.rtype {
+                RDataType::DS(Some(ds)) => Some(ds.clone()),
+                _ => None,
+            }).collect()
+        };
+
+        // a DS we cant use is as good as none at all (RFC 4035 5.2)
+        let usable: Vec<&DsData> = ds_set.iter().filter(|ds| supported_algorithm(ds.algorithm) && supported_digest(ds.digest_type)).collect();
+        if usable.is_empty() {
+            self.remember(zone, ZoneKeys::Insecure, ttl);
+            return Ok(ZoneKeys::Insecure);
+        }
+
+        let res = fetch(&question(zone, RDataType::DNSKEY(None))).map_err(|e| format!("couldnt get the DNSKEY of {:?}: {:#}", zone, e))?;
+        let (key_records, sigs) = rrsets(&res.answers).into_iter()
+            .find(|(set, _)| matches!(set[0].rtype, RDataType::DNSKEY(_)) && owner_is(&set[0], zone))
+            .ok_or_else(|| format!("{:?} has a DS but no DNSKEY", zone))?;
+        let keys: Vec<DnskeyData> = key_records.iter().filter_map(|r| match &r.rtype {
+            RDataType::DNSKEY(Some(key)) => Some(key.clone()),
+            _ => None,
+        }).collect();
+
+        let entry: Vec<DnskeyData> = keys.iter()
+            .filter(|k| usable.iter().any(|ds| matches_ds(zone, k, ds)))
+            .cloned()
+            .collect();
+        if entry.is_empty() {
+            return Err(format!("no DNSKEY of {:?} matches its DS", zone));
+        }
+        verify_rrset(&key_records, &sigs, &entry, now)?;
+
+        let keys = ZoneKeys::Secure(keys.into_iter().filter(|k| k.flags & ZONE_KEY != 0 && k.protocol == 3).collect());
+        self.remember(zone, keys.clone(), ttl.min(min_ttl(&key_records)));
+        Ok(keys)
+    }
+
+    // Ok when the response proves `zone` is delegated without a DS, an insecure delegation
+    fn no_ds(&self, zone: &str, res: &DnsPacket, fetch: &Fetch, depth: usize) -> Result<(), String> {
+        let now = unix_now();
+        // nothing signed at all is fine as long as something above already isnt
+        if !res.authorities.iter().any(|r| matches!(r.rtype, RDataType::RRSIG(_))) {
+            let parent = zone.split_once('.').map_or("", |(_, parent)| parent);
+            return match self.zone_security(parent, fetch) {
+                Security::Insecure => Ok(()),
+                Security::Secure => Err(format!("no proof that {:?} has no DS", zone)),
+                Security::Bogus(why) => Err(why),
+            };
+        }
+        let mut proofs = Vec::new();
+        for (rrset, sigs) in rrsets(&res.authorities) {
+            if !matches!(rrset[0].rtype, RDataType::NSEC(_) | RDataType::NSEC3(_)) {
+                continue;
+            }
+            let signer = match sigs.iter().find(|s| s.signer_name != zone && in_zone(zone, &s.signer_name)) {
+                Some(sig) => sig.signer_name.to_lowercase(),
+                None => return Err(format!("denial of the DS of {:?} isnt signed by its parent", zone)),
+            };
+            match self.zone_keys(&signer, fetch, depth + 1)? {
+                ZoneKeys::Secure(keys) => verify_rrset(&rrset, &sigs, &keys, now)?,
+                // the parent isnt signed either
+                ZoneKeys::Insecure => return Ok(()),
+            };
+            proofs.extend(rrset);
+        }
+        match Denial::new(&proofs).unsigned_delegation(zone) {
+            true => Ok(()),
+            false => Err(format!("no proof that {:?} has no DS", zone)),
+        }
+    }
+
+    // for unsigned data, if it sits under a signed zone that it should have been signed by. Walks
+    // down from the anchor looking for the delegation that makes it insecure
+    fn zone_security(&self, name: &str, fetch: &Fetch) -> Security {
+        let anchor = match self.anchors.iter().filter(|a| in_zone(name, &a.zone)).max_by_key(|a| a.zone.len()) {
+            Some(anchor) => anchor.zone.clone(),
+            None => return Security::Insecure,
+        };
+        match self.zone_keys(&anchor, fetch, 0) {
+            Ok(ZoneKeys::Secure(_)) => {},
+            Ok(ZoneKeys::Insecure) => return Security::Insecure,
+            Err(why) => return Security::Bogus(why),
+        }
+
+        let labels: Vec<&str> = name.split('.').filter(|l| !l.is_empty()).collect();
+        let below = labels.len() - label_count(&anchor);
+        for i in (0..below).rev() {
+            let zone = labels[i..].join(".");
+            // names that arent zone cuts dont prove anything either way, they get skipped
+            if let Ok(ZoneKeys::Insecure) = self.zone_keys(&zone, fetch, 0) {
+                return Security::Insecure;
+            }
+        }
+        Security::Secure
+    }
+
+    /// Number of zones with keys remembered, expired ones included until they get cleaned out
+    pub fn len(&self) -> usize {
+        self.zones.lock().unwrap_or_else(PoisonError::into_inner).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn cached(&self, zone: &str) -> Option<ZoneKeys> {
+        let mut zones = self.zones.lock().unwrap_or_else(PoisonError::into_inner);
+        match zones.get(zone) {
+            Some((keys, expires)) if *expires > Instant::now() => Some(keys.clone()),
+            Some(_) => {
+                zones.remove(zone);
+                None
+            }
+            None => None,
+        }
+    }
+
+    // keys live as long as the smallest DS or DNSKEY ttl on the way to them. A full map is
+    // cleaned of what expired first, and if that wasnt enough whatever expires soonest goes
+    fn remember(&self, zone: &str, keys: ZoneKeys, ttl: u32) {
+        let now = Instant::now();
+        let expires = now + Duration::from_secs(ttl.min(MAX_KEY_TTL) as u64);
+        let mut zones = self.zones.lock().unwrap_or_else(PoisonError::into_inner);
+        if zones.len() >= MAX_ZONES && !zones.contains_key(zone) {
+            zones.retain(|_, (_, expires)| *expires > now);
+        }
+        if zones.len() >= MAX_ZONES && !zones.contains_key(zone) {
+            let soonest = zones.iter().min_by_key(|(_, (_, expires))| *expires).map(|(z, _)| z.clone());
+            if let Some(soonest) = soonest {
+                zones.remove(&soonest);
+            }
+        }
+        zones.insert(zone.to_owned(), (keys, expires));
+    }
+}
+
+// the NSEC and NSEC3 records of a response, already validated
+struct Denial<'a> {
+    nsecs: Vec<(&'a str, &'a NsecData)>,
+    nsec3s: Vec<(&'a str, &'a Nsec3Data)>,
+}
+
+impl<'a> Denial<'a> {
+    fn new(records: &'a [DnsRecord]) -> Denial<'a> {
+        let mut denial = Denial { nsecs: Vec::new(), nsec3s: Vec::new() };
+        for rec in records {
+            match (&rec.rtype, rec.domain.name()) {
+                (RDataType::NSEC(Some(nsec)), Some(owner)) => denial.nsecs.push((owner, nsec)),
+                (RDataType::NSEC3(Some(nsec3)), Some(owner)) => denial.nsec3s.push((owner, nsec3)),
+                _ => {},
+            }
+        }
+        denial
+    }
+
+    fn is_empty(&self) -> bool {
+        self.nsecs.is_empty() && self.nsec3s.is_empty()
+    }
+
+    // NXDOMAIN or NODATA for `name` (RFC 4035 5.4 and RFC 5155 8)
+    fn denies(&self, name: &str, qtype: u16, nxdomain: bool) -> Security {
+        if self.nsec3s.iter().any(|(_, n)| n.iterations > MAX_NSEC3_ITERATIONS || n.hash_algorithm != NSEC3_SHA1) {
+            return Security::Insecure;
+        }
+        let proven = if nxdomain {
+            self.nsec_nxdomain(name) || self.nsec3_nxdomain(name)
+        } else {
+            self.nsec_nodata(name, qtype) || self.nsec3_nodata(name, qtype)
+        };
+        match proven {
+            true => Security::Secure,
+            false => Security::Bogus(format!("no proof that {} {} doesnt exist", name, record::type_name(qtype))),
+        }
+    }
+
+    fn nsec_nxdomain(&self, name: &str) -> bool {
+        let covering = match self.nsecs.iter().find(|(owner, nsec)| covers(owner, &nsec.next_domain, name)) {
+            Some(nsec) => nsec,
+            None => return false,
+        };
+        let closest_encloser = closest_encloser_of(name, covering.0, &covering.1.next_domain);
+        let wildcard = wildcard_at(&closest_encloser);
+        self.nsecs.iter().any(|(owner, nsec)| covers(owner, &nsec.next_domain, &wildcard))
+    }
+
+    fn nsec_nodata(&self, name: &str, qtype: u16) -> bool {
+        let lacks = |nsec: &NsecData| {
+            !nsec.types.contains(&qtype) && !nsec.types.contains(&CNAME)
+                // the parent side of a delegation only speaks for the DS
+                && (qtype == DS || !nsec.types.contains(&NS) || nsec.types.contains(&SOA))
+        };
+        if self.nsecs.iter().any(|(owner, nsec)| owner.eq_ignore_ascii_case(name) && lacks(nsec)) {
+            return true;
+        }
+        // or the name came from a wildcard that doesnt have the type either
+        let covering = match self.nsecs.iter().find(|(owner, nsec)| covers(owner, &nsec.next_domain, name)) {
+            Some(nsec) => nsec,
+            None => return false,
+        };
+        // an empty non-terminal has no NSEC of its own, the one covering it points under it
+        let next = &covering.1.next_domain;
+        if in_zone(next, name) && !next.eq_ignore_ascii_case(name) {
+            return true;
+        }
+        let wildcard = wildcard_at(&closest_encloser_of(name, covering.0, next));
+        self.nsecs.iter().any(|(owner, nsec)| owner.eq_ignore_ascii_case(&wildcard) && lacks(nsec))
+    }
+
+    fn nsec3_nxdomain(&self, name: &str) -> bool {
+        match self.closest_encloser_proof(name) {
+            Some((closest_encloser, _)) => self.nsec3_covering(&wildcard_at(&closest_encloser)).is_some(),
+            None => false,
+        }
+    }
+
+    fn nsec3_nodata(&self, name: &str, qtype: u16) -> bool {
+        let lacks = |nsec3: &Nsec3Data| {
+            !nsec3.types.contains(&qtype) && !nsec3.types.contains(&CNAME)
+                && (qtype == DS || !nsec3.types.contains(&NS) || nsec3.types.contains(&SOA))
+        };
+        if self.nsec3_matching(name).is_some_and(lacks) {
+            return true;
+        }
+        let (closest_encloser, next_closer) = match self.closest_encloser_proof(name) {
+            Some(proof) => proof,
+            None => return false,
+        };
+        // no DS under an opt-out span, the delegation just isnt signed (RFC 5155 8.6)
+        if qtype == DS && next_closer.flags & NSEC3_OPT_OUT != 0 {
+            return true;
+        }
+        self.nsec3_matching(&wildcard_at(&closest_encloser)).is_some_and(lacks)
+    }
+
+    // the closest encloser of a name that doesnt exist, and the NSEC3 covering the next closer
+    // name below it (RFC 5155 8.3)
+    fn closest_encloser_proof(&self, name: &str) -> Option<(String, &'a Nsec3Data)> {
+        let labels: Vec<&str> = name.split('.').filter(|l| !l.is_empty()).collect();
+        for i in 1..=labels.len() {
+            let candidate = labels[i..].join(".");
+            if self.nsec3_matching(&candidate).is_some() {
+                let next_closer = labels[i - 1..].join(".");
+                return Some((candidate, self.nsec3_covering(&next_closer)?));
+            }
+        }
+        None
+    }
+
+    // there is nothing closer to `name` than the wildcard it got its answer from
+    fn no_closer_match(&self, name: &str, closest_encloser: &str) -> bool {
+        if self.nsecs.iter().any(|(owner, nsec)| covers(owner, &nsec.next_domain, name)) {
+            return true;
+        }
+        let labels: Vec<&str> = name.split('.').filter(|l| !l.is_empty()).collect();
+        let next_closer = labels[labels.len() - label_count(closest_encloser) - 1..].join(".");
+        self.nsec3_covering(&next_closer).is_some()
+    }
+
+    // `zone` is a delegation, and the NSEC or NSEC3 at it says there is no DS
+    fn unsigned_delegation(&self, zone: &str) -> bool {
+        let unsigned = |types: &[u16]| types.contains(&NS) && !types.contains(&DS) && !types.contains(&SOA);
+        if self.nsecs.iter().any(|(owner, nsec)| owner.eq_ignore_ascii_case(zone) && unsigned(&nsec.types)) {
+            return true;
+        }
+        if self.nsec3s.iter().any(|(_, n)| n.iterations > MAX_NSEC3_ITERATIONS) {
+            return true;
+        }
+        if self.nsec3_matching(zone).is_some_and(|n| unsigned(&n.types)) {
+            return true;
+        }
+        self.closest_encloser_proof(zone).is_some_and(|(_, next_closer)| next_closer.flags & NSEC3_OPT_OUT != 0)
+    }
+
+    fn nsec3_matching(&self, name: &str) -> Option<&'a Nsec3Data> {
+        self.nsec3s.iter().find(|(owner, nsec3)| {
+            nsec3_owner_hash(owner, name).is_some_and(|owner_hash| owner_hash == nsec3_hash(name, nsec3.iterations, &nsec3.salt))
+        }).map(|(_, n)| *n)
+    }
+
+    fn nsec3_covering(&self, name: &str) -> Option<&'a Nsec3Data> {
+        self.nsec3s.iter().find(|(owner, nsec3)| {
+            let owner_hash = match nsec3_owner_hash(owner, name) {
+                Some(hash) => hash,
+                None => return false,
+            };
+            let hash = nsec3_hash(name, nsec3.iterations, &nsec3.salt);
+            let next = &nsec3.next_hashed;
+            match owner_hash.cmp(next) {
+                Ordering::Less => owner_hash < hash && hash < *next,
+                // the last one in the zone wraps around to the first
+                _ => hash > owner_hash || hash < *next,
+            }
+        }).map(|(_, n)| *n)
+    }
+}
+
+const NS: u16 = 2;
+const SOA: u16 = 6;
+const CNAME: u16 = 5;
+const DS: u16 = 43;
+
+// the hash in an NSEC3 owner name, if the NSEC3 is in a zone `name` could be in
+fn nsec3_owner_hash(owner: &str, name: &str) -> Option<Vec<u8>> {
+    let (hash, zone) = owner.split_once('.').unwrap_or((owner, ""));
+    if !in_zone(name, zone) {
+        return None;
+    }
+    encoding::base32hex_decode(hash)
+}
+
+/// The NSEC3 hash of a name, SHA-1 over the name and salt, then again over the hash and salt
+/// `iterations` more times (RFC 5155 5)
+pub fn nsec3_hash(name: &str, iterations: u16, salt: &[u8]) -> Vec<u8> {
+    let mut buf = DnsBuffer::canonical();
+    // names that long dont exist, the hash of whatever made it in is as good as any
+    let _ = buf.write_plain_domain(name);
+    let mut hash = buf.buf[..buf.pos].to_vec();
+    for _ in 0..=iterations {
+        let mut ctx = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
+        ctx.update(&hash);
+        ctx.update(salt);
+        hash = ctx.finish().as_ref().to_vec();
+    }
+    hash
+}
+
+/// Orders names the DNSSEC way, label by label from the right, each label compared as lowercase
+/// bytes (RFC 4034 6.1)
+pub fn canonical_cmp(a: &str, b: &str) -> Ordering {
+    let a = a.split('.').rev().filter(|l| !l.is_empty()).map(|l| l.to_ascii_lowercase());
+    let b = b.split('.').rev().filter(|l| !l.is_empty()).map(|l| l.to_ascii_lowercase());
+    a.cmp(b)
+}
+
+// the NSEC owner..next span has `name` strictly inside it
+fn covers(owner: &str, next: &str, name: &str) -> bool {
+    let after_owner = canonical_cmp(owner, name) == Ordering::Less;
+    let before_next = canonical_cmp(name, next) == Ordering::Less;
+    match canonical_cmp(owner, next) {
+        Ordering::Less => after_owner && before_next,
+        // the last NSEC points back at the apex
+        _ => after_owner || before_next,
+    }
+}
+
+// the longest ancestor of `name` the NSEC shows to exist
+fn closest_encloser_of(name: &str, owner: &str, next: &str) -> String {
+    let labels: Vec<&str> = name.split('.').filter(|l| !l.is_empty()).collect();
+    (0..=labels.len())
+        .map(|i| labels[i..].join("."))
+        .find(|ancestor| in_zone(owner, ancestor) || in_zone(next, ancestor))
+        .unwrap_or_default()
+}
+
+fn wildcard_at(name: &str) -> String {
+    match name.is_empty() {
+        true => "*".to_owned(),
+        false => format!("*.{}", name),
+    }
+}
+
+/// Labels of a name for the RRSIG labels field, the root and a leading * dont count
+pub fn label_count(name: &str) -> usize {
+    name.split('.').filter(|l| !l.is_empty()).count() - name.starts_with("*.") as usize - (name == "*") as usize
+}
+
+/// RFC 4034 appendix B, over the DNSKEY rdata
+pub fn key_tag(key: &DnskeyData) -> u16 {
+    let rdata = dnskey_rdata(key);
+    let mut ac: u32 = 0;
+    for (i, b) in rdata.iter().enumerate() {
+        ac += if i & 1 == 1 { *b as u32 } else { (*b as u32) << 8 };
+    }
+    ac += (ac >> 16) & 0xFFFF;
+    (ac & 0xFFFF) as u16
+}
+
+fn dnskey_rdata(key: &DnskeyData) -> Vec<u8> {
+    let mut rdata = vec![(key.flags >> 8) as u8, key.flags as u8, key.protocol, key.algorithm];
+    rdata.extend_from_slice(&key.public_key);
+    rdata
+}
+
+/// The DS digest of a key, over the owner name and the DNSKEY rdata (RFC 4034 5.1.4)
+pub fn ds_digest(owner: &str, key: &DnskeyData, digest_type: u8) -> Option<Vec<u8>> {
+    let algorithm = match digest_type {
+        DIGEST_SHA1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        DIGEST_SHA256 => &digest::SHA256,
+        DIGEST_SHA384 => &digest::SHA384,
+        _ => return None,
+    };
+    let mut buf = DnsBuffer::canonical();
+    buf.write_plain_domain(owner).ok()?;
+    let mut ctx = digest::Context::new(algorithm);
+    ctx.update(&buf.buf[..buf.pos]);
+    ctx.update(&dnskey_rdata(key));
+    Some(ctx.finish().as_ref().to_vec())
+}
+
+pub fn matches_ds(owner: &str, key: &DnskeyData, ds: &DsData) -> bool {
+    ds.algorithm == key.algorithm
+        && ds.key_tag == key_tag(key)
+        && ds_digest(owner, key, ds.digest_type).is_some_and(|d| d == ds.digest)
+}
+
+pub fn supported_algorithm(algorithm: u8) -> bool {
+    matches!(algorithm, RSASHA256 | ECDSAP256SHA256 | ECDSAP384SHA384 | ED25519)
+}
+
+pub fn supported_digest(digest_type: u8) -> bool {
+    matches!(digest_type, DIGEST_SHA1 | DIGEST_SHA256 | DIGEST_SHA384)
+}
+
+/// What an RRSIG signs: its own rdata without the signature, then every record of the set in
+/// canonical form and order, with the original ttl (RFC 4034 3.1.8.1)
+pub fn signed_data(rrset: &[DnsRecord], sig: &RrsigData) -> anyhow::Result<Vec<u8>> {
+    let mut buf = DnsBuffer::canonical();
+    buf.write_u16(sig.type_covered)?;
+    buf.write(sig.algorithm)?;
+    buf.write(sig.labels)?;
+    buf.write_u32(sig.original_ttl)?;
+    buf.write_u32(sig.expiration)?;
+    buf.write_u32(sig.inception)?;
+    buf.write_u16(sig.key_tag)?;
+    buf.write_plain_domain(&sig.signer_name)?;
+
+    // records that came from a wildcard are signed as the wildcard
+    let owner = rrset.first().and_then(|r| r.domain.name()).unwrap_or("");
+    let labels: Vec<&str> = owner.split('.').filter(|l| !l.is_empty()).collect();
+    let owner = if (sig.labels as usize) < label_count(owner) {
+        wildcard_at(&labels[labels.len() - sig.labels as usize..].join("."))
+    } else {
+        owner.to_owned()
+    };
+
+    let mut records = Vec::new();
+    for rec in rrset {
+        let mut rr = DnsBuffer::canonical();
+        DnsRecord {
+            domain: Domain::Domain(owner.clone()),
+            ttl: Some(sig.original_ttl),
+            ..rec.clone()
+        }.write(&mut rr, &mut HashMap::new())?;
+        records.push(rr.buf[..rr.pos].to_vec());
+    }
+    // the name, type, class, ttl and length in front are the same for all, bar the length
+    let rdata_at = buf_name_len(&owner) + 10;
+    records.sort_by(|a, b| a[rdata_at..].cmp(&b[rdata_at..]));
+    records.dedup();
+
+    let mut data = buf.buf[..buf.pos].to_vec();
+    for rec in records {
+        data.extend(rec);
+    }
+    Ok(data)
+}
+
+fn buf_name_len(name: &str) -> usize {
+    name.split('.').filter(|l| !l.is_empty()).map(|l| l.len() + 1).sum::<usize>() + 1
+}
+
+/// Checks one signature against one key, nothing but the crypto
+pub fn verify_signature(key: &DnskeyData, data: &[u8], sig: &[u8]) -> bool {
+    match key.algorithm {
+        RSASHA256 => {
+            // exponent length in one byte, or a zero and then two bytes (RFC 3110 2)
+            let (e_len, rest) = match key.public_key.split_first() {
+                Some((0, rest)) if rest.len() >= 2 => (u16::from_be_bytes([rest[0], rest[1]]) as usize, &rest[2..]),
+                Some((len, rest)) => (*len as usize, rest),
+                None => return false,
+            };
+            if rest.len() <= e_len {
+                return false;
+            }
+            let (e, n) = rest.split_at(e_len);
+            signature::RsaPublicKeyComponents { n, e }
+                .verify(&signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY, data, sig)
+                .is_ok()
+        }
+        ECDSAP256SHA256 | ECDSAP384SHA384 => {
+            let algorithm = match key.algorithm {
+                ECDSAP256SHA256 => &signature::ECDSA_P256_SHA256_FIXED,
+                _ => &signature::ECDSA_P384_SHA384_FIXED,
+            };
+            // DNSKEY has just the two coordinates, ring wants the uncompressed point
+            let mut point = vec![0x04];
+            point.extend_from_slice(&key.public_key);
+            signature::UnparsedPublicKey::new(algorithm, point).verify(data, sig).is_ok()
+        }
+        ED25519 => signature::UnparsedPublicKey::new(&signature::ED25519, &key.public_key).verify(data, sig).is_ok(),
+        _ => false,
+    }
+}
+
+/// Looks for a signature over `rrset` that is current and checks out against one of `keys`,
+/// which have to be the keys of the signer zone (RFC 4035 5.3)
+pub fn verify_rrset<'a>(rrset: &[DnsRecord], sigs: &'a [RrsigData], keys: &[DnskeyData], now: u32) -> Result<&'a RrsigData, String> {
+    let first = rrset.first().ok_or_else(|| "empty RRset".to_owned())?;
+    let owner = first.domain.name().unwrap_or("");
+    let rtype = first.rtype.to_num();
+    let what = format!("{} {}", owner, record::type_name(rtype));
+
+    let mut why = format!("{} has no usable signature", what);
+    for sig in sigs.iter().filter(|s| s.type_covered == rtype && s.labels as usize <= label_count(owner)) {
+        // serial number arithmetic, the times wrap around in 2106 (RFC 4034 3.1.5)
+        if (now.wrapping_sub(sig.inception) as i32) < 0 || (sig.expiration.wrapping_sub(now) as i32) < 0 {
+            why = format!("signature over {} isnt valid now", what);
+            continue;
+        }
+        let data = match signed_data(rrset, sig) {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+        let candidates = keys.iter().filter(|k| {
+            k.algorithm == sig.algorithm && k.protocol == 3 && k.flags & ZONE_KEY != 0 && key_tag(k) == sig.key_tag
+        });
+        for key in candidates {
+            if verify_signature(key, &data, &sig.signature) {
+                return Ok(sig);
+            }
+            why = format!("signature over {} doesnt verify", what);
+        }
+    }
+    Err(why)
+}
+
+// the RRsets of a section with the signatures covering each, RRSIGs dont make sets of their own
+fn rrsets(records: &[DnsRecord]) -> Vec<(Vec<DnsRecord>, Vec<RrsigData>)> {
+    let mut sets: Vec<(Vec<DnsRecord>, Vec<RrsigData>)> = Vec::new();
+    let key = |r: &DnsRecord| (r.domain.name().unwrap_or("").to_lowercase(), r.rtype.to_num(), r.rclass.to_num());
+    for rec in records.iter().filter(|r| !matches!(r.rtype, RDataType::RRSIG(_) | RDataType::OPT(_))) {
+        match sets.iter_mut().find(|(set, _)| key(&set[0]) == key(rec)) {
+            Some((set, _)) => set.push(rec.clone()),
+            None => sets.push((vec![rec.clone()], Vec::new())),
+        }
+    }
+    for rec in records {
+        if let RDataType::RRSIG(Some(sig)) = &rec.rtype {
+            let covers = |set: &Vec<DnsRecord>| {
+                let (name, rtype, class) = key(&set[0]);
+                name == key(rec).0 && rtype == sig.type_covered && class == rec.rclass.to_num()
+            };
+            if let Some((_, sigs)) = sets.iter_mut().find(|(set, _)| covers(set)) {
+                sigs.push(sig.clone());
+            }
+        }
+    }
+    sets
+}
+
+/// Drops the DNSSEC records a client that didnt set DO never asked for (RFC 4035 3.2.1)
+pub fn strip(pack: &mut DnsPacket) {
+    let qtype = pack.questions.first().map(|q| q.rtype.to_num());
+    let keep = |r: &DnsRecord| {
+        !matches!(r.rtype, RDataType::RRSIG(_) | RDataType::NSEC(_) | RDataType::NSEC3(_)) || Some(r.rtype.to_num()) == qtype
+    };
+    pack.answers.retain(keep);
+    pack.authorities.retain(keep);
+    pack.resources.retain(keep);
+}
+
+fn question(name: &str, rtype: RDataType) -> DnsRecord {
+    DnsRecord {
+        domain: Domain::Domain(name.to_owned()),
+        rtype,
+        rclass: RClass::IN,
+        ttl: None,
+        data_len: None,
+    }
+}
+
+fn owner_is(rec: &DnsRecord, name: &str) -> bool {
+    rec.domain.name().is_some_and(|n| n.eq_ignore_ascii_case(name))
+}
+
+fn min_ttl(records: &[DnsRecord]) -> u32 {
+    records.iter().filter_map(|r| r.ttl).min().unwrap_or(0)
+}
+
+/// Seconds since 1970, what RRSIG times count in
+pub fn unix_now() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as u32).unwrap_or(0)
+}
@@ -0,0 +1,241 @@
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use anyhow::{self, Context};
+use serde::Deserialize;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{body::{Bytes, Incoming}, header, service::service_fn, Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use rustls::{ClientConfig, ServerConfig, pki_types::ServerName};
+use tokio::runtime::{self, Runtime};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use crate::{buffer::DnsBuffer, encoding, packet::DnsPacket, record::RDataType, server::{self, Handler}};
+
+/// Where DoH is, unless told otherwise
+pub const DOH_PORT: u16 = 443;
+/// ALPN id of HTTP/2, the only HTTP spoken here
+pub const DOH_ALPN: &[u8] = b"h2";
+/// The path queries go to
+pub const DOH_PATH: &str = "/dns-query";
+/// Media type of a DNS message in wire format (RFC 8484 6)
+pub const DNS_MESSAGE: &str = "application/dns-message";
+/// A DNS message cant be longer than this, so neither can a body with one in it
+pub const MAX_BODY_SIZE: usize = 65535;
+
+/// An upstream spoken to over HTTPS. The certificate has to be for the host in `url`, `addr` is
+/// where that host is so nothing has to be looked up to get there
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct HttpsUpstream {
+    pub addr: SocketAddr,
+    pub url: String,
+    pub host: String, // from the url, without the port
+}
+
+// ADDR[:PORT]#URL, like 8.8.8.8#https://dns.google/dns-query
+impl std::str::FromStr for HttpsUpstream {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<HttpsUpstream> {
+        let (addr, url) = s.split_once('#').ok_or_else(|| anyhow::anyhow!("has to be ADDR[:PORT]#URL"))?;
+        let addr = match addr.parse::<SocketAddr>() {
+            Ok(addr) => addr,
+            Err(_) => SocketAddr::new(addr.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>()?, DOH_PORT),
+        };
+        let authority = url.strip_prefix("https://")
+            .map(|rest| rest.split(['/', '?']).next().unwrap_or(""))
+            .ok_or_else(|| anyhow::anyhow!("the url has to be https://"))?;
+        let host = match authority.strip_prefix('[') {
+            Some(v6) => v6.split(']').next().unwrap_or(""),
+            None => authority.split(':').next().unwrap_or(""),
+        };
+        ServerName::try_from(host)?;
+        url.parse::<hyper::Uri>()?;
+        Ok(HttpsUpstream { addr, url: url.to_owned(), host: host.to_owned() })
+    }
+}
+
+impl TryFrom<String> for HttpsUpstream {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<HttpsUpstream> {
+        s.parse()
+    }
+}
+
+/// How long an HTTP cache may keep `res` (RFC 8484 5.1): as long as the shortest ttl in the
+/// answers, or for an answer without any the authority records, which is the SOA of negative ones
+pub fn max_age(res: &DnsPacket) -> u32 {
+    let records = match res.answers.is_empty() {
+        true => &res.authorities,
+        false => &res.answers,
+    };
+    records.iter()
+        .filter(|r| !matches!(r.rtype, RDataType::OPT(_)))
+        .filter_map(|r| r.ttl)
+        .min()
+        .unwrap_or(0)
+}
+
+/// Starts answering DoH on the listeners in the background, with a tokio runtime of its own.
+/// Handlers block, so they run on its blocking threads instead of holding up the connections
+pub fn serve(listeners: Vec<TcpListener>, config: Arc<ServerConfig>, handler: Arc<Handler>, keep_alive: Duration) -> anyhow::Result<()> {
+    let runtime = runtime::Builder::new_multi_thread().enable_all().build()
+        .context("doh error: couldnt start the runtime")?;
+    for listener in listeners.iter() {
+        listener.set_nonblocking(true)?;
+    }
+    let acceptor = TlsAcceptor::from(config);
+
+    thread::spawn(move || runtime.block_on(async move {
+        let mut accepting = Vec::new();
+        for listener in listeners {
+            match tokio::net::TcpListener::from_std(listener) {
+                Ok(listener) => accepting.push(tokio::spawn(accept(listener, acceptor.clone(), handler.clone(), keep_alive))),
+                Err(e) => println!("doh error: {:#}", e),
+            }
+        }
+        for task in accepting {
+            let _ = task.await;
+        }
+    }));
+    Ok(())
+}
+
+async fn accept(listener: tokio::net::TcpListener, acceptor: TlsAcceptor, handler: Arc<Handler>, keep_alive: Duration) {
+    loop {
+        let sock = match listener.accept().await {
+            Ok((sock, _)) => sock,
+            Err(_) => continue,
+        };
+        let acceptor = acceptor.clone();
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let stream = match tokio::time::timeout(keep_alive, acceptor.accept(sock)).await {
+                Ok(Ok(stream)) => stream,
+                _ => return,
+            };
+            let service = service_fn(move |req| {
+                let handler = handler.clone();
+                async move { Ok::<_, Infallible>(answer(req, handler).await) }
+            });
+            // idle connections stay open, the pings are what gets rid of clients that went away
+            let _ = hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                .timer(TokioTimer::new())
+                .keep_alive_interval(keep_alive)
+                .keep_alive_timeout(keep_alive)
+                .serve_connection(TokioIo::new(stream), service)
+                .await;
+        });
+    }
+}
+
+// GET with the query in the `dns` parameter or POST with it as the body (RFC 8484 4.1)
+async fn answer(req: Request<Incoming>, handler: Arc<Handler>) -> Response<Full<Bytes>> {
+    if req.uri().path() != DOH_PATH {
+        return status(StatusCode::NOT_FOUND);
+    }
+    let query = match *req.method() {
+        Method::GET => {
+            let dns = req.uri().query().unwrap_or("").split('&').find_map(|p| p.strip_prefix("dns="));
+            match dns.and_then(encoding::base64url_decode) {
+                Some(query) => query,
+                None => return status(StatusCode::BAD_REQUEST),
+            }
+        }
+        Method::POST => {
+            let content_type = req.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok());
+            if content_type.is_none_or(|t| !t.eq_ignore_ascii_case(DNS_MESSAGE)) {
+                return status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            }
+            match Limited::new(req.into_body(), MAX_BODY_SIZE).collect().await {
+                Ok(body) => body.to_bytes().to_vec(),
+                Err(_) => return status(StatusCode::PAYLOAD_TOO_LARGE),
+            }
+        }
+        _ => return status(StatusCode::METHOD_NOT_ALLOWED),
+    };
+
+    let res = tokio::task::spawn_blocking(move || {
+        let mut buf = DnsBuffer::from_bytes(&query);
+        // no truncation either, same as tcp
+        let mut r_buf = DnsBuffer::from_bytes(&[]);
+        server::respond(&mut buf, &mut r_buf, &*handler).then(|| r_buf.buf[..r_buf.pos].to_vec())
+    }).await;
+    let res = match res {
+        Ok(Some(res)) => res,
+        _ => return status(StatusCode::BAD_REQUEST),
+    };
+
+    let max_age = DnsPacket::from_buf(&mut DnsBuffer::from_bytes(&res)).map_or(0, |res| max_age(&res));
+    Response::builder()
+        .header(header::CONTENT_TYPE, DNS_MESSAGE)
+        .header(header::CACHE_CONTROL, format!("max-age={}", max_age))
+        .body(Full::new(Bytes::from(res)))
+        .unwrap_or_else(|_| status(StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+fn status(code: StatusCode) -> Response<Full<Bytes>> {
+    let mut res = Response::new(Full::new(Bytes::new()));
+    *res.status_mut() = code;
+    res
+}
+
+/// Forwards queries over HTTPS for code that isnt async, on a small runtime of its own. Every
+/// query gets a new connection, same as the DoT upstreams
+#[derive(Debug)]
+pub struct Client {
+    runtime: Runtime,
+    config: Arc<ClientConfig>,
+}
+
+impl Client {
+    /// `config` has to offer h2 over ALPN, `tls::client_config(ca_file, DOH_ALPN)` does
+    pub fn new(config: Arc<ClientConfig>) -> anyhow::Result<Client> {
+        let runtime = runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .context("doh error: couldnt start the runtime")?;
+        Ok(Client { runtime, config })
+    }
+
+    /// POSTs `query` to `upstream` and waits up to `timeout` for the whole exchange
+    pub fn exchange(&self, query: &DnsPacket, upstream: &HttpsUpstream, timeout: Duration) -> anyhow::Result<DnsPacket> {
+        let mut buf = DnsBuffer::from_bytes(&[]);
+        query.write(&mut buf)?;
+        buf.buf.truncate(buf.pos);
+
+        // the timer has to be made inside the runtime, hence the async block
+        let body = self.runtime.block_on(async { tokio::time::timeout(timeout, self.post(upstream, buf.buf)).await })
+            .map_err(|_| anyhow::anyhow!("upstream error: no reply from {} in time", upstream.url))??;
+        let res = DnsPacket::from_buf(&mut DnsBuffer::from_bytes(&body))?;
+        if !res.is_response_to(query) {
+            return Err(anyhow::anyhow!("upstream error: {} answered something else", upstream.url));
+        }
+        Ok(res)
+    }
+
+    async fn post(&self, upstream: &HttpsUpstream, query: Vec<u8>) -> anyhow::Result<Bytes> {
+        let name = ServerName::try_from(upstream.host.clone())?;
+        let sock = tokio::net::TcpStream::connect(upstream.addr).await?;
+        let stream = TlsConnector::from(self.config.clone()).connect(name, sock).await?;
+        let (mut sender, conn) = hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream)).await?;
+        // the connection goes away by itself once the sender is dropped
+        tokio::spawn(conn);
+
+        let req = Request::post(&upstream.url)
+            .header(header::CONTENT_TYPE, DNS_MESSAGE)
+            .header(header::ACCEPT, DNS_MESSAGE)
+            .body(Full::new(Bytes::from(query)))?;
+        let res = sender.send_request(req).await?;
+        if res.status() != StatusCode::OK {
+            return Err(anyhow::anyhow!("upstream error: {} answered http {}", upstream.url, res.status()));
+        }
+        let body = Limited::new(res.into_body(), MAX_BODY_SIZE).collect().await
+            .map_err(|e| anyhow::anyhow!("upstream error: bad body from {}: {}", upstream.url, e))?;
+        Ok(body.to_bytes())
+    }
+}
@@ -0,0 +1,169 @@
+use crate::buffer::{self, DnsBuffer};
+use crate::error::{self, ParseError};
+use crate::record::{DnsRecord, Domain, RClass, RDataType};
+
+/// What the server advertises and caps udp responses at, the DNS flag day 2020 value
+pub const SERVER_UDP_PAYLOAD: u16 = 1232;
+
+/// The only EDNS version there is
+pub const EDNS_VERSION: u8 = 0;
+
+/// Extended rcode bits for BADVERS (16), the upper 8 bits of the 12 bit rcode
+pub const BADVERS: u8 = 1;
+
+// option codes
+const NSID: u16 = 3;
+const CLIENT_SUBNET: u16 = 8;
+const COOKIE: u16 = 10;
+const PADDING: u16 = 12;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EdnsOption {
+    Nsid(Vec<u8>),
+    ClientSubnet {
+        family: u16, // 1 for ipv4, 2 for ipv6
+        source_prefix: u8,
+        scope_prefix: u8,
+        address: Vec<u8>, // only as many bytes as the source prefix needs
+    },
+    Cookie(Vec<u8>), // 8 bytes client cookie, then the server cookie if there is one
+    Padding(u16), // only the length, padding is always zeroes
+    Unknown(u16, Vec<u8>),
+}
+
+impl EdnsOption {
+    pub fn code(&self) -> u16 {
+        match self {
+            EdnsOption::Nsid(_) => NSID,
+            EdnsOption::ClientSubnet { .. } => CLIENT_SUBNET,
+            EdnsOption::Cookie(_) => COOKIE,
+            EdnsOption::Padding(_) => PADDING,
+            EdnsOption::Unknown(code, _) => *code,
+        }
+    }
+
+    /// Reads options until `end`, which is where the OPT rdata stops
+    pub fn read_all(buf: &mut DnsBuffer, end: usize) -> error::Result<Vec<EdnsOption>> {
+        let mut options = Vec::new();
+        while buf.pos < end {
+            let offset = buf.pos;
+            let code = buf.read_u16()?;
+            let len = buf.read_u16()? as usize;
+            if buf.pos + len > end {
+                return Err(ParseError::BadOptionLength { offset, len });
+            }
+            let data = buf.get_range(buf.pos, len)?.to_vec();
+            buf.step(len)?;
+
+            options.push(match code {
+                NSID => EdnsOption::Nsid(data),
+                CLIENT_SUBNET if len >= 4 => EdnsOption::ClientSubnet {
+                    family: u16::from_be_bytes([data[0], data[1]]),
+                    source_prefix: data[2],
+                    scope_prefix: data[3],
+                    address: data[4..].to_vec(),
+                },
+                COOKIE => EdnsOption::Cookie(data),
+                PADDING => EdnsOption::Padding(len as u16),
+                _ => EdnsOption::Unknown(code, data),
+            });
+        }
+        Ok(options)
+    }
+
+    pub fn write(&self, buf: &mut DnsBuffer) -> error::Result<()> {
+        buf.write_u16(self.code())?;
+        match self {
+            EdnsOption::Nsid(data) | EdnsOption::Cookie(data) | EdnsOption::Unknown(_, data) => {
+                buf.write_u16(data.len() as u16)?;
+                for b in data {
+                    buf.write(*b)?;
+                }
+            }
+            EdnsOption::ClientSubnet { family, source_prefix, scope_prefix, address } => {
+                buf.write_u16(4 + address.len() as u16)?;
+                buf.write_u16(*family)?;
+                buf.write(*source_prefix)?;
+                buf.write(*scope_prefix)?;
+                for b in address {
+                    buf.write(*b)?;
+                }
+            }
+            EdnsOption::Padding(len) => {
+                buf.write_u16(*len)?;
+                for _ in 0..*len {
+                    buf.write(0)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The contents of an OPT pseudo record (RFC 6891), which hides its fields in the class and ttl
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edns {
+    pub udp_payload_size: u16,
+    pub extended_rcode: u8,
+    pub version: u8,
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+impl Edns {
+    pub fn new(udp_payload_size: u16) -> Edns {
+        Edns {
+            udp_payload_size,
+            extended_rcode: 0,
+            version: EDNS_VERSION,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+
+    /// None if the record isnt an OPT
+    pub fn from_record(rec: &DnsRecord) -> Option<Edns> {
+        let options = match &rec.rtype {
+            RDataType::OPT(options) => options.clone().unwrap_or_default(),
+            _ => return None,
+        };
+        let ttl = rec.ttl.unwrap_or(0);
+
+        Some(Edns {
+            udp_payload_size: rec.rclass.to_num(),
+            extended_rcode: (ttl >> 24) as u8,
+            version: ((ttl >> 16) & 0xFF) as u8,
+            dnssec_ok: (ttl & (1 << 15)) > 0,
+            options,
+        })
+    }
+
+    pub fn to_record(&self) -> DnsRecord {
+        DnsRecord {
+            domain: Domain::Domain(String::new()),
+            rtype: RDataType::OPT(Some(self.options.clone())),
+            rclass: RClass::from_num(self.udp_payload_size),
+            ttl: Some(
+                ((self.extended_rcode as u32) << 24)
+                | ((self.version as u32) << 16)
+                | ((self.dnssec_ok as u32) << 15)
+                ),
+            data_len: None,
+        }
+    }
+
+    /// How big a udp response to whoever sent this can get. Anything under 512 is treated as 512
+    pub fn max_response_size(&self) -> usize {
+        self.udp_payload_size.clamp(buffer::UDP_MAX_SIZE as u16, SERVER_UDP_PAYLOAD) as usize
+    }
+
+    /// The OPT the server puts in responses to a query that had this one
+    pub fn reply(&self) -> Edns {
+        let mut reply = Edns::new(SERVER_UDP_PAYLOAD);
+        reply.dnssec_ok = self.dnssec_ok;
+        if self.version > EDNS_VERSION {
+            reply.extended_rcode = BADVERS;
+        }
+        reply
+    }
+}
@@ -0,0 +1,158 @@
+//! The text forms DNSSEC rdata uses in zone files: base64 for keys and signatures, base32hex for
+//! NSEC3 hashes, hex for digests and salts, and the YYYYMMDDHHmmSS timestamps of RRSIG. Plus the
+//! base64url DoH puts queries in urls with
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const BASE64URL: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+const BASE32HEX: &[u8; 32] = b"0123456789ABCDEFGHIJKLMNOPQRSTUV";
+
+/// Padded base64 (RFC 4648 4)
+pub fn base64_encode(bytes: &[u8]) -> String {
+    encode_base64(bytes, BASE64, true)
+}
+
+/// Base64 with or without the padding, whitespace is skipped since zone files split long keys
+pub fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.split_ascii_whitespace().collect::<String>();
+    let digits = text.trim_end_matches('=');
+    if text.len() - digits.len() > 2 {
+        return None;
+    }
+    decode_bits(digits, 6, |c| BASE64.iter().position(|b| *b == c))
+}
+
+/// Unpadded base64url (RFC 4648 5), what the `dns` parameter of a DoH GET is in (RFC 8484 4.1)
+pub fn base64url_encode(bytes: &[u8]) -> String {
+    encode_base64(bytes, BASE64URL, false)
+}
+
+/// Base64url, padding is tolerated even though DoH says to leave it off
+pub fn base64url_decode(text: &str) -> Option<Vec<u8>> {
+    let digits = text.trim_end_matches('=');
+    if text.len() - digits.len() > 2 {
+        return None;
+    }
+    decode_bits(digits, 6, |c| BASE64URL.iter().position(|b| *b == c))
+}
+
+fn encode_base64(bytes: &[u8], alphabet: &[u8; 64], padded: bool) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(alphabet[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else if padded {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Unpadded base32hex (RFC 4648 7), the way NSEC3 hashes are written
+pub fn base32hex_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity((bytes.len() * 8).div_ceil(5));
+    let mut bits = 0u32;
+    let mut n = 0u32;
+    for b in bytes {
+        n = (n << 8) | *b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32HEX[(n >> bits & 0x1F) as usize] as char);
+        }
+        n &= (1 << bits) - 1;
+    }
+    if bits > 0 {
+        out.push(BASE32HEX[(n << (5 - bits) & 0x1F) as usize] as char);
+    }
+    out
+}
+
+/// Base32hex in either case, the padding is optional
+pub fn base32hex_decode(text: &str) -> Option<Vec<u8>> {
+    let digits = text.trim_end_matches('=');
+    decode_bits(digits, 5, |c| BASE32HEX.iter().position(|b| *b == c.to_ascii_uppercase()))
+}
+
+pub fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+pub fn hex_decode(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+// packs `width` bit digits into bytes, leftover bits have to be zero or it wasnt made by an encoder
+fn decode_bits(digits: &str, width: u32, value: impl Fn(u8) -> Option<usize>) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(digits.len() * width as usize / 8);
+    let mut bits = 0u32;
+    let mut n = 0u32;
+    for c in digits.bytes() {
+        n = (n << width) | value(c)? as u32;
+        bits += width;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((n >> bits) as u8);
+            n &= (1 << bits) - 1;
+        }
+    }
+    if bits >= width || n != 0 {
+        return None;
+    }
+    Some(out)
+}
+
+/// RRSIG time as YYYYMMDDHHmmSS in UTC (RFC 4034 3.2)
+pub fn timestamp_encode(secs: u32) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    let (y, m, d) = civil_from_days(days);
+    format!("{:04}{:02}{:02}{:02}{:02}{:02}", y, m, d, rem / 3600, rem / 60 % 60, rem % 60)
+}
+
+/// Either the YYYYMMDDHHmmSS form or plain seconds since the epoch
+pub fn timestamp_decode(text: &str) -> Option<u32> {
+    if text.len() != 14 {
+        return text.parse().ok();
+    }
+    if !text.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let num = |r: std::ops::Range<usize>| text[r].parse::<i64>().ok();
+    let (y, m, d) = (num(0..4)?, num(4..6)?, num(6..8)?);
+    let (h, min, s) = (num(8..10)?, num(10..12)?, num(12..14)?);
+    if !(1..=12).contains(&m) || !(1..=31).contains(&d) || h > 23 || min > 59 || s > 59 {
+        return None;
+    }
+    let secs = days_from_civil(y, m, d) * 86400 + h * 3600 + min * 60 + s;
+    u32::try_from(secs).ok()
+}
+
+// Howard Hinnant's days_from_civil and its inverse, days counted from 1970-01-01
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + if m <= 2 { 1 } else { 0 }, m, d)
+}
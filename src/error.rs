@@ -0,0 +1,44 @@
+use thiserror::Error;
+
+/// What can go wrong reading (or writing) the wire format. Every variant carries the offset in
+/// the message where it went wrong, so bad packets can be told apart without parsing strings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum ParseError {
+    #[error("end of buffer at offset {offset}")]
+    EndOfBuffer { offset: usize },
+    #[error("compression pointer at offset {offset} has a bad target {target}")]
+    BadPointer { offset: usize, target: usize },
+    #[error("more than {jumps} compression pointers in the name at offset {offset}, probably a loop")]
+    PointerLoop { offset: usize, jumps: usize },
+    #[error("unknown label type {label:#04x} at offset {offset}")]
+    BadLabelType { offset: usize, label: u8 },
+    #[error("label at offset {offset} is {len} bytes, 63 is the max")]
+    LabelTooLong { offset: usize, len: usize },
+    #[error("name at offset {offset} is longer than 255 bytes")]
+    NameTooLong { offset: usize },
+    #[error("rdata at offset {offset} doesnt fit its length of {len}")]
+    BadRdataLength { offset: usize, len: usize },
+    #[error("EDNS option at offset {offset} says {len} bytes, more than the OPT has left")]
+    BadOptionLength { offset: usize, len: usize },
+    #[error("buffer full at offset {offset}")]
+    BufferFull { offset: usize },
+}
+
+impl ParseError {
+    /// Where in the message it went wrong
+    pub fn offset(&self) -> usize {
+        match *self {
+            ParseError::EndOfBuffer { offset }
+            | ParseError::BadPointer { offset, .. }
+            | ParseError::PointerLoop { offset, .. }
+            | ParseError::BadLabelType { offset, .. }
+            | ParseError::LabelTooLong { offset, .. }
+            | ParseError::NameTooLong { offset }
+            | ParseError::BadRdataLength { offset, .. }
+            | ParseError::BadOptionLength { offset, .. }
+            | ParseError::BufferFull { offset } => offset,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, ParseError>;
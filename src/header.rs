@@ -1,5 +1,6 @@
-use anyhow;
-use crate::buffer::DnsBuffer;
+use crate::{buffer::DnsBuffer, error::{self, ParseError}};
+
+pub const HEADER_SIZE: usize = 12;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ResultCode {
@@ -19,12 +20,12 @@ impl ResultCode {
             3 => ResultCode::NXDOMAIN,
             4 => ResultCode::NOTIMP,
             5 => ResultCode::REFUSED,
-            0 | _ => ResultCode::NOERROR,
+            _ => ResultCode::NOERROR,
         }
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct DnsHeader {
     pub id: u16, // 16 bits // random id
 
@@ -46,6 +47,12 @@ pub struct DnsHeader {
     pub resource_entries: u16,      // 16 bits
 }
 
+impl Default for DnsHeader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DnsHeader {
     pub fn new() -> DnsHeader {
         DnsHeader {
@@ -70,34 +77,45 @@ impl DnsHeader {
         }
     }
 
-    pub fn read(&mut self, buf: &mut DnsBuffer) -> anyhow::Result<()>{
-        self.id = buf.read_u16()?;
+    pub fn read(&mut self, buf: &mut DnsBuffer) -> error::Result<()> {
+        *self = DnsHeader::from_slice(buf.get_range(buf.pos, HEADER_SIZE)?)?;
+        buf.step(HEADER_SIZE)?;
+        Ok(())
+    }
+
+    /// Parses a header straight from the first 12 bytes of `data`
+    pub fn from_slice(data: &[u8]) -> error::Result<DnsHeader> {
+        if data.len() < HEADER_SIZE {
+            return Err(ParseError::EndOfBuffer { offset: data.len() });
+        }
+        let mut header = DnsHeader::new();
+        header.id = u16::from_be_bytes([data[0], data[1]]);
 
         // flags 
-        let flags_1 = buf.read()?;
-        let flags_2 = buf.read()?;
-        self.recursion_desired = (flags_1 & (1 << 0)) > 0;
-        self.truncated_message = (flags_1 & (1 << 1)) > 0;
-        self.authoritative_answer = (flags_1 & (1 << 2)) > 0;
-        self.opcode = (flags_1 >> 3) & 0x0F;
-        self.response = (flags_1 & (1 << 7)) > 0;
-
-        self.rescode = ResultCode::from_num(flags_2 & 0x0F);
-        self.checking_disabled = (flags_2 & (1 << 4)) > 0;
-        self.authed_data = (flags_2 & (1 << 5)) > 0;
-        self.z = (flags_2 & (1 << 6)) > 0;
-        self.recursion_available = (flags_2 & (1 << 7)) > 0;
-
-
-        self.questions = buf.read_u16()?;
-        self.answers = buf.read_u16()?;
-        self.authoritative_entries = buf.read_u16()?;
-        self.resource_entries = buf.read_u16()?;
-
-        Ok(())
+        let flags_1 = data[2];
+        let flags_2 = data[3];
+        header.recursion_desired = (flags_1 & (1 << 0)) > 0;
+        header.truncated_message = (flags_1 & (1 << 1)) > 0;
+        header.authoritative_answer = (flags_1 & (1 << 2)) > 0;
+        header.opcode = (flags_1 >> 3) & 0x0F;
+        header.response = (flags_1 & (1 << 7)) > 0;
+
+        header.rescode = ResultCode::from_num(flags_2 & 0x0F);
+        header.checking_disabled = (flags_2 & (1 << 4)) > 0;
+        header.authed_data = (flags_2 & (1 << 5)) > 0;
+        header.z = (flags_2 & (1 << 6)) > 0;
+        header.recursion_available = (flags_2 & (1 << 7)) > 0;
+
+
+        header.questions = u16::from_be_bytes([data[4], data[5]]);
+        header.answers = u16::from_be_bytes([data[6], data[7]]);
+        header.authoritative_entries = u16::from_be_bytes([data[8], data[9]]);
+        header.resource_entries = u16::from_be_bytes([data[10], data[11]]);
+
+        Ok(header)
     }
 
-    pub fn write(&self, buf: &mut DnsBuffer) -> anyhow::Result<()> {
+    pub fn write(&self, buf: &mut DnsBuffer) -> error::Result<()> {
         buf.write_u16(self.id)?;
 
         buf.write(
@@ -105,7 +123,7 @@ impl DnsHeader {
             | ((self.truncated_message as u8) << 1)
             | ((self.authoritative_answer as u8) << 2)
             | (self.opcode << 3)
-            | ((self.response as u8) << 7) as u8,
+            | ((self.response as u8) << 7),
             )?;
 
         buf.write(
@@ -1,6 +1,21 @@
+#[allow(clippy::module_inception)]
 pub mod question;
 pub mod packet;
 pub mod record;
 pub mod header;
 pub mod buffer;
 pub mod server;
+pub mod config;
+pub mod upstream;
+pub mod resolver;
+pub mod cache;
+pub mod tcp;
+pub mod edns;
+pub mod view;
+pub mod zone;
+pub mod error;
+pub mod encoding;
+pub mod dnssec;
+pub mod signer;
+pub mod tls;
+pub mod doh;
@@ -1,59 +1,99 @@
-use std::collections::HashMap;
-use std::net::Ipv4Addr;
-use std::str::FromStr;
+use std::sync::Arc;
 use deez_ns::buffer::DnsBuffer;
+use deez_ns::cache::Cache;
 use deez_ns::header::ResultCode;
-use deez_ns::record::{DnsRecord, RDataType, RClass, Domain};
-use deez_ns::server::Server;
-
-fn main() {
-    let mut cache: HashMap<String, RDataType> = HashMap::new();
-    cache.insert("yahoo.com".to_owned(), RDataType::A(Some(Ipv4Addr::from_str("0.0.0.1").unwrap())));
-    cache.insert("youtube.com".to_owned(), RDataType::A(Some(Ipv4Addr::from_str("0.0.0.2").unwrap())));
-    
-    let server = Server::new("0.0.0.0");
-    
-    loop {
-        let buf = &mut DnsBuffer::new();
-        let (mut pack, from) = server.get_query(buf).unwrap();
-
-        let r_buf = &mut DnsBuffer::new();
-        if let Some(data) =  cache.get(&pack.questions[0].domain.get_string(buf).unwrap()) {
-            pack.header.response = true;
-            pack.header.answers = 1;
-            pack.header.rescode = ResultCode::NOERROR;
-            pack.header.recursion_available = true;
-            pack.header.write(r_buf).unwrap();
-
-            let fuck = HashMap::new();
-            pack.questions[0].write(r_buf, &fuck).unwrap();
-            DnsRecord {
-                domain: Domain::Jump(Box::new([0xC0, *pack.domain_jumps.get(&pack.questions[0].domain.get_string(buf).unwrap()).unwrap()])),
-                rtype: data.clone(),
-                rclass: RClass::IN,
-                ttl: Some(1000),
-                data_len: Some(4),
-            }.write(r_buf, &pack.domain_jumps).unwrap();
-        } else {
-            let r_pack = server.resolve(&pack).unwrap();
-            r_pack.header.write(r_buf).unwrap();
-            let quest_dom_jmp: HashMap<String, u8> = HashMap::new();
-            r_pack.questions.iter().for_each(|q| {q.write(r_buf, &quest_dom_jmp).unwrap()});
-            r_pack.answers.iter().for_each(|a| {a.write(r_buf, &r_pack.domain_jumps).unwrap()});
-
-            r_pack.questions
-                .iter()
-                .zip(r_pack.answers.iter())
-                .for_each(
-                    |(q, a)| {
-                        cache.insert(q.domain.get_string(buf).unwrap(), a.rtype.clone());
-                    }
-                );
-            println!("{:#?}", r_pack);
+use deez_ns::packet::DnsPacket;
+use deez_ns::server::{Server, Handler};
+use deez_ns::config::ServerConfig;
+use deez_ns::edns::{self, Edns};
+use deez_ns::dnssec;
+
+/// Answers one query into `r_buf`, from the cache or upstream. Both udp and tcp queries end up here,
+/// `r_buf.max_size` is what decides if the response gets truncated
+fn handle_query(server: &Server, cache: &Cache, mut pack: DnsPacket, r_buf: &mut DnsBuffer) -> anyhow::Result<()> {
+    let client_edns = pack.edns();
+
+    // only EDNS version 0 exists, anything newer gets BADVERS and nothing else
+    if client_edns.as_ref().is_some_and(|e| e.version > edns::EDNS_VERSION) {
+        pack.header.response = true;
+        pack.resources.clear();
+        pack.set_edns(client_edns.as_ref().map(Edns::reply));
+        return pack.write_truncated(r_buf);
+    }
+
+    if let Some(mut r_pack) = server.authoritative(&pack) {
+        r_pack.set_edns(client_edns.as_ref().map(Edns::reply));
+        return r_pack.write_truncated(r_buf);
+    }
+
+    let dnssec_ok = client_edns.as_ref().is_some_and(|e| e.dnssec_ok);
+    let question = pack.questions.first()
+        .ok_or_else(|| anyhow::anyhow!("query error: no question"))?;
+    let cached = question.domain.name()
+        .and_then(|name| cache.lookup(name, question.rtype.to_num(), question.rclass.to_num()));
+
+    if let Some(cached) = cached {
+        pack.header.response = true;
+        pack.header.rescode = cached.rescode;
+        pack.header.recursion_available = true;
+        pack.header.authed_data = cached.authed_data;
+
+        pack.answers.extend(cached.answers);
+        pack.authorities.extend(cached.authorities);
+        pack.resources.clear(); // whatever the client put there isnt ours to echo back
+        if !dnssec_ok {
+            dnssec::strip(&mut pack);
+        }
+        pack.set_edns(client_edns.as_ref().map(Edns::reply));
+        pack.write_truncated(r_buf)?;
+    } else {
+        let mut r_pack = server.resolve_unstripped(&pack)?;
+
+        // with CD nothing got validated, that isnt for everyone else. The signatures go in the
+        // cache either way, the next client might want them
+        let unchecked = server.config.dnssec && pack.header.checking_disabled;
+        if matches!(r_pack.header.rescode, ResultCode::NOERROR | ResultCode::NXDOMAIN) && !unchecked {
+            cache.insert_answers(&r_pack);
+            cache.insert_negative(&r_pack);
         }
-        println!("{:02X?}", &r_buf.buf[0..r_buf.pos]);
-        server.respond_with(r_buf, from).unwrap();
+
+        if !dnssec_ok {
+            dnssec::strip(&mut r_pack);
+        }
+        r_pack.set_edns(client_edns.as_ref().map(Edns::reply));
+        r_pack.write_truncated(r_buf)?;
     }
+    Ok(())
 }
 
+fn main() -> anyhow::Result<()> {
+    let config = ServerConfig::from_args(std::env::args().skip(1))?;
 
+    let cache = Arc::new(Cache::new(config.cache_max_bytes));
+    let server = Arc::new(Server::new(config)?);
+    println!("listening on {:?}", server.local_addrs()?);
+    if !server.tls_addrs()?.is_empty() {
+        println!("dot on {:?}", server.tls_addrs()?);
+    }
+    if !server.https_addrs()?.is_empty() {
+        println!("doh on {:?}", server.https_addrs()?);
+    }
+    // what the parent zones need to hold for the chain of trust to reach the signed ones
+    for zone in server.zones() {
+        for ds in zone.ds() {
+            println!("{}. DS {}", zone.origin, ds);
+        }
+    }
+
+    let handler: Arc<Handler> = {
+        let server = server.clone();
+        Arc::new(move |pack, r_buf| handle_query(&server, &cache, pack, r_buf))
+    };
+    server.serve_tcp(handler.clone())?;
+    server.serve_tls(handler.clone())?;
+    server.serve_https(handler.clone())?;
+    for thread in server.serve_udp(handler)? {
+        let _ = thread.join();
+    }
+    Ok(())
+}
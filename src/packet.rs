@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use crate::{header::DnsHeader, record::{DnsRecord, RecordType}, buffer::DnsBuffer};
+use crate::{header::{DnsHeader, ResultCode}, record::{DnsRecord, RecordType, Domain, RDataType}, buffer::{self, DnsBuffer}, edns::Edns, error};
 
 #[derive(Debug)]
 pub struct DnsPacket {
@@ -8,7 +8,13 @@ pub struct DnsPacket {
     pub answers: Vec<DnsRecord>,
     pub authorities: Vec<DnsRecord>,
     pub resources: Vec<DnsRecord>,
-    pub domain_jumps: HashMap<String, u8>, // maps a domaing to its respective offset
+    pub domain_jumps: HashMap<String, u16>, // maps a domaing to its respective offset in the parsed buffer
+}
+
+impl Default for DnsPacket {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl DnsPacket {
@@ -23,7 +29,7 @@ impl DnsPacket {
         }
     }
 
-    pub fn from_buf(buf: &mut DnsBuffer) -> anyhow::Result<DnsPacket> {
+    pub fn from_buf(buf: &mut DnsBuffer) -> error::Result<DnsPacket> {
         let mut dns_p = DnsPacket::new();
 
         dns_p.header = DnsHeader::new();
@@ -39,12 +45,137 @@ impl DnsPacket {
             dns_p.authorities.push(DnsRecord::from_buf(buf, RecordType::OTHER, &mut dns_p.domain_jumps)?);
         }
         for _ in 0..dns_p.header.resource_entries {
-            dns_p.questions.push(DnsRecord::from_buf(buf, RecordType::OTHER, &mut dns_p.domain_jumps)?);
+            dns_p.resources.push(DnsRecord::from_buf(buf, RecordType::OTHER, &mut dns_p.domain_jumps)?);
         }
 
         Ok(dns_p)
     }
 
+    /// Writes the whole packet. The section counts in the header are taken from the vecs and not
+    /// from `self.header`, and names are compressed against offsets in `buf` as they get written.
+    /// What goes wrong is a `ParseError`, in an anyhow so handlers can return it as is
+    pub fn write(&self, buf: &mut DnsBuffer) -> anyhow::Result<()> {
+        let mut header = self.header.clone();
+        header.questions = self.questions.len() as u16;
+        header.answers = self.answers.len() as u16;
+        header.authoritative_entries = self.authorities.len() as u16;
+        header.resource_entries = self.resources.len() as u16;
+        header.write(buf)?;
+
+        let mut domain_jumps = HashMap::new();
+        for rec in self.questions.iter()
+            .chain(self.answers.iter())
+            .chain(self.authorities.iter())
+            .chain(self.resources.iter()) {
+            rec.write(buf, &mut domain_jumps)?;
+        }
+        Ok(())
+    }
+
+    /// Follows the cname chain in the answers starting at `domain`. If the chain ends at a name
+    /// that has no `rtype` answer, that name is returned since it still needs to be looked up
+    pub fn unresolved_cname(&self, domain: &str, rtype: u16) -> Option<String> {
+        let mut name = domain.to_owned();
+        let mut followed = false;
+
+        // a chain cant be longer than the answers, this also stops cname loops
+        for _ in 0..=self.answers.len() {
+            let answered = self.answers.iter().any(|a| {
+                matches!(&a.domain, Domain::Domain(d) if d.eq_ignore_ascii_case(&name)) && a.rtype.to_num() == rtype
+            });
+            if answered {
+                return None;
+            }
+
+            let target = self.answers.iter().find_map(|a| match (&a.domain, &a.rtype) {
+                (Domain::Domain(d), RDataType::CNAME(Some(target))) if d.eq_ignore_ascii_case(&name) => Some(target.clone()),
+                _ => None,
+            });
+            match target {
+                Some(target) => {
+                    name = target;
+                    followed = true;
+                }
+                None => break,
+            }
+        }
+
+        if followed { Some(name) } else { None }
+    }
+
+    /// An empty response to this query with `rescode`, for when there is nothing better to say
+    pub fn response_to(&self, rescode: ResultCode) -> DnsPacket {
+        let mut res = DnsPacket::new();
+        res.header.id = self.header.id;
+        res.header.opcode = self.header.opcode;
+        res.header.recursion_desired = self.header.recursion_desired;
+        res.header.recursion_available = true;
+        res.header.response = true;
+        res.header.rescode = rescode;
+        res.questions = self.questions.clone();
+        res
+    }
+
+    /// If this is the response to `query`, same id and the same questions. Anything else coming
+    /// back from an upstream is someone else's or spoofed (RFC 5452)
+    pub fn is_response_to(&self, query: &DnsPacket) -> bool {
+        self.header.response
+            && self.header.id == query.header.id
+            && self.questions.len() == query.questions.len()
+            && self.questions.iter().zip(query.questions.iter()).all(|(a, q)| {
+                a.rtype.to_num() == q.rtype.to_num()
+                    && a.rclass.to_num() == q.rclass.to_num()
+                    && match (a.domain.name(), q.domain.name()) {
+                        (Some(a), Some(q)) => a.eq_ignore_ascii_case(q),
+                        _ => false,
+                    }
+            })
+    }
+
+    /// Like write, but when the packet doesnt fit `buf.max_size` only the header and questions
+    /// are sent, with the truncated bit set so the client knows to retry over tcp
+    pub fn write_truncated(&self, buf: &mut DnsBuffer) -> anyhow::Result<()> {
+        let start = buf.pos;
+        let max_size = buf.max_size;
+
+        buf.set_max_size(buffer::MAX_SIZE);
+        let res = self.write(buf);
+        buf.set_max_size(max_size);
+        res?;
+
+        if buf.pos <= max_size {
+            return Ok(());
+        }
+
+        buf.pos = start;
+        buf.buf.truncate(max_size);
+        let mut header = self.header.clone();
+        header.truncated_message = true;
+        let mut truncated = DnsPacket {
+            header,
+            questions: self.questions.clone(),
+            ..DnsPacket::new()
+        };
+        // the OPT has to stay, even in a truncated response (RFC 6891)
+        truncated.set_edns(self.edns());
+        truncated.write(buf)
+    }
+
+    /// The OPT record of the additional section, if there is one
+    pub fn edns(&self) -> Option<Edns> {
+        self.resources.iter().find_map(Edns::from_record)
+    }
 
-    
+    /// Replaces the OPT record in the additional section, None just removes it
+    pub fn set_edns(&mut self, edns: Option<Edns>) {
+        self.resources.retain(|r| !matches!(r.rtype, RDataType::OPT(_)));
+        if let Some(edns) = edns {
+            self.resources.push(edns.to_record());
+        }
+    }
+
+    /// Largest udp response the sender of this query takes, 512 unless it said otherwise with EDNS
+    pub fn max_response_size(&self) -> usize {
+        self.edns().map_or(buffer::UDP_MAX_SIZE, |e| e.max_response_size())
+    }
 }
@@ -1,21 +1,69 @@
 use std::net::{Ipv4Addr, Ipv6Addr};
 use std::collections::HashMap;
-use anyhow;
+use std::fmt;
 use crate::buffer;
+use crate::encoding;
+use crate::error::{self, ParseError};
+use crate::edns::EdnsOption;
 
-#[derive(Debug, Clone)]
+/// Mnemonics of the types we know by name, everything else is TYPEnnn (RFC 3597 5)
+const TYPE_NAMES: &[(u16, &str)] = &[
+    (1, "A"),
+    (2, "NS"),
+    (5, "CNAME"),
+    (6, "SOA"),
+    (12, "PTR"),
+    (15, "MX"),
+    (16, "TXT"),
+    (28, "AAAA"),
+    (33, "SRV"),
+    (41, "OPT"),
+    (43, "DS"),
+    (46, "RRSIG"),
+    (47, "NSEC"),
+    (48, "DNSKEY"),
+    (50, "NSEC3"),
+    (51, "NSEC3PARAM"),
+];
+
+/// How the type is written in zone files
+pub fn type_name(num: u16) -> String {
+    match TYPE_NAMES.iter().find(|(n, _)| *n == num) {
+        Some((_, name)) => name.to_string(),
+        None => format!("TYPE{}", num),
+    }
+}
+
+/// The type number for a mnemonic or TYPEnnn, in any case
+pub fn type_from_name(name: &str) -> Option<u16> {
+    let name = name.to_ascii_uppercase();
+    match TYPE_NAMES.iter().find(|(_, n)| *n == name) {
+        Some((num, _)) => Some(*num),
+        None => name.strip_prefix("TYPE").and_then(|n| n.parse().ok()),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Domain {
     Domain(String),
-    Jump(Box<[u8; 2]>),
+    Jump(u16), // offset of the name in the packet, only the lower 14 bits are used
 }
 
 impl Domain {
-    pub fn get_string(&self, buf: &mut buffer::DnsBuffer) -> anyhow::Result<String> {
+    /// The name if it is already a string, jumps need `get_string` and the buffer they point into
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Domain::Domain(str) => Some(str),
+            Domain::Jump(_) => None,
+        }
+    }
+
+    pub fn get_string(&self, buf: &mut buffer::DnsBuffer) -> error::Result<String> {
         match &self {
             Domain::Domain(str) => Ok(str.to_owned()),
             Domain::Jump(jump_offset) => {
                 let pos_back = buf.pos; // save it for later
-                buf.seek(jump_offset[1] as usize)?;
+                buf.seek((*jump_offset & 0x3FFF) as usize)?;
                 let str = buf.get_domain()?;
                 buf.pos = pos_back;
                 Ok(str)
@@ -25,13 +73,219 @@ impl Domain {
 }
 
 // represents the type of a record
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum RDataType {
-    UNKNOWN(u16),
+    UNKNOWN(u16, Option<Vec<u8>>), // type number and the raw rdata, kept as is (RFC 3597)
     A(Option<Ipv4Addr>),
     NS(Option<String>),
-    TXT(Option<String>),
+    CNAME(Option<String>),
+    SOA(Option<SoaData>),
+    PTR(Option<String>),
+    MX(Option<MxData>),
+    TXT(Option<Vec<u8>>), // the raw rdata, character-strings with their length bytes. Not always utf8
     AAAA(Option<Ipv6Addr>),
+    SRV(Option<SrvData>),
+    OPT(Option<Vec<EdnsOption>>), // see edns::Edns for the fields hidden in the class and ttl
+    DS(Option<DsData>),
+    RRSIG(Option<RrsigData>),
+    NSEC(Option<NsecData>),
+    DNSKEY(Option<DnskeyData>),
+    NSEC3(Option<Nsec3Data>),
+    NSEC3PARAM(Option<Nsec3ParamData>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SoaData {
+    pub mname: String, // primary nameserver of the zone
+    pub rname: String, // mailbox of whoever is responsible, first dot is the @
+    pub serial: u32,
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    pub minimum: u32, // also the ttl for negative answers (RFC 2308)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MxData {
+    pub priority: u16,
+    pub exchange: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvData {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// RFC 4034 2
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnskeyData {
+    pub flags: u16, // 256 is a zone key, 257 also has the secure entry point bit
+    pub protocol: u8, // always 3
+    pub algorithm: u8,
+    pub public_key: Vec<u8>,
+}
+
+/// RFC 4034 5
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DsData {
+    pub key_tag: u16,
+    pub algorithm: u8,
+    pub digest_type: u8,
+    pub digest: Vec<u8>,
+}
+
+/// RFC 4034 3, the times are seconds since the epoch, mod 2^32
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RrsigData {
+    pub type_covered: u16,
+    pub algorithm: u8,
+    pub labels: u8,
+    pub original_ttl: u32,
+    pub expiration: u32,
+    pub inception: u32,
+    pub key_tag: u16,
+    pub signer_name: String,
+    pub signature: Vec<u8>,
+}
+
+/// RFC 4034 4
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NsecData {
+    pub next_domain: String,
+    pub types: Vec<u16>, // sorted, without duplicates
+}
+
+/// RFC 5155 3
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Nsec3Data {
+    pub hash_algorithm: u8,
+    pub flags: u8, // just opt-out so far
+    pub iterations: u16,
+    pub salt: Vec<u8>,
+    pub next_hashed: Vec<u8>, // raw hash, base32hex is only for the owner name and presentation
+    pub types: Vec<u16>,
+}
+
+/// RFC 5155 4
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Nsec3ParamData {
+    pub hash_algorithm: u8,
+    pub flags: u8,
+    pub iterations: u16,
+    pub salt: Vec<u8>,
+}
+
+// zone file forms of the rdata, names are written absolute
+impl fmt::Display for DnskeyData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {} {}", self.flags, self.protocol, self.algorithm, encoding::base64_encode(&self.public_key))
+    }
+}
+
+impl fmt::Display for DsData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {} {}", self.key_tag, self.algorithm, self.digest_type, encoding::hex_encode(&self.digest))
+    }
+}
+
+impl fmt::Display for RrsigData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f, "{} {} {} {} {} {} {} {}. {}",
+            type_name(self.type_covered), self.algorithm, self.labels, self.original_ttl,
+            encoding::timestamp_encode(self.expiration), encoding::timestamp_encode(self.inception),
+            self.key_tag, self.signer_name, encoding::base64_encode(&self.signature),
+        )
+    }
+}
+
+impl fmt::Display for NsecData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.", self.next_domain)?;
+        for t in self.types.iter() {
+            write!(f, " {}", type_name(*t))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Nsec3Data {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f, "{} {} {} {} {}",
+            self.hash_algorithm, self.flags, self.iterations, salt_text(&self.salt), encoding::base32hex_encode(&self.next_hashed),
+        )?;
+        for t in self.types.iter() {
+            write!(f, " {}", type_name(*t))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Nsec3ParamData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {} {}", self.hash_algorithm, self.flags, self.iterations, salt_text(&self.salt))
+    }
+}
+
+// an empty salt is written as a dash
+fn salt_text(salt: &[u8]) -> String {
+    if salt.is_empty() {
+        "-".to_owned()
+    } else {
+        encoding::hex_encode(salt)
+    }
+}
+
+/// Reads an NSEC/NSEC3 type bitmap running up to `end` (RFC 4034 4.1.2). Windows have to come in
+/// order and be 1 to 32 bytes long
+pub fn read_type_bitmap(buf: &mut buffer::DnsBuffer, end: usize) -> error::Result<Vec<u16>> {
+    let mut types = Vec::new();
+    let mut last_window = None;
+    while buf.pos < end {
+        let offset = buf.pos;
+        let window = buf.read()?;
+        let len = buf.read()? as usize;
+        if last_window.is_some_and(|w| w >= window) || !(1..=32).contains(&len) || buf.pos + len > end {
+            return Err(ParseError::BadRdataLength { offset, len });
+        }
+        last_window = Some(window);
+
+        for (i, byte) in buf.get_range(buf.pos, len)?.iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    types.push((window as u16) << 8 | (i * 8 + bit) as u16);
+                }
+            }
+        }
+        buf.step(len)?;
+    }
+    Ok(types)
+}
+
+/// Writes `types` as a type bitmap, they dont need to be sorted
+pub fn write_type_bitmap(buf: &mut buffer::DnsBuffer, types: &[u16]) -> error::Result<()> {
+    let mut types = types.to_vec();
+    types.sort_unstable();
+    types.dedup();
+
+    for window in types.chunk_by(|a, b| a >> 8 == b >> 8) {
+        let mut bits = [0u8; 32];
+        for t in window {
+            let low = (t & 0xFF) as usize;
+            bits[low / 8] |= 0x80 >> (low % 8);
+        }
+        let len = (window[window.len() - 1] & 0xFF) as usize / 8 + 1;
+        buf.write((window[0] >> 8) as u8)?;
+        buf.write(len as u8)?;
+        for b in &bits[..len] {
+            buf.write(*b)?;
+        }
+    }
+    Ok(())
 }
 
 impl RDataType {
@@ -39,35 +293,71 @@ impl RDataType {
         match num {
             1 => Self::A(None),
             2 => Self::NS(None),
+            5 => Self::CNAME(None),
+            6 => Self::SOA(None),
+            12 => Self::PTR(None),
+            15 => Self::MX(None),
             16 => Self::TXT(None),
             28 => Self::AAAA(None),
-            _ => Self::UNKNOWN(num)
+            33 => Self::SRV(None),
+            41 => Self::OPT(None),
+            43 => Self::DS(None),
+            46 => Self::RRSIG(None),
+            47 => Self::NSEC(None),
+            48 => Self::DNSKEY(None),
+            50 => Self::NSEC3(None),
+            51 => Self::NSEC3PARAM(None),
+            _ => Self::UNKNOWN(num, None)
         }
     }
 
     pub fn to_num(&self) -> u16 {
         match self {
-            RDataType::UNKNOWN(x) => *x,
+            RDataType::UNKNOWN(x, _) => *x,
             RDataType::NS(_) => 2,
+            RDataType::CNAME(_) => 5,
+            RDataType::SOA(_) => 6,
+            RDataType::PTR(_) => 12,
+            RDataType::MX(_) => 15,
+            RDataType::SRV(_) => 33,
+            RDataType::OPT(_) => 41,
             RDataType::A(_) => 1,
             RDataType::AAAA(_) => 28,
             RDataType::TXT(_) => 16,
+            RDataType::DS(_) => 43,
+            RDataType::RRSIG(_) => 46,
+            RDataType::NSEC(_) => 47,
+            RDataType::DNSKEY(_) => 48,
+            RDataType::NSEC3(_) => 50,
+            RDataType::NSEC3PARAM(_) => 51,
         }
     }
 
     pub fn has_data(&self) -> bool {
         match self {
-            RDataType::UNKNOWN(_) => true,
+            RDataType::UNKNOWN(_, op) => op.is_some(),
             RDataType::NS(op) => op.is_some(),
+            RDataType::CNAME(op) => op.is_some(),
+            RDataType::PTR(op) => op.is_some(),
+            RDataType::SOA(op) => op.is_some(),
+            RDataType::MX(op) => op.is_some(),
+            RDataType::SRV(op) => op.is_some(),
+            RDataType::OPT(op) => op.is_some(),
             RDataType::A(op) => op.is_some(),
             RDataType::AAAA(op) => op.is_some(),
             RDataType::TXT(op) => op.is_some(),
+            RDataType::DS(op) => op.is_some(),
+            RDataType::RRSIG(op) => op.is_some(),
+            RDataType::NSEC(op) => op.is_some(),
+            RDataType::DNSKEY(op) => op.is_some(),
+            RDataType::NSEC3(op) => op.is_some(),
+            RDataType::NSEC3PARAM(op) => op.is_some(),
         }
     }
 }
 
 // represents the class of a record
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum RClass {
     IN,
     SOMETHING(u16),
@@ -90,7 +380,7 @@ impl RClass {
 }
 
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DnsRecord {
     pub domain: Domain,
     pub rtype: RDataType,
@@ -105,25 +395,23 @@ pub enum RecordType {
 }
 
 impl  DnsRecord {
-    pub fn from_buf(buf: &mut buffer::DnsBuffer, record_type: RecordType, domains: &mut HashMap<String, u8>) -> anyhow::Result<DnsRecord> {
-        let domain: Domain;
-        let domain_text = buf.get_domain()?;
-
-        domain = match domains.get(&domain_text)  {
-            None => {
-                let off = domain_text
-                    .split('.')
-                    .map(|label| {label.len() as u8 + 1})
-                    .sum::<u8>()
-                    + 1;
-                domains.insert(domain_text.clone(), buf.pos as u8 - off);
-                Domain::Domain(domain_text)
-            }
-            Some(offset) => {
-                Domain::Jump(Box::new([0xC0, *offset]))
-            }
+    pub fn from_buf(buf: &mut buffer::DnsBuffer, record_type: RecordType, domains: &mut HashMap<String, u16>) -> error::Result<DnsRecord> {
+        // if the name itself starts with a jump, the name really lives where the jump points
+        let start = buf.pos;
+        let first = buf.get(start)?;
+        let offset = if (first & 0xC0) == 0xC0 {
+            (((first as u16) & 0x3F) << 8) | buf.get(start + 1)? as u16
+        } else {
+            start as u16
         };
 
+        let domain_text = buf.get_domain()?;
+        if start <= buffer::MAX_JUMP_OFFSET {
+            domains.entry(domain_text.clone()).or_insert(offset);
+        }
+        // names are always kept whole, compression is redone against the output buffer on write
+        let domain = Domain::Domain(domain_text);
+
         let mut rtype = RDataType::from_num(buf.read_u16()?);
         let rclass = RClass::from_num(buf.read_u16()?);
 
@@ -138,16 +426,28 @@ impl  DnsRecord {
             RecordType::OTHER => {
                 let ttl = buf.read_u32()?;
                 let data_len = buf.read_u16()?;
+                let rdata_start = buf.pos;
+                let rdata_end = rdata_start + data_len as usize;
+                let bad_len = ParseError::BadRdataLength { offset: rdata_start, len: data_len as usize };
+                if rdata_end > buf.buf.len() {
+                    return Err(bad_len);
+                }
+                // addresses have exactly one size, anything else would be read as garbage
+                let fixed_len = match rtype {
+                    RDataType::A(_) => Some(4),
+                    RDataType::AAAA(_) => Some(16),
+                    _ => None,
+                };
+                if fixed_len.is_some_and(|l| l != data_len) {
+                    return Err(bad_len);
+                }
+                // whatever is left of the rdata, for the fields that just run to its end
+                let rest = |buf: &buffer::DnsBuffer| rdata_end.checked_sub(buf.pos).ok_or(bad_len);
 
                 rtype = match rtype {
                     RDataType::A(_) => {
                         let raw_addr = buf.read_u32()?;
-                        RDataType::A(Some(Ipv4Addr::new(
-                                    ((raw_addr >> 24) & 0xFF) as u8,
-                                    ((raw_addr >> 16) & 0xFF) as u8,
-                                    ((raw_addr >> 8) & 0xFF) as u8,
-                                    ((raw_addr >> 0) & 0xFF) as u8,
-                                    )))
+                        RDataType::A(Some(Ipv4Addr::from(raw_addr)))
                     }
                     RDataType::AAAA(_) => {
                         let raw_addr1 = buf.read_u32()?;
@@ -156,26 +456,124 @@ impl  DnsRecord {
                         let raw_addr4 = buf.read_u32()?;
                         RDataType::AAAA(Some(Ipv6Addr::new(
                                     ((raw_addr1 >> 16) & 0xFFFF) as u16,
-                                    ((raw_addr1 >> 0) & 0xFFFF) as u16,
+                                    (raw_addr1 & 0xFFFF) as u16,
                                     ((raw_addr2 >> 16) & 0xFFFF) as u16,
-                                    ((raw_addr2 >> 0) & 0xFFFF) as u16,
+                                    (raw_addr2 & 0xFFFF) as u16,
                                     ((raw_addr3 >> 16) & 0xFFFF) as u16,
-                                    ((raw_addr3 >> 0) & 0xFFFF) as u16,
+                                    (raw_addr3 & 0xFFFF) as u16,
                                     ((raw_addr4 >> 16) & 0xFFFF) as u16,
-                                    ((raw_addr4 >> 0) & 0xFFFF) as u16,
+                                    (raw_addr4 & 0xFFFF) as u16,
                                     )))
                     }
                     RDataType::NS(_) => {
                         RDataType::NS(Some(buf.get_domain()?))
                     }
+                    RDataType::CNAME(_) => {
+                        RDataType::CNAME(Some(buf.get_domain()?))
+                    }
+                    RDataType::PTR(_) => {
+                        RDataType::PTR(Some(buf.get_domain()?))
+                    }
+                    RDataType::SOA(_) => {
+                        RDataType::SOA(Some(SoaData {
+                            mname: buf.get_domain()?,
+                            rname: buf.get_domain()?,
+                            serial: buf.read_u32()?,
+                            refresh: buf.read_u32()?,
+                            retry: buf.read_u32()?,
+                            expire: buf.read_u32()?,
+                            minimum: buf.read_u32()?,
+                        }))
+                    }
+                    RDataType::MX(_) => {
+                        RDataType::MX(Some(MxData {
+                            priority: buf.read_u16()?,
+                            exchange: buf.get_domain()?,
+                        }))
+                    }
+                    RDataType::SRV(_) => {
+                        RDataType::SRV(Some(SrvData {
+                            priority: buf.read_u16()?,
+                            weight: buf.read_u16()?,
+                            port: buf.read_u16()?,
+                            target: buf.get_domain()?,
+                        }))
+                    }
                     RDataType::TXT(_) => {
-                        RDataType::TXT(Some(String::from_utf8_lossy(buf.get_range(buf.pos, data_len as usize)?).to_string()))
+                        RDataType::TXT(Some(buf.get_range(buf.pos, data_len as usize)?.to_vec()))
+                    }
+                    RDataType::OPT(_) => {
+                        RDataType::OPT(Some(EdnsOption::read_all(buf, rdata_end)?))
+                    }
+                    RDataType::DNSKEY(_) => {
+                        let flags = buf.read_u16()?;
+                        let protocol = buf.read()?;
+                        let algorithm = buf.read()?;
+                        let public_key = buf.get_range(buf.pos, rest(buf)?)?.to_vec();
+                        RDataType::DNSKEY(Some(DnskeyData { flags, protocol, algorithm, public_key }))
+                    }
+                    RDataType::DS(_) => {
+                        let key_tag = buf.read_u16()?;
+                        let algorithm = buf.read()?;
+                        let digest_type = buf.read()?;
+                        let digest = buf.get_range(buf.pos, rest(buf)?)?.to_vec();
+                        RDataType::DS(Some(DsData { key_tag, algorithm, digest_type, digest }))
+                    }
+                    RDataType::RRSIG(_) => {
+                        let type_covered = buf.read_u16()?;
+                        let algorithm = buf.read()?;
+                        let labels = buf.read()?;
+                        let original_ttl = buf.read_u32()?;
+                        let expiration = buf.read_u32()?;
+                        let inception = buf.read_u32()?;
+                        let key_tag = buf.read_u16()?;
+                        let signer_name = buf.get_domain()?;
+                        let signature = buf.get_range(buf.pos, rest(buf)?)?.to_vec();
+                        RDataType::RRSIG(Some(RrsigData {
+                            type_covered, algorithm, labels, original_ttl, expiration, inception, key_tag, signer_name, signature,
+                        }))
                     }
-                    RDataType::UNKNOWN(x)=> {
-                        RDataType::UNKNOWN(x)
+                    RDataType::NSEC(_) => {
+                        let next_domain = buf.get_domain()?;
+                        rest(buf)?;
+                        let types = read_type_bitmap(buf, rdata_end)?;
+                        RDataType::NSEC(Some(NsecData { next_domain, types }))
+                    }
+                    RDataType::NSEC3(_) => {
+                        let hash_algorithm = buf.read()?;
+                        let flags = buf.read()?;
+                        let iterations = buf.read_u16()?;
+                        let salt_len = buf.read()? as usize;
+                        let salt = buf.get_range(buf.pos, salt_len)?.to_vec();
+                        buf.step(salt_len)?;
+                        let hash_len = buf.read()? as usize;
+                        let next_hashed = buf.get_range(buf.pos, hash_len)?.to_vec();
+                        buf.step(hash_len)?;
+                        rest(buf)?;
+                        let types = read_type_bitmap(buf, rdata_end)?;
+                        RDataType::NSEC3(Some(Nsec3Data { hash_algorithm, flags, iterations, salt, next_hashed, types }))
+                    }
+                    RDataType::NSEC3PARAM(_) => {
+                        let hash_algorithm = buf.read()?;
+                        let flags = buf.read()?;
+                        let iterations = buf.read_u16()?;
+                        let salt_len = buf.read()? as usize;
+                        let salt = buf.get_range(buf.pos, salt_len)?.to_vec();
+                        buf.step(salt_len)?;
+                        RDataType::NSEC3PARAM(Some(Nsec3ParamData { hash_algorithm, flags, iterations, salt }))
+                    }
+                    RDataType::UNKNOWN(x, _)=> {
+                        RDataType::UNKNOWN(x, Some(buf.get_range(buf.pos, data_len as usize)?.to_vec()))
                     }
                 };
 
+                // the names in there cant run past the rdata either. After that always land right
+                // after the rdata, whatever the parser above consumed
+                if buf.pos > rdata_end {
+                    return Err(bad_len);
+                }
+                buf.seek(rdata_end)?;
+
                 Ok(DnsRecord {
                     domain,
                     rtype,
@@ -187,109 +585,152 @@ impl  DnsRecord {
         }
     }
 
-    pub fn write(&self, buf: &mut buffer::DnsBuffer, domain_jumps: &HashMap<String, u8>) -> anyhow::Result<()> {
+    /// Writes the record, compressing names against `domain_jumps` and adding the new ones to it.
+    /// Records without a ttl are questions and stop after the class, for the rest the rdata length
+    /// is computed from what actually got written, `data_len` is ignored
+    pub fn write(&self, buf: &mut buffer::DnsBuffer, domain_jumps: &mut HashMap<String, u16>) -> error::Result<()> {
         match &self.domain {
             Domain::Domain(domain) => {
-                let mut found = false;
-                for d in domain_jumps.keys() {
-                    if let Some(idx) = domain.find(d) {
-                        let (new, repeat) = domain.split_at(idx);
-                        let new = new.trim_end_matches(|c| c == '.');
-                        buf.write(new.len() as u8)?;
-                        for b in new.as_bytes() {
-                            buf.write(*b)?;
-                        }
-                        buf.write(0xC0)?;
-                        buf.write(*domain_jumps.get(repeat).unwrap())?;
-                        found = true;
-                        break;
-                    }
-                }
-
-                if !found {
-                    for label in domain.split('.') {
-                        let len = label.len();
-                        if len > 63 {
-                            return Err(anyhow::anyhow!("write_record error: exceeded max label lenght of 63"))
-                        }
-                        buf.write(len as u8)?;
-                        for byte in label.as_bytes() {
-                            buf.write(*byte)?;
-                        }
-                    }
-                    buf.write(0)?;
-                }
+                buf.write_domain(domain, domain_jumps)?;
             }
-            Domain::Jump(jump_code) => {
-                buf.write(jump_code[0])?;
-                buf.write(jump_code[1])?;
+            Domain::Jump(offset) => {
+                buf.write_u16(0xC000 | (*offset & 0x3FFF))?;
             }
         }
 
         buf.write_u16(self.rtype.to_num())?;
         buf.write_u16(self.rclass.to_num())?;
 
-        if let Some(ttl) = self.ttl {
-            buf.write_u32(ttl)?;
-        }
-        if let Some(dlen) = self.data_len {
-            buf.write_u16(dlen)?;
-        }
-        
-        // garantees self.rtype has data and is safe to unwrap
-        if !self.rtype.has_data() {
-            return Ok(());
-        }
+        let ttl = match self.ttl {
+            Some(ttl) => ttl,
+            None => return Ok(()),
+        };
+        buf.write_u32(ttl)?;
+
+        // placeholder, patched once the rdata is written
+        let len_pos = buf.pos;
+        buf.write_u16(0)?;
 
-        match &self.rtype {
-            RDataType::A(data) => {
-                for o in data.unwrap().octets() {
-                    buf.write(o)?;
+        // garantees self.rtype has data and is safe to unwrap
+        if self.rtype.has_data() {
+            match &self.rtype {
+                RDataType::A(data) => {
+                    for o in data.unwrap().octets() {
+                        buf.write(o)?;
+                    }
                 }
-            }
-            RDataType::AAAA(data) => {
-                for o in data.unwrap().octets() {
-                    buf.write(o)?;
+                RDataType::AAAA(data) => {
+                    for o in data.unwrap().octets() {
+                        buf.write(o)?;
+                    }
                 }
-            }
-            RDataType::NS(data) | RDataType::TXT(data) => {
-                let mut found = false;
-                for d in domain_jumps.keys() {
-                    if let Some(idx) = data.as_ref().unwrap().find(d) {
-                        let (new, repeat) = data.as_ref().unwrap().split_at(idx);
-                        let new = new.trim_end_matches(|c| c == '.');
-                        buf.write(new.len() as u8)?;
-                        for b in new.as_bytes() {
-                            buf.write(*b)?;
-                        }
-                        buf.write(0xC0)?;
-                        buf.write(*domain_jumps.get(repeat).unwrap())?;
-                        found = true;
-                        break;
+                RDataType::NS(data) | RDataType::CNAME(data) | RDataType::PTR(data) => {
+                    buf.write_domain(data.as_ref().unwrap(), domain_jumps)?;
+                }
+                RDataType::SOA(data) => {
+                    let soa = data.as_ref().unwrap();
+                    buf.write_domain(&soa.mname, domain_jumps)?;
+                    buf.write_domain(&soa.rname, domain_jumps)?;
+                    buf.write_u32(soa.serial)?;
+                    buf.write_u32(soa.refresh)?;
+                    buf.write_u32(soa.retry)?;
+                    buf.write_u32(soa.expire)?;
+                    buf.write_u32(soa.minimum)?;
+                }
+                RDataType::MX(data) => {
+                    let mx = data.as_ref().unwrap();
+                    buf.write_u16(mx.priority)?;
+                    buf.write_domain(&mx.exchange, domain_jumps)?;
+                }
+                RDataType::SRV(data) => {
+                    // RFC 2782 says the srv target must not be compressed
+                    let srv = data.as_ref().unwrap();
+                    buf.write_u16(srv.priority)?;
+                    buf.write_u16(srv.weight)?;
+                    buf.write_u16(srv.port)?;
+                    buf.write_plain_domain(&srv.target)?;
+                }
+                RDataType::TXT(data) => {
+                    for b in data.as_ref().unwrap() {
+                        buf.write(*b)?;
                     }
                 }
-
-                if !found {
-                    for label in data.as_ref().unwrap().split('.') { 
-                        let len = label.len();
-                        if len > 63 {
-                            return Err(anyhow::anyhow!("write_record error: exceeded max label lenght of 63"))
-                        }
-                        buf.write(len as u8)?;
-                        for byte in label.as_bytes() {
-                            buf.write(*byte)?;
-                        }
+                RDataType::OPT(data) => {
+                    for option in data.as_ref().unwrap() {
+                        option.write(buf)?;
                     }
-                    buf.write(0)?;
                 }
-            }
-            RDataType::UNKNOWN(_) => {
-                for b in "could read data, unknow type".to_owned().as_bytes() {
-                    buf.write(*b)?;
+                RDataType::DNSKEY(data) => {
+                    let key = data.as_ref().unwrap();
+                    buf.write_u16(key.flags)?;
+                    buf.write(key.protocol)?;
+                    buf.write(key.algorithm)?;
+                    write_bytes(buf, &key.public_key)?;
+                }
+                RDataType::DS(data) => {
+                    let ds = data.as_ref().unwrap();
+                    buf.write_u16(ds.key_tag)?;
+                    buf.write(ds.algorithm)?;
+                    buf.write(ds.digest_type)?;
+                    write_bytes(buf, &ds.digest)?;
+                }
+                RDataType::RRSIG(data) => {
+                    // RFC 4034 3.1.7 and 4.1.1, the signer and next names are never compressed
+                    let sig = data.as_ref().unwrap();
+                    buf.write_u16(sig.type_covered)?;
+                    buf.write(sig.algorithm)?;
+                    buf.write(sig.labels)?;
+                    buf.write_u32(sig.original_ttl)?;
+                    buf.write_u32(sig.expiration)?;
+                    buf.write_u32(sig.inception)?;
+                    buf.write_u16(sig.key_tag)?;
+                    buf.write_plain_domain(&sig.signer_name)?;
+                    write_bytes(buf, &sig.signature)?;
+                }
+                RDataType::NSEC(data) => {
+                    let nsec = data.as_ref().unwrap();
+                    buf.write_plain_domain(&nsec.next_domain)?;
+                    write_type_bitmap(buf, &nsec.types)?;
+                }
+                RDataType::NSEC3(data) => {
+                    let nsec3 = data.as_ref().unwrap();
+                    buf.write(nsec3.hash_algorithm)?;
+                    buf.write(nsec3.flags)?;
+                    buf.write_u16(nsec3.iterations)?;
+                    write_short_bytes(buf, &nsec3.salt)?;
+                    write_short_bytes(buf, &nsec3.next_hashed)?;
+                    write_type_bitmap(buf, &nsec3.types)?;
+                }
+                RDataType::NSEC3PARAM(data) => {
+                    let param = data.as_ref().unwrap();
+                    buf.write(param.hash_algorithm)?;
+                    buf.write(param.flags)?;
+                    buf.write_u16(param.iterations)?;
+                    write_short_bytes(buf, &param.salt)?;
+                }
+                RDataType::UNKNOWN(_, data) => {
+                    for b in data.as_ref().unwrap() {
+                        buf.write(*b)?;
+                    }
                 }
             }
         }
+
+        buf.set_u16(len_pos, (buf.pos - len_pos - 2) as u16)?;
         Ok(())
     }
 }
 
+fn write_bytes(buf: &mut buffer::DnsBuffer, bytes: &[u8]) -> error::Result<()> {
+    for b in bytes {
+        buf.write(*b)?;
+    }
+    Ok(())
+}
+
+// a length byte and then the bytes, for salts and hashes
+fn write_short_bytes(buf: &mut buffer::DnsBuffer, bytes: &[u8]) -> error::Result<()> {
+    let len = u8::try_from(bytes.len()).map_err(|_| ParseError::BadRdataLength { offset: buf.pos, len: bytes.len() })?;
+    buf.write(len)?;
+    write_bytes(buf, bytes)
+}
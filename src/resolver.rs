@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use anyhow;
+use crate::{packet::DnsPacket, header::ResultCode, record::{DnsRecord, Domain, RClass, RDataType}, edns::{self, Edns}, upstream};
+
+/// How many referrals one lookup can follow before giving up
+pub const MAX_REFERRALS: usize = 16;
+/// How deep lookups of nameserver addresses without glue can nest
+pub const MAX_NS_DEPTH: usize = 4;
+/// Delegations are kept at most this long, whatever the NS ttl says
+pub const MAX_DELEGATION_TTL: u32 = 24 * 60 * 60;
+/// How many zone cuts the resolver remembers, the ones closest to expiring go first
+pub const MAX_DELEGATIONS: usize = 10_000;
+
+/// The IANA root servers, a to m
+pub fn root_hints() -> Vec<SocketAddr> {
+    [
+        [198, 41, 0, 4],
+        [170, 247, 170, 2],
+        [192, 33, 4, 12],
+        [199, 7, 91, 13],
+        [192, 203, 230, 10],
+        [192, 5, 5, 241],
+        [192, 112, 36, 4],
+        [198, 97, 190, 53],
+        [192, 36, 148, 17],
+        [192, 58, 128, 30],
+        [193, 0, 14, 129],
+        [199, 7, 83, 42],
+        [202, 12, 27, 33],
+    ].iter().map(|ip| SocketAddr::from((Ipv4Addr::from(*ip), 53))).collect()
+}
+
+/// Iterative resolver, walks down from the root hints following referrals like a recursive
+/// server does instead of asking someone else to do it. The zone cuts it goes through are
+/// remembered for as long as their NS and glue ttls say, so the next lookup under one of them
+/// starts there instead of at the root. Clones share what was learned
+#[derive(Debug, Clone)]
+pub struct Resolver {
+    pub root_hints: Vec<SocketAddr>,
+    pub port: u16, // port used for every nameserver found on the way, only the hints have their own
+    pub timeout: Duration,
+    pub dnssec_ok: bool, // ask for the signatures too, for validating what comes back
+    delegations: Arc<Mutex<Delegations>>,
+}
+
+// lowercase zone to its servers and when they stop being good
+type Delegations = HashMap<String, (Vec<SocketAddr>, Instant)>;
+
+// what a response means for the lookup
+enum Step {
+    Done,
+    Referral(String, Vec<String>), // child zone and its nameservers
+    Lame,
+}
+
+impl Resolver {
+    pub fn new(root_hints: Vec<SocketAddr>, timeout: Duration) -> Resolver {
+        Resolver {
+            root_hints,
+            port: 53,
+            timeout,
+            dnssec_ok: false,
+            delegations: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Number of delegations remembered, expired ones included until they get cleaned out
+    pub fn delegations(&self) -> usize {
+        self.delegations.lock().unwrap_or_else(PoisonError::into_inner).len()
+    }
+
+    /// Looks up `question` starting from the closest delegation already known, the root if there
+    /// is none. What comes back is the response of the server that had the final say, answer,
+    /// NXDOMAIN or NODATA
+    pub fn resolve(&self, question: &DnsRecord) -> anyhow::Result<DnsPacket> {
+        self.resolve_at(question, 0)
+    }
+
+    fn resolve_at(&self, question: &DnsRecord, depth: usize) -> anyhow::Result<DnsPacket> {
+        if depth > MAX_NS_DEPTH {
+            return Err(anyhow::anyhow!("resolve error: nameserver lookups nested too deep"));
+        }
+        let qname = question.domain.name()
+            .ok_or_else(|| anyhow::anyhow!("resolve error: question has no name"))?
+            .to_owned();
+
+        // the DS of a zone is with its parent, the zone's own servers dont have it (RFC 4035 3.1.4.1)
+        let start = match question.rtype {
+            RDataType::DS(_) => qname.split_once('.').map_or("", |(_, parent)| parent),
+            _ => &qname,
+        };
+        let (zone, servers) = match self.closest_delegation(start) {
+            Some(cut) => cut,
+            None => return self.iterate(question, &qname, String::new(), self.root_hints.clone(), depth),
+        };
+        match self.iterate(question, &qname, zone.clone(), servers, depth) {
+            Ok(res) => Ok(res),
+            // the servers might have moved since, the root knows where they went
+            Err(_) => {
+                self.forget(&zone);
+                self.iterate(question, &qname, String::new(), self.root_hints.clone(), depth)
+            }
+        }
+    }
+
+    // follows referrals down from `servers`, which are the ones for `zone`
+    fn iterate(&self, question: &DnsRecord, qname: &str, mut zone: String, mut servers: Vec<SocketAddr>, depth: usize) -> anyhow::Result<DnsPacket> {
+        'referral: for _ in 0..MAX_REFERRALS {
+            let mut last_err = anyhow::anyhow!("resolve error: no servers for zone {:?}", zone);
+            for server in servers.iter() {
+                let res = match self.query(question, *server) {
+                    Ok(res) => res,
+                    Err(e) => {
+                        last_err = e;
+                        continue;
+                    }
+                };
+
+                match step(&res, qname, &zone) {
+                    Step::Done => return Ok(in_bailiwick(res, &zone)),
+                    Step::Referral(child, nameservers) => {
+                        let (addrs, ttl) = self.nameserver_addrs(&res, &zone, &child, &nameservers, depth);
+                        if addrs.is_empty() {
+                            last_err = anyhow::anyhow!("resolve error: no address for any nameserver of {:?}", child);
+                            continue;
+                        }
+                        self.remember(&child, addrs.clone(), ttl);
+                        zone = child;
+                        servers = addrs;
+                        continue 'referral;
+                    }
+                    Step::Lame => {
+                        last_err = anyhow::anyhow!("resolve error: {} is lame for {:?}", server, zone);
+                    }
+                }
+            }
+            return Err(last_err);
+        }
+        Err(anyhow::anyhow!("resolve error: more than {} referrals for {}", MAX_REFERRALS, qname))
+    }
+
+    fn query(&self, question: &DnsRecord, server: SocketAddr) -> anyhow::Result<DnsPacket> {
+        let mut pack = DnsPacket::new();
+        pack.header.id = upstream::random_id();
+        pack.questions.push(question.clone());
+        let mut edns = Edns::new(edns::SERVER_UDP_PAYLOAD);
+        edns.dnssec_ok = self.dnssec_ok;
+        pack.set_edns(Some(edns));
+
+        upstream::exchange(&pack, server, self.timeout)
+    }
+
+    // glue first, and only when there is none the nameserver names get looked up themselves.
+    // Also how long the delegation to `child` from `zone` is good for, the smallest ttl of the NS
+    // set and the addresses
+    fn nameserver_addrs(&self, res: &DnsPacket, zone: &str, child: &str, nameservers: &[String], depth: usize) -> (Vec<SocketAddr>, u32) {
+        let ns_ttl = res.authorities.iter()
+            .filter(|r| matches!(r.rtype, RDataType::NS(_)) && r.domain.name().is_some_and(|n| n.eq_ignore_ascii_case(child)))
+            .filter_map(|r| r.ttl)
+            .min()
+            .unwrap_or(0);
+        let (mut addrs, mut ttl) = glue(res, zone, nameservers, self.port);
+        if !addrs.is_empty() {
+            return (addrs, ttl.min(ns_ttl));
+        }
+
+        ttl = ns_ttl;
+        for ns in nameservers {
+            let question = DnsRecord {
+                domain: Domain::Domain(ns.clone()),
+                rtype: RDataType::A(None),
+                rclass: RClass::IN,
+                ttl: None,
+                data_len: None,
+            };
+            if let Ok(ns_res) = self.resolve_at(&question, depth + 1) {
+                for rec in ns_res.answers.iter() {
+                    if let RDataType::A(Some(ip)) = rec.rtype {
+                        addrs.push(SocketAddr::new(IpAddr::V4(ip), self.port));
+                        ttl = ttl.min(rec.ttl.unwrap_or(0));
+                    }
+                }
+            }
+            if !addrs.is_empty() {
+                break;
+            }
+        }
+        (addrs, ttl)
+    }
+
+    // the deepest zone cut above `name` that is still good, with its servers
+    fn closest_delegation(&self, name: &str) -> Option<(String, Vec<SocketAddr>)> {
+        let name = name.trim_end_matches('.').to_lowercase();
+        let delegations = self.delegations.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        let mut zone = name.as_str();
+        loop {
+            if let Some((servers, expires)) = delegations.get(zone) {
+                if *expires > now {
+                    return Some((zone.to_owned(), servers.clone()));
+                }
+            }
+            zone = zone.split_once('.')?.1;
+        }
+    }
+
+    // a full map is cleaned of what expired first, and if that wasnt enough whatever expires
+    // soonest goes
+    fn remember(&self, zone: &str, servers: Vec<SocketAddr>, ttl: u32) {
+        if ttl == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let expires = now + Duration::from_secs(ttl.min(MAX_DELEGATION_TTL) as u64);
+        let zone = zone.trim_end_matches('.').to_lowercase();
+        let mut delegations = self.delegations.lock().unwrap_or_else(PoisonError::into_inner);
+        if delegations.len() >= MAX_DELEGATIONS && !delegations.contains_key(&zone) {
+            delegations.retain(|_, (_, expires)| *expires > now);
+        }
+        if delegations.len() >= MAX_DELEGATIONS && !delegations.contains_key(&zone) {
+            let soonest = delegations.iter().min_by_key(|(_, (_, expires))| *expires).map(|(z, _)| z.clone());
+            if let Some(soonest) = soonest {
+                delegations.remove(&soonest);
+            }
+        }
+        delegations.insert(zone, (servers, expires));
+    }
+
+    fn forget(&self, zone: &str) {
+        self.delegations.lock().unwrap_or_else(PoisonError::into_inner).remove(zone);
+    }
+}
+
+fn step(res: &DnsPacket, qname: &str, zone: &str) -> Step {
+    match res.header.rescode {
+        ResultCode::NOERROR => {},
+        ResultCode::NXDOMAIN => return Step::Done,
+        _ => return Step::Lame,
+    }
+    if !res.answers.is_empty() || res.header.authoritative_answer {
+        return Step::Done;
+    }
+
+    let mut child = None;
+    let mut nameservers = Vec::new();
+    for rec in res.authorities.iter() {
+        if let (Some(owner), RDataType::NS(Some(ns))) = (rec.domain.name(), &rec.rtype) {
+            child.get_or_insert_with(|| owner.to_lowercase());
+            if child.as_deref().is_some_and(|c| c.eq_ignore_ascii_case(owner)) {
+                nameservers.push(ns.to_lowercase());
+            }
+        }
+    }
+
+    match child {
+        // a referral has to get closer to the name, anything else is a lame server
+        Some(child) if child != zone && in_zone(&child, zone) && in_zone(qname, &child) => {
+            Step::Referral(child, nameservers)
+        }
+        Some(_) => Step::Lame,
+        // no answer, no referral, not authoritative either, treat it like NODATA
+        None => Step::Done,
+    }
+}
+
+// only what the server of `zone` has a say about, records for names outside of it could be
+// anything and would end up in the cache. The OPT isnt a record about any name and stays
+fn in_bailiwick(mut res: DnsPacket, zone: &str) -> DnsPacket {
+    let keep = |r: &DnsRecord| matches!(r.rtype, RDataType::OPT(_)) || r.domain.name().is_some_and(|n| in_zone(n, zone));
+    res.answers.retain(keep);
+    res.authorities.retain(keep);
+    res.resources.retain(keep);
+    res
+}
+
+// the addresses of the nameservers in the additional section and their smallest ttl. Only glue
+// from inside `zone`, the one the referring server is for, it has no say about anything else
+fn glue(res: &DnsPacket, zone: &str, nameservers: &[String], port: u16) -> (Vec<SocketAddr>, u32) {
+    let mut v4 = Vec::new();
+    let mut v6 = Vec::new();
+    let mut ttl = u32::MAX;
+    for rec in res.resources.iter() {
+        let is_ns = rec.domain.name().is_some_and(|n| nameservers.iter().any(|ns| ns.eq_ignore_ascii_case(n)) && in_zone(n, zone));
+        if !is_ns {
+            continue;
+        }
+        match rec.rtype {
+            RDataType::A(Some(ip)) => v4.push(SocketAddr::new(IpAddr::V4(ip), port)),
+            RDataType::AAAA(Some(ip)) => v6.push(SocketAddr::new(IpAddr::V6(ip), port)),
+            _ => continue,
+        }
+        ttl = ttl.min(rec.ttl.unwrap_or(0));
+    }
+    // v4 first, plenty of places still cant reach v6
+    v4.extend(v6);
+    (v4, ttl)
+}
+
+/// If `name` is `zone` or somewhere under it, "" being the root
+pub fn in_zone(name: &str, zone: &str) -> bool {
+    let name = name.trim_end_matches('.');
+    let zone = zone.trim_end_matches('.');
+    if zone.is_empty() || name.eq_ignore_ascii_case(zone) {
+        return true;
+    }
+    let (name, zone) = (name.as_bytes(), zone.as_bytes());
+    name.len() > zone.len()
+        && name[name.len() - zone.len()..].eq_ignore_ascii_case(zone)
+        && name[name.len() - zone.len() - 1] == b'.'
+}
@@ -1,43 +1,529 @@
-use std::net::{UdpSocket, SocketAddr};
-use std::collections::HashMap;
-use anyhow;
-use crate::{buffer::DnsBuffer, packet::DnsPacket};
+use std::net::{UdpSocket, SocketAddr, TcpListener};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, PoisonError, atomic::{AtomicUsize, Ordering}, mpsc::{self, TrySendError}};
+use std::thread::{self, JoinHandle};
+use anyhow::{self, Context};
+use socket2::{self, Protocol, Socket, Type};
+use crate::{config::{Mode, ServerConfig}, buffer::DnsBuffer, packet::DnsPacket, header::{DnsHeader, ResultCode}, record::{DnsRecord, Domain, RDataType}, edns::{self, Edns}, resolver::{self, Resolver}, zone::Zone, tcp, tls, doh, upstream::{self, Upstreams}, dnssec::{self, Security, Validator}, signer::SigningKey};
+
+/// How many cnames are chased before giving up on a chain
+pub const MAX_CNAME_DEPTH: usize = 8;
+
+/// Udp queries waiting for a free worker, past this new ones are dropped and left to the client to retry
+pub const UDP_QUEUE_SIZE: usize = 1024;
+
+/// What every query goes through, udp or tcp. It writes the response into the buffer, whose
+/// max_size is already set to what the transport allows
+pub type Handler = dyn Fn(DnsPacket, &mut DnsBuffer) -> anyhow::Result<()> + Send + Sync;
 
 pub struct Server {
-    sock: UdpSocket,
+    pub config: ServerConfig,
+    resolver: Resolver,
+    upstreams: Upstreams,
+    validator: Option<Validator>, // only with config.dnssec
+    zones: Vec<Zone>,
+    udp: Vec<UdpSocket>,
+    tcp: Vec<TcpListener>,
+    tls: Vec<TcpListener>,
+    tls_config: Option<Arc<rustls::ServerConfig>>,
+    https: Vec<TcpListener>,
+    https_config: Option<Arc<rustls::ServerConfig>>, // same certificate, but with h2 for ALPN
+    connections: Arc<AtomicUsize>, // tcp and DoT ones open right now
 }
 
 impl Server {
-    pub fn new(string: &str) -> Server {
-        Server {
-            sock: UdpSocket::bind((string, 3000)).unwrap()
+    /// Loads the zones (signed with their keys, if they have any), then binds a udp socket and a
+    /// tcp listener on every listen address of the config, and the DoT and DoH listeners if there is a certificate
+    pub fn new(config: ServerConfig) -> anyhow::Result<Server> {
+        let mut zones = Vec::new();
+        for zone_config in config.zones.iter() {
+            let mut zone = Zone::from_file(&zone_config.file, &zone_config.origin)?;
+            if !zone_config.keys.is_empty() {
+                let keys = zone_config.keys.iter()
+                    .map(SigningKey::from_file)
+                    .collect::<anyhow::Result<Vec<SigningKey>>>()?;
+                zone.sign_with(keys)?;
+            }
+            zones.push(zone);
+        }
+
+        let mut udp = Vec::new();
+        let mut tcp = Vec::new();
+        for addr in config.listen.iter() {
+            let sock = bind_udp(*addr).with_context(|| format!("server error: couldnt bind udp on {}", addr))?;
+            // with port 0 tcp goes on whatever port udp got, so both are reachable at local_addrs
+            let addr = sock.local_addr()?;
+            udp.push(sock);
+            tcp.push(bind_tcp(addr).with_context(|| format!("server error: couldnt bind tcp on {}", addr))?);
+        }
+        let mut tls = Vec::new();
+        let mut https = Vec::new();
+        let (tls_config, https_config) = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => (Some(tls::server_config(cert, key, tls::DOT_ALPN)?), Some(tls::server_config(cert, key, doh::DOH_ALPN)?)),
+            _ => (None, None),
+        };
+        if tls_config.is_some() {
+            for addr in config.tls_listen.iter() {
+                tls.push(bind_tcp(*addr).with_context(|| format!("server error: couldnt bind tls on {}", addr))?);
+            }
+            for addr in config.https_listen.iter() {
+                https.push(bind_tcp(*addr).with_context(|| format!("server error: couldnt bind https on {}", addr))?);
+            }
         }
+
+        let mut resolver = Resolver::new(config.root_hints.clone(), config.upstream_timeout());
+        resolver.dnssec_ok = config.dnssec;
+        let mut upstreams = Upstreams::new(&config.upstreams, config.upstream_timeout(), config.upstream_retries);
+        if !config.tls_upstreams.is_empty() {
+            upstreams = upstreams.with_tls(&config.tls_upstreams, tls::client_config(&config.tls_ca_file, tls::DOT_ALPN)?);
+        }
+        if !config.https_upstreams.is_empty() {
+            let client = doh::Client::new(tls::client_config(&config.tls_ca_file, doh::DOH_ALPN)?)?;
+            upstreams = upstreams.with_https(&config.https_upstreams, client);
+        }
+        let validator = config.dnssec.then(|| Validator::new(config.trust_anchors.clone()));
+        let connections = Arc::new(AtomicUsize::new(0));
+        Ok(Server { config, resolver, upstreams, validator, zones, udp, tcp, tls, tls_config, https, https_config, connections })
+    }
+
+    /// Where the sockets actually ended up, useful when the config asked for port 0
+    pub fn local_addrs(&self) -> anyhow::Result<Vec<SocketAddr>> {
+        Ok(self.udp.iter().map(|s| s.local_addr()).collect::<Result<_, _>>()?)
     }
 
-    pub fn get_query(&self, buf: &mut DnsBuffer) -> anyhow::Result<(DnsPacket, SocketAddr)> {
-        let (_, from) = self.sock.recv_from(&mut buf.buf)?;
-        Ok((DnsPacket::from_buf(buf)?, from))
+    /// Where the DoT listeners ended up, empty without a certificate
+    pub fn tls_addrs(&self) -> anyhow::Result<Vec<SocketAddr>> {
+        Ok(self.tls.iter().map(|l| l.local_addr()).collect::<Result<_, _>>()?)
     }
-    
-    pub fn respond_with(&self, buf: &DnsBuffer, to: SocketAddr) -> anyhow::Result<()> {
-        let _ = self.sock.send_to(&buf.buf[0..buf.pos], to)?;
+
+    /// Where the DoH listeners ended up, empty without a certificate
+    pub fn https_addrs(&self) -> anyhow::Result<Vec<SocketAddr>> {
+        Ok(self.https.iter().map(|l| l.local_addr()).collect::<Result<_, _>>()?)
+    }
+
+    /// Starts accepting tcp connections in the background, each one gets its own thread and
+    /// every query on it goes through `handler`, same as the udp ones. Past
+    /// `config.max_connections` open ones, tcp and DoT together, new connections are closed
+    /// as soon as they are accepted
+    pub fn serve_tcp(&self, handler: Arc<Handler>) -> anyhow::Result<()> {
+        for listener in self.tcp.iter() {
+            let listener = listener.try_clone()?;
+            let handler = handler.clone();
+            let idle_timeout = self.config.tcp_idle_timeout();
+            let (open, max) = (self.connections.clone(), self.config.max_connections);
+
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = match stream {
+                        Ok(stream) => stream,
+                        Err(_) => continue,
+                    };
+                    // dropping the stream is what closes it
+                    let slot = match ConnectionSlot::take(&open, max) {
+                        Some(slot) => slot,
+                        None => continue,
+                    };
+                    let handler = handler.clone();
+                    thread::spawn(move || {
+                        let _slot = slot;
+                        // a client that stops reading is as good as gone, it doesnt get to hold a slot
+                        if stream.set_read_timeout(Some(idle_timeout)).and_then(|_| stream.set_write_timeout(Some(idle_timeout))).is_ok() {
+                            let _ = tcp::serve_connection(&mut stream, &*handler);
+                        }
+                    });
+                }
+            });
+        }
         Ok(())
     }
 
+    /// Same as `serve_tcp` for the DoT listeners (RFC 7858), the TLS handshake comes first and
+    /// then it is the same framing. Does nothing without a certificate
+    pub fn serve_tls(&self, handler: Arc<Handler>) -> anyhow::Result<()> {
+        let config = match &self.tls_config {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+        for listener in self.tls.iter() {
+            let listener = listener.try_clone()?;
+            let handler = handler.clone();
+            let config = config.clone();
+            let idle_timeout = self.config.tcp_idle_timeout();
+            let (open, max) = (self.connections.clone(), self.config.max_connections);
+
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(_) => continue,
+                    };
+                    let slot = match ConnectionSlot::take(&open, max) {
+                        Some(slot) => slot,
+                        None => continue,
+                    };
+                    let handler = handler.clone();
+                    let config = config.clone();
+                    thread::spawn(move || {
+                        let _slot = slot;
+                        // a client that stops reading is as good as gone, it doesnt get to hold a slot
+                        if stream.set_read_timeout(Some(idle_timeout)).and_then(|_| stream.set_write_timeout(Some(idle_timeout))).is_ok() {
+                            let _ = tls::serve_connection(stream, config, &*handler);
+                        }
+                    });
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Starts answering DoH (RFC 8484) on the https listeners, queries go through `handler` like
+    /// everywhere else. Does nothing without a certificate
+    pub fn serve_https(&self, handler: Arc<Handler>) -> anyhow::Result<()> {
+        let config = match &self.https_config {
+            Some(config) if !self.https.is_empty() => config.clone(),
+            _ => return Ok(()),
+        };
+        let listeners = self.https.iter().map(|l| l.try_clone()).collect::<Result<Vec<_>, _>>()?;
+        doh::serve(listeners, config, handler, self.config.tcp_idle_timeout())
+    }
+
+    /// Starts `config.workers` threads answering udp queries through `handler`, and a thread per
+    /// udp socket that only reads and hands the queries to them. A slow query only holds up its
+    /// own worker. The reader threads are returned
+    pub fn serve_udp(&self, handler: Arc<Handler>) -> anyhow::Result<Vec<JoinHandle<()>>> {
+        let (jobs, queue) = mpsc::sync_channel::<UdpJob>(UDP_QUEUE_SIZE);
+        let queue = Arc::new(Mutex::new(queue));
+        for _ in 0..self.config.workers {
+            let queue = queue.clone();
+            let handler = handler.clone();
+            thread::spawn(move || loop {
+                // own statement, so the lock is let go before the query gets handled
+                let job = queue.lock().unwrap_or_else(PoisonError::into_inner).recv();
+                match job {
+                    Ok(job) => {
+                        if let Err(e) = answer_udp(job, &*handler) {
+                            println!("udp error: {:#}", e);
+                        }
+                    }
+                    Err(_) => return,
+                }
+            });
+        }
+
+        let mut threads = Vec::new();
+        for sock in self.udp.iter() {
+            let sock = Arc::new(sock.try_clone()?);
+            let jobs = jobs.clone();
+
+            threads.push(thread::spawn(move || loop {
+                let mut buf = DnsBuffer::with_size(edns::SERVER_UDP_PAYLOAD as usize);
+                let (len, from) = match sock.recv_from(&mut buf.buf) {
+                    Ok(res) => res,
+                    Err(e) => {
+                        println!("udp error: {:#}", e);
+                        continue;
+                    }
+                };
+                buf.buf.truncate(len);
+
+                match jobs.try_send(UdpJob { sock: sock.clone(), buf, from }) {
+                    Ok(()) | Err(TrySendError::Full(_)) => {},
+                    Err(TrySendError::Disconnected(_)) => return,
+                }
+            }));
+        }
+        Ok(threads)
+    }
+
+    /// The zones we answer for with authority
+    pub fn zones(&self) -> &[Zone] {
+        &self.zones
+    }
+
+    /// The answer from our own zones, if the question falls in one of them. The most specific zone
+    /// wins when they nest. Signed zones add their RRSIGs and NSECs for clients that set DO
+    pub fn authoritative(&self, pack: &DnsPacket) -> Option<DnsPacket> {
+        let question = pack.questions.first()?;
+        let name = question.domain.name()?;
+        let zone = self.zones.iter()
+            .filter(|z| resolver::in_zone(name, &z.origin))
+            .max_by_key(|z| z.origin.len())?;
+
+        let mut res = zone.answer(question);
+        if pack.edns().is_some_and(|e| e.dnssec_ok) {
+            zone.sign(&mut res);
+        }
+        res.header.id = pack.header.id;
+        res.header.opcode = pack.header.opcode;
+        res.header.recursion_desired = pack.header.recursion_desired;
+        res.header.recursion_available = true;
+        Some(res)
+    }
+
+    /// Answers the query, see `lookup`. When the answer is a cname chain that stops before an
+    /// answer of the asked type, the rest of the chain is looked up too and added to the answers.
+    /// If the answer cant be had at all the client gets a SERVFAIL. With dnssec on the answer is
+    /// validated unless the client set CD, see `validate`. The DNSSEC records the client didnt
+    /// ask for are stripped
     pub fn resolve(&self, pack: &DnsPacket) -> anyhow::Result<DnsPacket> {
-        let server = ("8.8.8.8", 53);
+        let mut res = self.resolve_unstripped(pack)?;
+        if !pack.edns().is_some_and(|e| e.dnssec_ok) {
+            dnssec::strip(&mut res);
+        }
+        Ok(res)
+    }
+
+    /// Like `resolve` but the RRSIGs and NSECs stay whatever the client asked for, this is what
+    /// goes in the cache so clients with DO can be answered from it too
+    pub fn resolve_unstripped(&self, pack: &DnsPacket) -> anyhow::Result<DnsPacket> {
+        let mut res = self.follow_cnames(pack)?;
+        if self.validator.is_some() && !self.validate(pack, &mut res) {
+            return Ok(pack.response_to(ResultCode::SERVFAIL));
+        }
+        Ok(res)
+    }
+
+    fn follow_cnames(&self, pack: &DnsPacket) -> anyhow::Result<DnsPacket> {
+        let mut res = match self.lookup(pack) {
+            Ok(res) => res,
+            Err(e) => {
+                println!("resolve error: {:#}", e);
+                return Ok(pack.response_to(ResultCode::SERVFAIL));
+            }
+        };
+
+        let question = match pack.questions.first() {
+            Some(q) => q,
+            None => return Ok(res),
+        };
+        let qname = match question.domain.name() {
+            Some(name) => name.to_owned(),
+            None => return Ok(res),
+        };
+        let qtype = question.rtype.to_num();
+        if qtype == RDataType::CNAME(None).to_num() {
+            return Ok(res);
+        }
 
-        let buf = &mut DnsBuffer::new();
-        pack.header.write(buf)?;
-        let fuck = HashMap::new(); // empty hashmap so questions dont get jumped
-        for q in pack.questions.iter() {
-            q.write(buf, &fuck)?;
+        for _ in 0..MAX_CNAME_DEPTH {
+            let target = match res.unresolved_cname(&qname, qtype) {
+                Some(target) => target,
+                None => break,
+            };
+
+            let mut next_q = DnsPacket::new();
+            next_q.header = pack.header.clone();
+            next_q.questions.push(DnsRecord {
+                domain: Domain::Domain(target),
+                ..question.clone()
+            });
+
+            let next = match self.lookup(&next_q) {
+                Ok(next) => next,
+                Err(e) => {
+                    println!("resolve error: {:#}", e);
+                    res.header.rescode = ResultCode::SERVFAIL;
+                    break;
+                }
+            };
+            // the SOA of a negative answer is about the end of the chain, it goes along with the rescode
+            if next.header.rescode != ResultCode::NOERROR || next.answers.is_empty() {
+                res.header.rescode = next.header.rescode;
+                res.authorities = next.authorities;
+                break;
+            }
+            res.answers.extend(next.answers);
+        }
+        Ok(res)
+    }
+
+    // sets AD on answers that check out, false when the answer is bogus
+    fn validate(&self, pack: &DnsPacket, res: &mut DnsPacket) -> bool {
+        let validator = match &self.validator {
+            Some(validator) => validator,
+            None => return true,
+        };
+        res.header.authed_data = false;
+        // CD means the client does its own checking and wants the data either way
+        let unchecked = pack.header.checking_disabled
+            || !matches!(res.header.rescode, ResultCode::NOERROR | ResultCode::NXDOMAIN);
+        if !unchecked {
+            let fetch = |question: &DnsRecord| {
+                let query = DnsPacket {
+                    header: DnsHeader { recursion_desired: true, checking_disabled: true, ..DnsHeader::new() },
+                    questions: vec![question.clone()],
+                    ..DnsPacket::new()
+                };
+                self.lookup(&query)
+            };
+            match validator.validate(res, &fetch) {
+                Security::Secure => res.header.authed_data = true,
+                Security::Insecure => {},
+                Security::Bogus(why) => {
+                    println!("dnssec error: {}", why);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Sends the query to the upstreams, see `Upstreams::exchange`
+    fn forward(&self, pack: &DnsPacket) -> anyhow::Result<DnsPacket> {
+        // the client's options (cookies and such) are between it and us, upstream gets our own OPT
+        let mut query = DnsPacket {
+            header: pack.header.clone(),
+            questions: pack.questions.clone(),
+            ..DnsPacket::new()
+        };
+        // and our own id, the client's might be easy to guess
+        query.header.id = upstream::random_id();
+        let mut edns = Edns::new(edns::SERVER_UDP_PAYLOAD);
+        edns.dnssec_ok = self.config.dnssec || pack.edns().is_some_and(|e| e.dnssec_ok);
+        query.set_edns(Some(edns));
+
+        let mut res = self.upstreams.exchange(&query)?;
+        res.header.id = pack.header.id;
+        Ok(res)
+    }
+
+    /// Gets the answer for the query's question, from the upstreams or by iterating from the
+    /// root depending on the mode. The header always comes back matching the client's query
+    fn lookup(&self, pack: &DnsPacket) -> anyhow::Result<DnsPacket> {
+        match self.config.mode {
+            Mode::Forward => self.forward(pack),
+            Mode::Recursive => {
+                let question = pack.questions.first()
+                    .ok_or_else(|| anyhow::anyhow!("resolve error: query has no question"))?;
+                let mut res = self.resolver.resolve(question)?;
+                res.header.id = pack.header.id;
+                res.header.recursion_desired = pack.header.recursion_desired;
+                res.header.recursion_available = true;
+                res.header.authoritative_answer = false;
+                Ok(res)
+            }
+        }
+    }
+}
+
+// a udp query read off one of the sockets, the response goes back out the same one
+struct UdpJob {
+    sock: Arc<UdpSocket>,
+    buf: DnsBuffer,
+    from: SocketAddr,
+}
+
+// one of the max_connections, given back when the connection thread is done with it
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    fn take(open: &Arc<AtomicUsize>, max: usize) -> Option<ConnectionSlot> {
+        open.fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| (n < max).then_some(n + 1)).ok()?;
+        Some(ConnectionSlot(open.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// v6 sockets only take v6, otherwise [::] would also grab the v4 port and a 0.0.0.0 next to it
+// couldnt be bound. Listening on both is then a matter of listing both
+fn socket_for(addr: SocketAddr, kind: Type, protocol: Protocol) -> std::io::Result<Socket> {
+    let sock = Socket::new(socket2::Domain::for_address(addr), kind, Some(protocol))?;
+    if addr.is_ipv6() {
+        sock.set_only_v6(true)?;
+    }
+    Ok(sock)
+}
+
+fn bind_udp(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    let sock = socket_for(addr, Type::DGRAM, Protocol::UDP)?;
+    sock.bind(&addr.into())?;
+    Ok(sock.into())
+}
+
+fn bind_tcp(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let sock = socket_for(addr, Type::STREAM, Protocol::TCP)?;
+    // same as std does, so a restart doesnt have to wait for old connections in TIME_WAIT
+    sock.set_reuse_address(true)?;
+    sock.bind(&addr.into())?;
+    sock.listen(1024)?;
+    Ok(sock.into())
+}
+
+fn answer_udp(job: UdpJob, handler: &Handler) -> anyhow::Result<()> {
+    let UdpJob { sock, mut buf, from } = job;
+
+    let mut r_buf = DnsBuffer::new();
+    if respond(&mut buf, &mut r_buf, handler) {
+        sock.send_to(&r_buf.buf[0..r_buf.pos], from)?;
+    }
+    Ok(())
+}
+
+/// Runs the raw query in `buf` through `handler`, the response ends up in `r_buf`. A query that
+/// doesnt parse gets a FORMERR as long as its header could be read, and when the handler fails or
+/// panics the client gets a SERVFAIL. False means there is nothing to send back at all
+pub fn respond<F>(buf: &mut DnsBuffer, r_buf: &mut DnsBuffer, handler: &F) -> bool
+where
+    F: Fn(DnsPacket, &mut DnsBuffer) -> anyhow::Result<()> + ?Sized,
+{
+    let header = match DnsHeader::from_slice(&buf.buf) {
+        Ok(header) => header,
+        Err(_) => return false,
+    };
+    // responses never get one back, or two servers could keep bouncing errors at each other
+    if header.response {
+        return false;
+    }
+
+    let pack = match DnsPacket::from_buf(buf) {
+        Ok(pack) => pack,
+        Err(e) => {
+            println!("query error: {:#}", e);
+            return write_error(&DnsPacket { header, ..DnsPacket::new() }, None, ResultCode::FORMERR, r_buf);
         }
-        
-        self.sock.send_to(&buf.buf[0..buf.pos], server)?;
+    };
+    if pack.header.opcode != 0 {
+        return write_error(&pack, pack.edns(), ResultCode::NOTIMP, r_buf);
+    }
+    if pack.questions.len() != 1 {
+        let edns = pack.edns();
+        let query = DnsPacket { header: pack.header, ..DnsPacket::new() };
+        return write_error(&query, edns, ResultCode::FORMERR, r_buf);
+    }
 
-        let mut deez2 = DnsBuffer::new();
-        self.sock.recv_from(&mut deez2.buf)?;
-        Ok(DnsPacket::from_buf(&mut deez2)?)
+    // udp starts out at 512 and EDNS can only raise that, tcp already takes everything
+    r_buf.set_max_size(r_buf.max_size.max(pack.max_response_size()));
+
+    let start = r_buf.pos;
+    let query = DnsPacket {
+        header: pack.header.clone(),
+        questions: pack.questions.clone(),
+        ..DnsPacket::new()
+    };
+    let edns = pack.edns();
+    match panic::catch_unwind(AssertUnwindSafe(|| handler(pack, r_buf))) {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            println!("query error: {:#}", e);
+            r_buf.pos = start;
+            write_error(&query, edns, ResultCode::SERVFAIL, r_buf)
+        }
+        // the panic hook already printed what happened
+        Err(_) => {
+            r_buf.pos = start;
+            write_error(&query, edns, ResultCode::SERVFAIL, r_buf)
+        }
     }
 }
+
+// a client that sent an OPT gets one back even when all it gets is an error (RFC 6891 7)
+fn write_error(query: &DnsPacket, edns: Option<Edns>, rescode: ResultCode, r_buf: &mut DnsBuffer) -> bool {
+    let mut res = query.response_to(rescode);
+    res.set_edns(edns.as_ref().map(Edns::reply));
+    res.write_truncated(r_buf).is_ok()
+}
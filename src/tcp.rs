@@ -0,0 +1,62 @@
+use std::io::{ErrorKind, Read, Write};
+use anyhow;
+use crate::{buffer::DnsBuffer, packet::DnsPacket, server};
+
+/// Reads one message with its two byte length prefix (RFC 1035 4.2.2).
+/// Ok(None) means the other side closed the connection, or went idle if the stream has a timeout
+pub fn read_frame<S: Read>(stream: &mut S) -> anyhow::Result<Option<DnsBuffer>> {
+    let mut len = [0; 2];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {},
+        Err(e) if matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+            return Ok(None);
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut buf = DnsBuffer::with_size(u16::from_be_bytes(len) as usize);
+    stream.read_exact(&mut buf.buf)?;
+    Ok(Some(buf))
+}
+
+/// Writes what is in `buf` up to `buf.pos`, with the length in front
+pub fn write_frame<S: Write>(stream: &mut S, buf: &DnsBuffer) -> anyhow::Result<()> {
+    // one write so the prefix and the message dont go out as separate segments
+    let mut frame = Vec::with_capacity(buf.pos + 2);
+    frame.extend_from_slice(&(buf.pos as u16).to_be_bytes());
+    frame.extend_from_slice(&buf.buf[0..buf.pos]);
+    stream.write_all(&frame)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Answers queries on the connection until the client closes it, goes idle or stops reading the
+/// responses, going by the stream's timeouts. Several queries can be in flight at once, they are
+/// just answered in the order they came. Broken queries get their error response like over udp
+/// and the connection stays up
+pub fn serve_connection<S, F>(stream: &mut S, handler: &F) -> anyhow::Result<()>
+where
+    S: Read + Write,
+    F: Fn(DnsPacket, &mut DnsBuffer) -> anyhow::Result<()> + ?Sized,
+{
+    while let Some(mut buf) = read_frame(stream)? {
+        // no truncation over tcp, the response can use the whole frame
+        let mut r_buf = DnsBuffer::from_bytes(&[]);
+        if server::respond(&mut buf, &mut r_buf, handler) {
+            write_frame(stream, &r_buf)?;
+        }
+    }
+    Ok(())
+}
+
+/// Sends `pack` as a query over the stream and reads back the response
+pub fn query<S: Read + Write>(stream: &mut S, pack: &DnsPacket) -> anyhow::Result<DnsPacket> {
+    let mut buf = DnsBuffer::from_bytes(&[]);
+    pack.write(&mut buf)?;
+    write_frame(stream, &buf)?;
+
+    match read_frame(stream)? {
+        Some(mut r_buf) => Ok(DnsPacket::from_buf(&mut r_buf)?),
+        None => Err(anyhow::anyhow!("tcp error: connection closed before the response")),
+    }
+}
@@ -0,0 +1,130 @@
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{self, Context};
+use serde::Deserialize;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, pem::PemObject};
+use crate::{buffer::DnsBuffer, packet::DnsPacket, tcp};
+
+/// Where DoT is, unless told otherwise (RFC 7858 3.1)
+pub const DOT_PORT: u16 = 853;
+/// ALPN id of DoT
+pub const DOT_ALPN: &[u8] = b"dot";
+/// The CA certificates of the system on most linuxes, upstream certificates get checked against
+/// these unless another file is given
+pub const SYSTEM_CA_FILE: &str = "/etc/ssl/certs/ca-certificates.crt";
+
+/// An upstream spoken to over TLS. `name` is what its certificate has to be for
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TlsUpstream {
+    pub addr: SocketAddr,
+    pub name: String,
+}
+
+// ADDR[:PORT]#NAME, like 1.1.1.1#one.one.one.one
+impl std::str::FromStr for TlsUpstream {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<TlsUpstream> {
+        let (addr, name) = match s.split_once('#') {
+            Some((addr, name)) if !name.is_empty() => (addr, name),
+            _ => return Err(anyhow::anyhow!("has to be ADDR[:PORT]#NAME")),
+        };
+        let addr = match addr.parse::<SocketAddr>() {
+            Ok(addr) => addr,
+            Err(_) => SocketAddr::new(addr.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>()?, DOT_PORT),
+        };
+        ServerName::try_from(name)?;
+        Ok(TlsUpstream { addr, name: name.to_owned() })
+    }
+}
+
+impl TryFrom<String> for TlsUpstream {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<TlsUpstream> {
+        s.parse()
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// What the DoT listeners present, a PEM certificate chain (leaf first) and its private key
+pub fn server_config(cert_file: &Path, key_file: &Path, alpn: &[u8]) -> anyhow::Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert_file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("tls error: couldnt read certificates from {}", cert_file.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow::anyhow!("tls error: no certificates in {}", cert_file.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_file)
+        .with_context(|| format!("tls error: couldnt read a private key from {}", key_file.display()))?;
+
+    let mut config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("tls error: certificate and key dont go together")?;
+    config.alpn_protocols = vec![alpn.to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// For talking to upstreams, whose certificates have to chain up to one in `ca_file` (PEM)
+pub fn client_config(ca_file: &Path, alpn: &[u8]) -> anyhow::Result<Arc<ClientConfig>> {
+    let certs = CertificateDer::pem_file_iter(ca_file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("tls error: couldnt read CA certificates from {}", ca_file.display()))?;
+    let mut roots = RootCertStore::empty();
+    // system bundles tend to have a few odd ones, those just get left out
+    let (added, _) = roots.add_parsable_certificates(certs);
+    if added == 0 {
+        return Err(anyhow::anyhow!("tls error: no usable CA certificates in {}", ca_file.display()));
+    }
+
+    let mut config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![alpn.to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// Sends `query` to `upstream` over a new TLS connection, with the same framing as tcp. The
+/// certificate has to be valid for `upstream.name`
+pub fn exchange(query: &DnsPacket, upstream: &TlsUpstream, config: &Arc<ClientConfig>, timeout: Duration) -> anyhow::Result<DnsPacket> {
+    let name = ServerName::try_from(upstream.name.clone())?;
+    let conn = ClientConnection::new(config.clone(), name)?;
+    let sock = TcpStream::connect_timeout(&upstream.addr, timeout)?;
+    sock.set_read_timeout(Some(timeout))?;
+    sock.set_write_timeout(Some(timeout))?;
+
+    let mut stream = StreamOwned::new(conn, sock);
+    let res = tcp::query(&mut stream, query)?;
+    if !res.is_response_to(query) {
+        return Err(anyhow::anyhow!("upstream error: {} answered something else over tls", upstream.addr));
+    }
+    stream.conn.send_close_notify();
+    let _ = stream.flush();
+    Ok(res)
+}
+
+/// Does the handshake and then answers queries like `tcp::serve_connection`, until the client
+/// closes the connection or goes idle for longer than the socket's read timeout
+pub fn serve_connection<F>(sock: TcpStream, config: Arc<ServerConfig>, handler: &F) -> anyhow::Result<()>
+where
+    F: Fn(DnsPacket, &mut DnsBuffer) -> anyhow::Result<()> + ?Sized,
+{
+    let conn = ServerConnection::new(config)?;
+    let mut stream = StreamOwned::new(conn, sock);
+    tcp::serve_connection(&mut stream, handler)?;
+    stream.conn.send_close_notify();
+    let _ = stream.flush();
+    Ok(())
+}
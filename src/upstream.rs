@@ -0,0 +1,250 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use anyhow;
+use rustls::ClientConfig;
+use crate::{buffer::{self, DnsBuffer}, header::ResultCode, packet::DnsPacket, tcp, tls::{self, TlsUpstream}, doh::{self, HttpsUpstream}};
+
+/// Failures in a row before an upstream is left alone for a while
+pub const FAILURES_TILL_DOWN: u32 = 3;
+/// How long a down upstream is skipped at first, doubled with every failure after that
+pub const DOWN_FOR: Duration = Duration::from_secs(5);
+/// Longest an upstream is ever skipped for
+pub const MAX_DOWN_FOR: Duration = Duration::from_secs(300);
+/// Retries wait twice as long as the previous round, up to this
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What is known about how one upstream has been doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Health {
+    pub addr: SocketAddr,
+    pub srtt: Duration,              // smoothed round trip time, failures count as a whole timeout
+    pub failures: u32,               // in a row
+    pub down_until: Option<Instant>, // skipped till then, unless nothing else is left
+}
+
+impl Health {
+    fn is_down(&self, now: Instant) -> bool {
+        self.down_until.is_some_and(|t| t > now)
+    }
+}
+
+/// The forwarders, tried fastest first by smoothed rtt. Whoever fails gets pushed back, and
+/// after a few failures in a row skipped for a while
+#[derive(Debug)]
+pub struct Upstreams {
+    health: Mutex<Vec<Health>>,
+    next: AtomicUsize, // where the rotation starts, so ties dont always go to the first one
+    pub timeout: Duration,
+    pub retries: usize, // extra rounds through the whole list, each with double the timeout
+    tls: Vec<TlsUpstream>, // the ones in `health` that get asked over tls
+    tls_config: Option<Arc<ClientConfig>>,
+    https: Vec<HttpsUpstream>, // and the ones asked over https
+    https_client: Option<doh::Client>,
+}
+
+impl Upstreams {
+    pub fn new(servers: &[SocketAddr], timeout: Duration, retries: usize) -> Upstreams {
+        let health = servers.iter()
+            .map(|addr| Health {
+                addr: *addr,
+                srtt: Duration::ZERO, // untried ones go first
+                failures: 0,
+                down_until: None,
+            })
+            .collect();
+        Upstreams {
+            health: Mutex::new(health),
+            next: AtomicUsize::new(0),
+            timeout,
+            retries,
+            tls: Vec::new(),
+            tls_config: None,
+            https: Vec::new(),
+            https_client: None,
+        }
+    }
+
+    /// Adds DoT upstreams (RFC 7858), which take their turn with the rest. Their certificates
+    /// get checked with `config`
+    pub fn with_tls(mut self, upstreams: &[TlsUpstream], config: Arc<ClientConfig>) -> Upstreams {
+        let health = self.health.get_mut().unwrap_or_else(PoisonError::into_inner);
+        health.extend(upstreams.iter().map(|u| Health {
+            addr: u.addr,
+            srtt: Duration::ZERO,
+            failures: 0,
+            down_until: None,
+        }));
+        self.tls.extend_from_slice(upstreams);
+        self.tls_config = Some(config);
+        self
+    }
+
+    /// Adds DoH upstreams (RFC 8484), same deal as `with_tls`
+    pub fn with_https(mut self, upstreams: &[HttpsUpstream], client: doh::Client) -> Upstreams {
+        let health = self.health.get_mut().unwrap_or_else(PoisonError::into_inner);
+        health.extend(upstreams.iter().map(|u| Health {
+            addr: u.addr,
+            srtt: Duration::ZERO,
+            failures: 0,
+            down_until: None,
+        }));
+        self.https.extend_from_slice(upstreams);
+        self.https_client = Some(client);
+        self
+    }
+
+    /// How every upstream has been doing
+    pub fn health(&self) -> Vec<Health> {
+        self.health.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// The order the next query tries the upstreams in, fastest first and down ones last
+    pub fn order(&self) -> Vec<SocketAddr> {
+        let now = Instant::now();
+        let mut health = self.health();
+        if !health.is_empty() {
+            let start = self.next.fetch_add(1, Ordering::Relaxed) % health.len();
+            health.rotate_left(start);
+        }
+        health.sort_by_key(|h| (h.is_down(now), h.srtt));
+        health.iter().map(|h| h.addr).collect()
+    }
+
+    /// Asks the upstreams one after the other until one gives a usable answer. A SERVFAIL or
+    /// REFUSED counts as a failure too. Every round after the first waits twice as long
+    pub fn exchange(&self, query: &DnsPacket) -> anyhow::Result<DnsPacket> {
+        let mut last_err = anyhow::anyhow!("upstream error: no upstreams");
+        let mut timeout = self.timeout;
+
+        for _ in 0..=self.retries {
+            for addr in self.order() {
+                let started = Instant::now();
+                match self.send(query, addr, timeout) {
+                    Ok(res) if !matches!(res.header.rescode, ResultCode::SERVFAIL | ResultCode::REFUSED) => {
+                        self.success(addr, started.elapsed());
+                        return Ok(res);
+                    }
+                    Ok(res) => {
+                        self.failure(addr, timeout);
+                        last_err = anyhow::anyhow!("upstream error: {} answered {:?}", addr, res.header.rescode);
+                    }
+                    Err(e) => {
+                        self.failure(addr, timeout);
+                        last_err = e.context(format!("upstream error: {} failed", addr));
+                    }
+                }
+            }
+            timeout = (timeout * 2).min(MAX_TIMEOUT);
+        }
+        Err(last_err)
+    }
+
+    // over tls or https for the ones that want it, the normal way for the rest
+    fn send(&self, query: &DnsPacket, addr: SocketAddr, timeout: Duration) -> anyhow::Result<DnsPacket> {
+        if let (Some(upstream), Some(client)) = (self.https.iter().find(|u| u.addr == addr), &self.https_client) {
+            return client.exchange(query, upstream, timeout);
+        }
+        match (self.tls.iter().find(|u| u.addr == addr), &self.tls_config) {
+            (Some(upstream), Some(config)) => tls::exchange(query, upstream, config, timeout),
+            _ => exchange(query, addr, timeout),
+        }
+    }
+
+    fn success(&self, addr: SocketAddr, rtt: Duration) {
+        let mut health = self.health.lock().unwrap_or_else(PoisonError::into_inner);
+        for h in health.iter_mut() {
+            if h.addr == addr {
+                h.srtt = smooth(h.srtt, rtt);
+                h.failures = 0;
+                h.down_until = None;
+            } else {
+                // the others slowly look better again, so a slow one gets another go eventually
+                h.srtt = h.srtt * 63 / 64;
+            }
+        }
+    }
+
+    fn failure(&self, addr: SocketAddr, timeout: Duration) {
+        let mut health = self.health.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(h) = health.iter_mut().find(|h| h.addr == addr) {
+            h.srtt = smooth(h.srtt, timeout);
+            h.failures += 1;
+            if h.failures >= FAILURES_TILL_DOWN {
+                let doublings = (h.failures - FAILURES_TILL_DOWN).min(16);
+                h.down_until = Some(Instant::now() + (DOWN_FOR * 2u32.pow(doublings)).min(MAX_DOWN_FOR));
+            }
+        }
+    }
+}
+
+// RFC 6298 style, the new sample counts for an eighth. The first one is taken as is
+fn smooth(srtt: Duration, rtt: Duration) -> Duration {
+    if srtt.is_zero() {
+        return rtt;
+    }
+    srtt * 7 / 8 + rtt / 8
+}
+
+/// Sends `query` to `server` over udp and waits up to `timeout` for the reply. If the reply
+/// comes back truncated the query is asked again over tcp. Only a reply matching the query's id
+/// and question counts, anything else that shows up in the meantime is dropped
+pub fn exchange(query: &DnsPacket, server: SocketAddr, timeout: Duration) -> anyhow::Result<DnsPacket> {
+    let buf = &mut DnsBuffer::new();
+    query.write(buf)?;
+
+    // own socket per query, so replies cant end up with whoever is reading the client one or
+    // with another query. Connecting it also leaves out datagrams from anyone but `server`
+    let sock = UdpSocket::bind(unspecified_for(server))?;
+    sock.connect(server)?;
+    sock.send(&buf.buf[0..buf.pos])?;
+
+    let deadline = Instant::now() + timeout;
+    let res = loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(anyhow::anyhow!("upstream error: no reply from {} in time", server));
+        }
+        sock.set_read_timeout(Some(left))?;
+
+        let mut deez2 = DnsBuffer::with_size(buffer::MAX_SIZE);
+        let len = sock.recv(&mut deez2.buf)?;
+        deez2.buf.truncate(len);
+        match DnsPacket::from_buf(&mut deez2) {
+            Ok(res) if res.is_response_to(query) => break res,
+            _ => continue,
+        }
+    };
+
+    // didnt fit in udp, ask again over tcp
+    if res.header.truncated_message {
+        let mut stream = TcpStream::connect_timeout(&server, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        let res = tcp::query(&mut stream, query)?;
+        if !res.is_response_to(query) {
+            return Err(anyhow::anyhow!("upstream error: {} answered something else over tcp", server));
+        }
+        return Ok(res);
+    }
+    Ok(res)
+}
+
+/// A transaction id nobody can guess, for the queries we make ourselves
+pub fn random_id() -> u16 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    // RandomState is seeded randomly per process, hashing a counter with it is enough here
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish() as u16
+}
+
+// any address of the same family as `addr`, to bind the upstream sockets on
+fn unspecified_for(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+    }
+}
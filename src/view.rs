@@ -0,0 +1,331 @@
+use crate::{buffer::{DnsBuffer, MAX_JUMPS}, error::{self, ParseError}, header::{DnsHeader, HEADER_SIZE}, record::{DnsRecord, RecordType}};
+
+/// Read only view over a packet in a `&[u8]`, nothing is copied or decoded until asked for.
+/// Good for when only the header and the qname of a packet matter
+#[derive(Debug, Clone)]
+pub struct PacketView<'a> {
+    data: &'a [u8],
+    pub header: DnsHeader,
+}
+
+impl<'a> PacketView<'a> {
+    pub fn new(data: &'a [u8]) -> error::Result<PacketView<'a>> {
+        Ok(PacketView {
+            data,
+            header: DnsHeader::from_slice(data)?,
+        })
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn questions(&self) -> QuestionIter<'a> {
+        QuestionIter {
+            data: self.data,
+            pos: HEADER_SIZE,
+            left: self.header.questions,
+        }
+    }
+
+    pub fn answers(&self) -> RecordIter<'a> {
+        self.records_after(self.header.answers, 0)
+    }
+
+    pub fn authorities(&self) -> RecordIter<'a> {
+        self.records_after(self.header.authoritative_entries, self.header.answers)
+    }
+
+    pub fn resources(&self) -> RecordIter<'a> {
+        self.records_after(
+            self.header.resource_entries,
+            self.header.answers.saturating_add(self.header.authoritative_entries),
+            )
+    }
+
+    // walks past the questions and `skip` records to find where a section starts. If something
+    // before it is broken the section starts at the end, so its first next() is an error
+    fn records_after(&self, count: u16, skip: u16) -> RecordIter<'a> {
+        let mut questions = self.questions();
+        let mut ok = questions.by_ref().all(|q| q.is_ok());
+
+        let mut records = RecordIter {
+            data: self.data,
+            pos: questions.pos,
+            left: skip,
+        };
+        if ok {
+            ok = records.by_ref().all(|r| r.is_ok());
+        }
+
+        RecordIter {
+            data: self.data,
+            pos: if ok { records.pos } else { self.data.len() },
+            left: count,
+        }
+    }
+}
+
+/// A name still sitting in the packet, jumps are only followed when it gets read
+#[derive(Debug, Clone, Copy)]
+pub struct NameView<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> NameView<'a> {
+    /// Offset of the name in the packet
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn labels(&self) -> LabelIter<'a> {
+        LabelIter {
+            data: self.data,
+            pos: self.offset,
+            start: self.offset,
+            jumps: 0,
+            done: false,
+        }
+    }
+
+    /// Decodes the name the same way `DnsBuffer::get_domain` does, lowercased and dot separated,
+    /// and with the same 255 byte limit
+    pub fn decode(&self) -> error::Result<String> {
+        let mut domain = String::new();
+        for label in self.labels() {
+            if !domain.is_empty() {
+                domain.push('.');
+            }
+            domain.push_str(&String::from_utf8_lossy(label?).to_lowercase());
+            // 255 on the wire, which is 2 bytes more than the dotted form
+            if domain.len() > 253 {
+                return Err(ParseError::NameTooLong { offset: self.offset });
+            }
+        }
+        Ok(domain)
+    }
+
+    /// Compares against `domain` without allocating
+    pub fn eq_domain(&self, domain: &str) -> bool {
+        let mut wanted = domain.split('.').filter(|l| !l.is_empty());
+        for label in self.labels() {
+            match (label, wanted.next()) {
+                (Ok(l), Some(w)) if l.eq_ignore_ascii_case(w.as_bytes()) => {},
+                _ => return false,
+            }
+        }
+        wanted.next().is_none()
+    }
+}
+
+/// Walks the labels of a name, following jumps. Jumps go anywhere in the packet, same as in
+/// `DnsBuffer::get_domain`, and after `MAX_JUMPS` of them the name counts as a loop
+pub struct LabelIter<'a> {
+    data: &'a [u8],
+    pos: usize,
+    start: usize, // where the name began
+    jumps: usize,
+    done: bool,
+}
+
+impl<'a> Iterator for LabelIter<'a> {
+    type Item = error::Result<&'a [u8]>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        loop {
+            let len = match self.data.get(self.pos) {
+                Some(len) => *len,
+                None => return self.fail(ParseError::EndOfBuffer { offset: self.pos }),
+            };
+
+            if (len & 0xC0) == 0xC0 {
+                if self.jumps >= MAX_JUMPS {
+                    return self.fail(ParseError::PointerLoop { offset: self.start, jumps: MAX_JUMPS });
+                }
+                let b2 = match self.data.get(self.pos + 1) {
+                    Some(b2) => *b2 as usize,
+                    None => return self.fail(ParseError::EndOfBuffer { offset: self.pos + 1 }),
+                };
+                let offset = (((len as usize) & 0x3F) << 8) | b2;
+                if offset >= self.data.len() {
+                    return self.fail(ParseError::BadPointer { offset: self.pos, target: offset });
+                }
+                self.pos = offset;
+                self.jumps += 1;
+                continue;
+            }
+
+            if len == 0 {
+                self.done = true;
+                return None;
+            }
+            if len > 63 {
+                return self.fail(ParseError::BadLabelType { offset: self.pos, label: len });
+            }
+
+            let start = self.pos + 1;
+            let end = start + len as usize;
+            if end > self.data.len() {
+                return self.fail(ParseError::EndOfBuffer { offset: self.data.len() });
+            }
+            self.pos = end;
+            return Some(Ok(&self.data[start..end]));
+        }
+    }
+}
+
+impl LabelIter<'_> {
+    fn fail<T>(&mut self, err: ParseError) -> Option<error::Result<T>> {
+        self.done = true;
+        Some(Err(err))
+    }
+}
+
+// length of the name at `pos` as written, a jump ends the name
+fn skip_name(data: &[u8], pos: usize) -> error::Result<usize> {
+    let mut local_pos = pos;
+    loop {
+        let len = *data.get(local_pos).ok_or(ParseError::EndOfBuffer { offset: local_pos })?;
+        if (len & 0xC0) == 0x40 || (len & 0xC0) == 0x80 {
+            return Err(ParseError::BadLabelType { offset: local_pos, label: len });
+        }
+        if (len & 0xC0) == 0xC0 {
+            local_pos += 2;
+            break;
+        }
+        local_pos += 1 + len as usize;
+        if len == 0 {
+            break;
+        }
+    }
+    if local_pos > data.len() {
+        return Err(ParseError::EndOfBuffer { offset: data.len() });
+    }
+    Ok(local_pos - pos)
+}
+
+fn read_u16(data: &[u8], pos: usize) -> error::Result<u16> {
+    match data.get(pos..pos + 2) {
+        Some(b) => Ok(u16::from_be_bytes([b[0], b[1]])),
+        None => Err(ParseError::EndOfBuffer { offset: pos }),
+    }
+}
+
+fn read_u32(data: &[u8], pos: usize) -> error::Result<u32> {
+    match data.get(pos..pos + 4) {
+        Some(b) => Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]])),
+        None => Err(ParseError::EndOfBuffer { offset: pos }),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QuestionView<'a> {
+    pub name: NameView<'a>,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RecordView<'a> {
+    data: &'a [u8],
+    offset: usize,
+    pub name: NameView<'a>,
+    pub rtype: u16,
+    pub rclass: u16,
+    pub ttl: u32,
+    pub rdata: &'a [u8],
+}
+
+impl RecordView<'_> {
+    /// Fully parses the record into an owned `DnsRecord`
+    pub fn to_record(&self) -> error::Result<DnsRecord> {
+        let mut buf = DnsBuffer::from_bytes(self.data);
+        buf.seek(self.offset)?;
+        DnsRecord::from_buf(&mut buf, RecordType::OTHER, &mut Default::default())
+    }
+}
+
+/// Stops after the first error, since the position of whatever follows is unknown
+pub struct QuestionIter<'a> {
+    data: &'a [u8],
+    pos: usize,
+    left: u16,
+}
+
+impl<'a> Iterator for QuestionIter<'a> {
+    type Item = error::Result<QuestionView<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.left == 0 {
+            return None;
+        }
+        self.left -= 1;
+
+        let res = (|| {
+            let name_len = skip_name(self.data, self.pos)?;
+            let fixed = self.pos + name_len;
+            let question = QuestionView {
+                name: NameView { data: self.data, offset: self.pos },
+                qtype: read_u16(self.data, fixed)?,
+                qclass: read_u16(self.data, fixed + 2)?,
+            };
+            self.pos = fixed + 4;
+            Ok(question)
+        })();
+
+        if res.is_err() {
+            self.left = 0;
+        }
+        Some(res)
+    }
+}
+
+/// Stops after the first error, since the position of whatever follows is unknown
+pub struct RecordIter<'a> {
+    data: &'a [u8],
+    pos: usize,
+    left: u16,
+}
+
+impl<'a> Iterator for RecordIter<'a> {
+    type Item = error::Result<RecordView<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.left == 0 {
+            return None;
+        }
+        self.left -= 1;
+
+        let res = (|| {
+            let name_len = skip_name(self.data, self.pos)?;
+            let fixed = self.pos + name_len;
+            let data_len = read_u16(self.data, fixed + 8)? as usize;
+            let rdata_start = fixed + 10;
+            let rdata = self.data
+                .get(rdata_start..rdata_start + data_len)
+                .ok_or(ParseError::BadRdataLength { offset: rdata_start, len: data_len })?;
+
+            let record = RecordView {
+                data: self.data,
+                offset: self.pos,
+                name: NameView { data: self.data, offset: self.pos },
+                rtype: read_u16(self.data, fixed)?,
+                rclass: read_u16(self.data, fixed + 2)?,
+                ttl: read_u32(self.data, fixed + 4)?,
+                rdata,
+            };
+            self.pos = rdata_start + data_len;
+            Ok(record)
+        })();
+
+        if res.is_err() {
+            self.left = 0;
+        }
+        Some(res)
+    }
+}
@@ -0,0 +1,934 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::sync::Arc;
+use anyhow::{self, Context};
+use crate::{packet::DnsPacket, header::ResultCode, resolver::in_zone, server::MAX_CNAME_DEPTH,
+    encoding, dnssec, signer::{Signer, SigningKey}, record::{type_from_name, DnsRecord, Domain, RClass, RDataType,
+    SoaData, MxData, SrvData, DnskeyData, DsData, RrsigData, NsecData, Nsec3Data, Nsec3ParamData}};
+
+/// qtype asking for every record at a name
+pub const ANY_TYPE: u16 = 255;
+
+/// A zone we are authoritative for, loaded from an RFC 1035 master file
+#[derive(Debug, Clone)]
+pub struct Zone {
+    pub origin: String, // lowercase without the trailing dot, "" for the root
+    records: BTreeMap<String, Vec<DnsRecord>>, // by lowercase owner
+    names: HashSet<String>, // every owner, and the empty non-terminals between them and the origin
+    signer: Option<Arc<Signer>>, // only for zones with keys
+    nsecs: Vec<DnsRecord>, // the NSEC chain in canonical order, empty without keys
+}
+
+// what a name holds, wildcards already applied
+enum Node {
+    Records(Vec<DnsRecord>),
+    Empty, // exists, but only because there are names under it
+    Missing,
+}
+
+impl Zone {
+    pub fn from_file(path: impl AsRef<Path>, origin: &str) -> anyhow::Result<Zone> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("zone error: couldnt read {}", path.display()))?;
+        Zone::parse(&text, origin)
+            .with_context(|| format!("zone error: bad zone file {}", path.display()))
+    }
+
+    /// Parses a master file for the zone at `origin`, which is also where relative names start
+    /// until a $ORIGIN says otherwise
+    pub fn parse(text: &str, origin: &str) -> anyhow::Result<Zone> {
+        let origin = normalize(origin);
+        let mut zone = Zone {
+            origin: origin.clone(),
+            records: BTreeMap::new(),
+            names: HashSet::new(),
+            signer: None,
+            nsecs: Vec::new(),
+        };
+
+        let mut parser = Parser {
+            origin,
+            default_ttl: None,
+            last_ttl: None,
+            last_owner: None,
+        };
+        for entry in entries(text)? {
+            let line = entry.line;
+            if let Some(rec) = parser.entry(entry).with_context(|| format!("zone error: line {}", line))? {
+                zone.add(rec).with_context(|| format!("zone error: line {}", line))?;
+            }
+        }
+        zone.check()?;
+        Ok(zone)
+    }
+
+    /// The SOA record at the origin, every loaded zone has exactly one
+    pub fn soa(&self) -> &DnsRecord {
+        self.records[&self.origin].iter()
+            .find(|r| matches!(r.rtype, RDataType::SOA(_)))
+            .expect("zone without soa got loaded")
+    }
+
+    /// From now on the zone is signed with `keys`. Their DNSKEYs go in at the apex and the NSEC
+    /// chain gets made, `sign` does the rest for each answer
+    pub fn sign_with(&mut self, keys: Vec<SigningKey>) -> anyhow::Result<()> {
+        if keys.is_empty() {
+            return Err(anyhow::anyhow!("zone error: no keys to sign {:?} with", self.origin));
+        }
+        let signer = Signer::new(&self.origin, keys);
+        for rec in signer.dnskeys(self.soa().ttl.unwrap_or(0)) {
+            self.add(rec)?;
+        }
+        self.nsecs = self.nsec_chain();
+        self.signer = Some(Arc::new(signer));
+        Ok(())
+    }
+
+    pub fn is_signed(&self) -> bool {
+        self.signer.is_some()
+    }
+
+    /// The DS records for the parent, one per key
+    pub fn ds(&self) -> Vec<DsData> {
+        self.signer.iter()
+            .flat_map(|signer| signer.keys.iter().map(|key| key.ds(&self.origin)))
+            .collect()
+    }
+
+    /// Every record owned by `name`
+    pub fn records(&self, name: &str) -> &[DnsRecord] {
+        self.records.get(&normalize(name)).map_or(&[], |v| v.as_slice())
+    }
+
+    /// Answers `question` from the zone alone (RFC 1034 4.3.2). Cnames are followed as long as
+    /// they stay in the zone, names under a delegation get a referral with whatever glue there
+    /// is, and missing names or types get NXDOMAIN or NODATA with the SOA in the authorities
+    pub fn answer(&self, question: &DnsRecord) -> DnsPacket {
+        let mut res = DnsPacket::new();
+        res.header.response = true;
+        res.header.authoritative_answer = true;
+        res.questions.push(question.clone());
+
+        let qtype = question.rtype.to_num();
+        let mut name = normalize(question.domain.name().unwrap_or(""));
+
+        for _ in 0..MAX_CNAME_DEPTH {
+            // the chain left the zone, the rest is up to the client
+            if !in_zone(&name, &self.origin) {
+                return res;
+            }
+
+            // the DS at a cut is the parent's, it gets answered from here (RFC 4035 3.1.4.1)
+            let cut = self.delegation(&name).filter(|cut| !(qtype == RDataType::DS(None).to_num() && *cut == name));
+            if let Some(cut) = cut {
+                // only the answers we already have are ours to vouch for
+                res.header.authoritative_answer = !res.answers.is_empty();
+                res.authorities.extend(self.records[&cut].iter().filter(|r| matches!(r.rtype, RDataType::NS(_))).cloned());
+                res.resources.extend(self.glue(&res.authorities));
+                return res;
+            }
+
+            let records = match self.node(&name) {
+                Node::Records(records) => records,
+                Node::Empty => Vec::new(),
+                Node::Missing => {
+                    res.header.rescode = ResultCode::NXDOMAIN;
+                    res.authorities.push(self.negative_soa());
+                    return res;
+                }
+            };
+
+            let matching: Vec<DnsRecord> = records.iter()
+                .filter(|r| qtype == ANY_TYPE || r.rtype.to_num() == qtype)
+                .cloned()
+                .collect();
+            if !matching.is_empty() {
+                res.answers.extend(matching);
+                return res;
+            }
+
+            let cname = records.iter().find_map(|r| match &r.rtype {
+                RDataType::CNAME(Some(target)) => Some((r.clone(), target.clone())),
+                _ => None,
+            });
+            match cname {
+                Some((rec, target)) => {
+                    res.answers.push(rec);
+                    name = normalize(&target);
+                }
+                None => {
+                    res.authorities.push(self.negative_soa());
+                    return res;
+                }
+            }
+        }
+        res
+    }
+
+    /// Adds what a validator needs to `res`, an answer from `answer`: RRSIGs over every RRset
+    /// that is ours, and the NSECs showing the name or type doesnt exist, that there is no DS at a
+    /// referral, or that a wildcard answer didnt have anything closer. Does nothing without keys
+    pub fn sign(&self, res: &mut DnsPacket) {
+        let signer = match &self.signer {
+            Some(signer) => signer,
+            None => return,
+        };
+        let now = dnssec::unix_now();
+        let sigs = |rrset: &[DnsRecord], owner: &str| match signer.sign(rrset, owner, now) {
+            Ok(sigs) => sigs,
+            Err(e) => {
+                println!("dnssec error: couldnt sign {}: {:#}", owner, e);
+                Vec::new()
+            }
+        };
+        // names whose NSEC (the one owned by it or covering it) goes in the authorities
+        let mut proofs = Vec::new();
+
+        let mut answers = Vec::new();
+        for rrset in rrsets(&res.answers) {
+            let owner = normalize(rrset[0].domain.name().unwrap_or(""));
+            let rrsigs = if self.records.contains_key(&owner) {
+                sigs(&rrset, &owner)
+            } else {
+                // the wildcard is what got signed, and there has to be proof nothing was closer
+                let rtype = rrset[0].rtype.to_num();
+                let wildcard: Vec<DnsRecord> = self.records(&self.wildcard_for(&owner)).iter()
+                    .filter(|r| r.rtype.to_num() == rtype)
+                    .cloned()
+                    .collect();
+                proofs.push(owner.clone());
+                sigs(&wildcard, &owner)
+            };
+            answers.extend(rrset);
+            answers.extend(rrsigs);
+        }
+
+        let (qname, qtype) = match res.questions.first() {
+            Some(q) => (normalize(q.domain.name().unwrap_or("")), q.rtype.to_num()),
+            None => return,
+        };
+        let target = res.unresolved_cname(&qname, qtype).map_or(qname, |n| normalize(&n));
+        let mut authorities = Vec::new();
+        for rrset in rrsets(&res.authorities) {
+            let owner = normalize(rrset[0].domain.name().unwrap_or(""));
+            match rrset[0].rtype {
+                // a referral, the NS are the child's and stay unsigned, but whether there is a DS is ours to say
+                RDataType::NS(_) if owner != self.origin => {
+                    let ds: Vec<DnsRecord> = self.records(&owner).iter()
+                        .filter(|r| matches!(r.rtype, RDataType::DS(_)))
+                        .cloned()
+                        .collect();
+                    authorities.extend(rrset);
+                    if ds.is_empty() {
+                        proofs.push(owner);
+                    } else {
+                        let rrsigs = sigs(&ds, &owner);
+                        authorities.extend(ds);
+                        authorities.extend(rrsigs);
+                    }
+                }
+                RDataType::SOA(_) => {
+                    let rrsigs = sigs(&rrset, &owner);
+                    authorities.extend(rrset);
+                    authorities.extend(rrsigs);
+                    if res.header.rescode == ResultCode::NXDOMAIN {
+                        proofs.push(target.clone());
+                        proofs.push(self.wildcard_for(&target));
+                    } else if self.names.contains(&target) {
+                        // NODATA, for empty non-terminals this is the NSEC covering it
+                        proofs.push(target.clone());
+                    } else {
+                        // NODATA from a wildcard that doesnt have the type either
+                        proofs.push(target.clone());
+                        proofs.push(self.wildcard_for(&target));
+                    }
+                }
+                _ => {
+                    let rrsigs = sigs(&rrset, &owner);
+                    authorities.extend(rrset);
+                    authorities.extend(rrsigs);
+                }
+            }
+        }
+
+        let mut added = HashSet::new();
+        for name in proofs {
+            if let Some(nsec) = self.nsec_for(&name) {
+                let owner = nsec.domain.name().unwrap_or("").to_owned();
+                if added.insert(owner.clone()) {
+                    authorities.push(nsec.clone());
+                    authorities.extend(sigs(std::slice::from_ref(nsec), &owner));
+                }
+            }
+        }
+        res.answers = answers;
+        res.authorities = authorities;
+    }
+
+    fn add(&mut self, rec: DnsRecord) -> anyhow::Result<()> {
+        let owner = normalize(rec.domain.name().unwrap_or(""));
+        if !in_zone(&owner, &self.origin) {
+            return Err(anyhow::anyhow!("zone error: {:?} is outside of {:?}", owner, self.origin));
+        }
+        for name in self.ancestors(&owner) {
+            self.names.insert(name);
+        }
+        self.names.insert(self.origin.clone());
+        self.records.entry(owner).or_default().push(rec);
+        Ok(())
+    }
+
+    fn check(&self) -> anyhow::Result<()> {
+        let soas = self.records(&self.origin).iter().filter(|r| matches!(r.rtype, RDataType::SOA(_))).count();
+        if soas != 1 {
+            return Err(anyhow::anyhow!("zone error: {:?} needs exactly one SOA at the origin, found {}", self.origin, soas));
+        }
+        for (owner, records) in self.records.iter() {
+            // a signed zone has the CNAME's RRSIG and NSEC next to it, and KEY is allowed too (RFC 4035 2.5)
+            let beside_cname = |r: &&DnsRecord| !matches!(r.rtype, RDataType::RRSIG(_) | RDataType::NSEC(_) | RDataType::UNKNOWN(25, _));
+            let cnames = records.iter().filter(|r| matches!(r.rtype, RDataType::CNAME(_))).count();
+            if cnames > 0 && records.iter().filter(beside_cname).count() > 1 {
+                return Err(anyhow::anyhow!("zone error: {:?} has a CNAME next to other records", owner));
+            }
+        }
+        Ok(())
+    }
+
+    // the topmost name between the origin and `name` with NS records, a zone cut
+    fn delegation(&self, name: &str) -> Option<String> {
+        self.ancestors(name).into_iter()
+            .find(|n| self.records(n).iter().any(|r| matches!(r.rtype, RDataType::NS(_))))
+    }
+
+    // the records at `name`, or the ones of the wildcard covering it with the owner swapped (RFC 4592)
+    fn node(&self, name: &str) -> Node {
+        if let Some(records) = self.records.get(name) {
+            return Node::Records(records.clone());
+        }
+        if self.names.contains(name) {
+            return Node::Empty;
+        }
+
+        match self.records.get(&self.wildcard_for(name)) {
+            Some(records) => Node::Records(records.iter()
+                .map(|r| DnsRecord { domain: Domain::Domain(name.to_owned()), ..r.clone() })
+                .collect()),
+            None => Node::Missing,
+        }
+    }
+
+    // the wildcard at the closest encloser of `name`, the one that would answer for it
+    fn wildcard_for(&self, name: &str) -> String {
+        let encloser = self.ancestors(name).into_iter().rev()
+            .find(|n| self.names.contains(n))
+            .unwrap_or_else(|| self.origin.clone());
+        if encloser.is_empty() { "*".to_owned() } else { format!("*.{}", encloser) }
+    }
+
+    // an NSEC for every name with records, in canonical order and the last one pointing back at
+    // the apex. Names under a delegation arent ours, and at the cut only the NS and DS are
+    fn nsec_chain(&self) -> Vec<DnsRecord> {
+        let ttl = self.negative_soa().ttl;
+        let mut owners: Vec<&String> = self.records.keys()
+            .filter(|owner| self.delegation(owner).is_none_or(|cut| &cut == *owner))
+            .collect();
+        owners.sort_by(|a, b| dnssec::canonical_cmp(a, b));
+
+        let (ns, ds) = (RDataType::NS(None).to_num(), RDataType::DS(None).to_num());
+        let own = [RDataType::RRSIG(None).to_num(), RDataType::NSEC(None).to_num()];
+        owners.iter().enumerate().map(|(i, owner)| {
+            let records = &self.records[*owner];
+            let cut = **owner != self.origin && records.iter().any(|r| r.rtype.to_num() == ns);
+            let mut types: Vec<u16> = records.iter()
+                .map(|r| r.rtype.to_num())
+                .filter(|t| !cut || *t == ns || *t == ds)
+                .chain(own)
+                .collect();
+            types.sort();
+            types.dedup();
+            DnsRecord {
+                domain: Domain::Domain((*owner).clone()),
+                rtype: RDataType::NSEC(Some(NsecData { next_domain: owners[(i + 1) % owners.len()].clone(), types })),
+                rclass: RClass::IN,
+                ttl,
+                data_len: None,
+            }
+        }).collect()
+    }
+
+    // the NSEC owned by `name`, or else the one whose span it falls in
+    fn nsec_for(&self, name: &str) -> Option<&DnsRecord> {
+        let after = self.nsecs.partition_point(|n| {
+            dnssec::canonical_cmp(n.domain.name().unwrap_or(""), name) != Ordering::Greater
+        });
+        match after {
+            // only names outside the zone sort before the apex
+            0 => None,
+            i => self.nsecs.get(i - 1),
+        }
+    }
+
+    // addresses for the nameservers that are inside the zone, they cant be found any other way
+    fn glue(&self, nameservers: &[DnsRecord]) -> Vec<DnsRecord> {
+        nameservers.iter()
+            .filter_map(|r| match &r.rtype {
+                RDataType::NS(Some(ns)) if in_zone(ns, &self.origin) => Some(self.records(ns)),
+                _ => None,
+            })
+            .flatten()
+            .filter(|r| matches!(r.rtype, RDataType::A(_) | RDataType::AAAA(_)))
+            .cloned()
+            .collect()
+    }
+
+    // negative answers are cached for the smaller of the SOA ttl and its minimum (RFC 2308 section 3)
+    fn negative_soa(&self) -> DnsRecord {
+        let soa = self.soa().clone();
+        let ttl = match &soa.rtype {
+            RDataType::SOA(Some(data)) => soa.ttl.unwrap_or(0).min(data.minimum),
+            _ => soa.ttl.unwrap_or(0),
+        };
+        DnsRecord { ttl: Some(ttl), ..soa }
+    }
+
+    // the names between the origin (excluded) and `name` (included), closest to the origin first
+    fn ancestors(&self, name: &str) -> Vec<String> {
+        if !in_zone(name, &self.origin) || name == self.origin {
+            return Vec::new();
+        }
+        let origin_labels = if self.origin.is_empty() { 0 } else { self.origin.split('.').count() };
+        let labels: Vec<&str> = name.split('.').collect();
+        (origin_labels + 1..=labels.len())
+            .map(|n| labels[labels.len() - n..].join("."))
+            .collect()
+    }
+}
+
+// records grouped by owner and type, in the order the sets first show up
+fn rrsets(records: &[DnsRecord]) -> Vec<Vec<DnsRecord>> {
+    let mut sets: Vec<Vec<DnsRecord>> = Vec::new();
+    let key = |r: &DnsRecord| (normalize(r.domain.name().unwrap_or("")), r.rtype.to_num());
+    for rec in records {
+        match sets.iter_mut().find(|set| key(&set[0]) == key(rec)) {
+            Some(set) => set.push(rec.clone()),
+            None => sets.push(vec![rec.clone()]),
+        }
+    }
+    sets
+}
+
+// lowercase and without the trailing dot, how names are kept around here
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String), // escapes still in, names need to know which dots were escaped
+    Quoted(Vec<u8>), // escapes already undone, so not always utf8
+}
+
+impl Token {
+    // a quoted string that isnt utf8 cant be a name or number anyway, it comes out as something that wont parse
+    fn text(&self) -> &str {
+        match self {
+            Token::Word(s) => s,
+            Token::Quoted(b) => std::str::from_utf8(b).unwrap_or("\u{FFFD}"),
+        }
+    }
+
+    // the bytes of a TXT string, for a word that means undoing its escapes now
+    fn bytes(&self) -> anyhow::Result<Cow<'_, [u8]>> {
+        match self {
+            Token::Word(s) if s.contains('\\') => {
+                let mut out = Vec::new();
+                let mut chars = s.chars().peekable();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => unescape(&mut chars, &mut out).ok_or_else(|| anyhow::anyhow!("zone error: bad escape in {:?}", s))?,
+                        c => out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                    }
+                }
+                Ok(Cow::Owned(out))
+            }
+            Token::Word(s) => Ok(Cow::Borrowed(s.as_bytes())),
+            Token::Quoted(b) => Ok(Cow::Borrowed(b)),
+        }
+    }
+}
+
+// one record or directive, parentheses already joined into one line
+struct Entry {
+    line: usize,
+    blank_owner: bool, // started with whitespace, so the owner is the previous one
+    tokens: Vec<Token>,
+}
+
+// splits the file into entries, taking care of comments, quotes and parentheses
+fn entries(text: &str) -> anyhow::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+    let mut depth = 0;
+    let mut current: Option<Entry> = None;
+    let mut at_line_start = true;
+
+    while let Some(c) = chars.next() {
+        let starts_entry = at_line_start && depth == 0;
+        at_line_start = false;
+        if starts_entry {
+            if let Some(entry) = current.take() {
+                if !entry.tokens.is_empty() {
+                    entries.push(entry);
+                }
+            }
+            current = Some(Entry {
+                line,
+                blank_owner: c == ' ' || c == '\t',
+                tokens: Vec::new(),
+            });
+        }
+        let entry = current.as_mut().expect("entry started above");
+
+        match c {
+            '\n' => {
+                line += 1;
+                at_line_start = true;
+            }
+            ' ' | '\t' | '\r' => {},
+            ';' => {
+                while chars.peek().is_some_and(|c| *c != '\n') {
+                    chars.next();
+                }
+            }
+            '(' => depth += 1,
+            ')' => {
+                if depth == 0 {
+                    return Err(anyhow::anyhow!("zone error: line {}: ) without (", line));
+                }
+                depth -= 1;
+            }
+            '"' => {
+                let mut s = Vec::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => unescape(&mut chars, &mut s).ok_or_else(|| anyhow::anyhow!("zone error: line {}: bad escape", line))?,
+                        Some('\n') => {
+                            line += 1;
+                            s.push(b'\n');
+                        }
+                        Some(c) => s.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                        None => return Err(anyhow::anyhow!("zone error: line {}: unterminated string", line)),
+                    }
+                }
+                entry.tokens.push(Token::Quoted(s));
+            }
+            c => {
+                let mut s = String::new();
+                let mut next = Some(c);
+                while let Some(c) = next {
+                    s.push(c);
+                    // an escaped space or ; is still part of the word, the escape itself gets undone later
+                    if c == '\\' {
+                        if let Some(escaped) = chars.next_if(|c| *c != '\n') {
+                            s.push(escaped);
+                        }
+                    }
+                    next = chars.next_if(|c| !c.is_whitespace() && !matches!(c, ';' | '(' | ')' | '"'));
+                }
+                entry.tokens.push(Token::Word(s));
+            }
+        }
+    }
+    if depth != 0 {
+        return Err(anyhow::anyhow!("zone error: line {}: ( without )", line));
+    }
+    if let Some(entry) = current {
+        if !entry.tokens.is_empty() {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+// what comes after a \, either the char itself or \DDD as a decimal byte.
+// That byte goes in as is, above 127 it isnt a char of its own
+fn unescape(chars: &mut std::iter::Peekable<std::str::Chars>, out: &mut Vec<u8>) -> Option<()> {
+    let first = chars.next()?;
+    if !first.is_ascii_digit() {
+        out.extend_from_slice(first.encode_utf8(&mut [0; 4]).as_bytes());
+        return Some(());
+    }
+    let mut digits = String::from(first);
+    for _ in 0..2 {
+        digits.push(chars.next().filter(|c| c.is_ascii_digit())?);
+    }
+    out.push(digits.parse::<u8>().ok()?);
+    Some(())
+}
+
+struct Parser {
+    origin: String,
+    default_ttl: Option<u32>, // from $TTL
+    last_ttl: Option<u32>,
+    last_owner: Option<String>,
+}
+
+impl Parser {
+    // a record, or None for directives
+    fn entry(&mut self, entry: Entry) -> anyhow::Result<Option<DnsRecord>> {
+        let mut tokens = entry.tokens.into_iter().peekable();
+
+        if let Some(Token::Word(first)) = tokens.peek() {
+            if first.starts_with('$') {
+                let directive = first.to_uppercase();
+                tokens.next();
+                let arg = tokens.next().ok_or_else(|| anyhow::anyhow!("zone error: {} needs a value", directive))?;
+                match directive.as_str() {
+                    "$ORIGIN" => self.origin = self.name(arg.text())?,
+                    "$TTL" => self.default_ttl = Some(parse_ttl(arg.text())?),
+                    // the records would have to come from a file next to this one, and there isnt always a file
+                    "$INCLUDE" => return Err(anyhow::anyhow!("zone error: $INCLUDE isnt supported, the records have to be in the zone itself")),
+                    _ => return Err(anyhow::anyhow!("zone error: {} isnt supported", directive)),
+                }
+                return Ok(None);
+            }
+        }
+
+        let owner = if entry.blank_owner {
+            self.last_owner.clone().ok_or_else(|| anyhow::anyhow!("zone error: no previous owner to use"))?
+        } else {
+            let owner = tokens.next().ok_or_else(|| anyhow::anyhow!("zone error: missing owner"))?;
+            self.name(owner.text())?
+        };
+        self.last_owner = Some(owner.clone());
+
+        // ttl and class can come in either order, both are optional
+        let mut ttl = None;
+        let mut rclass = None;
+        let rtype = loop {
+            let token = tokens.next().ok_or_else(|| anyhow::anyhow!("zone error: missing type"))?;
+            let word = token.text().to_uppercase();
+            if ttl.is_none() && word.starts_with(|c: char| c.is_ascii_digit()) {
+                ttl = Some(parse_ttl(&word)?);
+            } else if rclass.is_none() && parse_class(&word).is_some() {
+                rclass = parse_class(&word);
+            } else {
+                break parse_type(&word)?;
+            }
+        };
+        // without $TTL the last explicit ttl carries over (RFC 1035 5.1)
+        let ttl = ttl.or(self.default_ttl).or(self.last_ttl)
+            .ok_or_else(|| anyhow::anyhow!("zone error: no ttl and no $TTL before it"))?;
+        self.last_ttl = Some(ttl);
+
+        let rdata: Vec<Token> = tokens.collect();
+        let rtype = self.rdata(rtype, &rdata)?;
+        Ok(Some(DnsRecord {
+            domain: Domain::Domain(owner),
+            rtype,
+            rclass: rclass.unwrap_or(RClass::IN),
+            ttl: Some(ttl),
+            data_len: None,
+        }))
+    }
+
+    fn rdata(&self, rtype: RDataType, rdata: &[Token]) -> anyhow::Result<RDataType> {
+        if rdata.first().is_some_and(|t| *t == Token::Word("\\#".to_owned())) {
+            return match rtype {
+                RDataType::UNKNOWN(num, _) => Ok(RDataType::UNKNOWN(num, Some(parse_generic(&rdata[1..])?))),
+                _ => Err(anyhow::anyhow!("zone error: \\# is only supported for unknown types")),
+            };
+        }
+
+        let words: Vec<&str> = rdata.iter().map(Token::text).collect();
+        let expect = |n: usize| {
+            if words.len() == n {
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!("zone error: type {} takes {} values, got {}", rtype.to_num(), n, words.len()))
+            }
+        };
+        // for the types whose last field can be split over several words
+        let at_least = |n: usize| {
+            if words.len() >= n {
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!("zone error: type {} takes at least {} values, got {}", rtype.to_num(), n, words.len()))
+            }
+        };
+
+        let rtype = match rtype {
+            RDataType::A(_) => {
+                expect(1)?;
+                RDataType::A(Some(words[0].parse::<Ipv4Addr>()?))
+            }
+            RDataType::AAAA(_) => {
+                expect(1)?;
+                RDataType::AAAA(Some(words[0].parse::<Ipv6Addr>()?))
+            }
+            RDataType::NS(_) => {
+                expect(1)?;
+                RDataType::NS(Some(self.name(words[0])?))
+            }
+            RDataType::CNAME(_) => {
+                expect(1)?;
+                RDataType::CNAME(Some(self.name(words[0])?))
+            }
+            RDataType::PTR(_) => {
+                expect(1)?;
+                RDataType::PTR(Some(self.name(words[0])?))
+            }
+            RDataType::SOA(_) => {
+                expect(7)?;
+                RDataType::SOA(Some(SoaData {
+                    mname: self.name(words[0])?,
+                    rname: self.name(words[1])?,
+                    serial: words[2].parse()?,
+                    refresh: parse_ttl(words[3])?,
+                    retry: parse_ttl(words[4])?,
+                    expire: parse_ttl(words[5])?,
+                    minimum: parse_ttl(words[6])?,
+                }))
+            }
+            RDataType::MX(_) => {
+                expect(2)?;
+                RDataType::MX(Some(MxData {
+                    priority: words[0].parse()?,
+                    exchange: self.name(words[1])?,
+                }))
+            }
+            RDataType::SRV(_) => {
+                expect(4)?;
+                RDataType::SRV(Some(SrvData {
+                    priority: words[0].parse()?,
+                    weight: words[1].parse()?,
+                    port: words[2].parse()?,
+                    target: self.name(words[3])?,
+                }))
+            }
+            RDataType::TXT(_) => {
+                if words.is_empty() {
+                    return Err(anyhow::anyhow!("zone error: TXT needs at least one string"));
+                }
+                let mut wire = Vec::new();
+                for token in rdata {
+                    let bytes = token.bytes()?;
+                    if bytes.len() > 255 {
+                        return Err(anyhow::anyhow!("zone error: TXT strings cant be longer than 255 bytes"));
+                    }
+                    wire.push(bytes.len() as u8);
+                    wire.extend_from_slice(&bytes);
+                }
+                RDataType::TXT(Some(wire))
+            }
+            RDataType::DNSKEY(_) => {
+                at_least(4)?;
+                RDataType::DNSKEY(Some(DnskeyData {
+                    flags: words[0].parse()?,
+                    protocol: words[1].parse()?,
+                    algorithm: words[2].parse()?,
+                    public_key: base64(&words[3..])?,
+                }))
+            }
+            RDataType::DS(_) => {
+                at_least(4)?;
+                RDataType::DS(Some(DsData {
+                    key_tag: words[0].parse()?,
+                    algorithm: words[1].parse()?,
+                    digest_type: words[2].parse()?,
+                    digest: hex(&words[3..].concat())?,
+                }))
+            }
+            RDataType::RRSIG(_) => {
+                at_least(9)?;
+                RDataType::RRSIG(Some(RrsigData {
+                    type_covered: parse_type(words[0])?.to_num(),
+                    algorithm: words[1].parse()?,
+                    labels: words[2].parse()?,
+                    original_ttl: parse_ttl(words[3])?,
+                    expiration: timestamp(words[4])?,
+                    inception: timestamp(words[5])?,
+                    key_tag: words[6].parse()?,
+                    signer_name: self.name(words[7])?,
+                    signature: base64(&words[8..])?,
+                }))
+            }
+            RDataType::NSEC(_) => {
+                at_least(1)?;
+                RDataType::NSEC(Some(NsecData {
+                    next_domain: self.name(words[0])?,
+                    types: parse_types(&words[1..])?,
+                }))
+            }
+            RDataType::NSEC3(_) => {
+                at_least(5)?;
+                RDataType::NSEC3(Some(Nsec3Data {
+                    hash_algorithm: words[0].parse()?,
+                    flags: words[1].parse()?,
+                    iterations: words[2].parse()?,
+                    salt: salt(words[3])?,
+                    next_hashed: encoding::base32hex_decode(words[4])
+                        .ok_or_else(|| anyhow::anyhow!("zone error: bad base32hex {:?}", words[4]))?,
+                    types: parse_types(&words[5..])?,
+                }))
+            }
+            RDataType::NSEC3PARAM(_) => {
+                expect(4)?;
+                RDataType::NSEC3PARAM(Some(Nsec3ParamData {
+                    hash_algorithm: words[0].parse()?,
+                    flags: words[1].parse()?,
+                    iterations: words[2].parse()?,
+                    salt: salt(words[3])?,
+                }))
+            }
+            RDataType::OPT(_) => return Err(anyhow::anyhow!("zone error: OPT doesnt belong in a zone")),
+            RDataType::UNKNOWN(num, _) => {
+                return Err(anyhow::anyhow!("zone error: TYPE{} needs its rdata in the \\# form", num));
+            }
+        };
+        Ok(rtype)
+    }
+
+    // absolute names end with a dot, anything else is relative to the current origin
+    fn name(&self, name: &str) -> anyhow::Result<String> {
+        if name == "@" {
+            return Ok(self.origin.clone());
+        }
+        if name == "." {
+            return Ok(String::new());
+        }
+        let (labels, absolute) = labels(name)?;
+        let name = labels.join(".");
+        if absolute || self.origin.is_empty() {
+            return Ok(name);
+        }
+        Ok(format!("{}.{}", name, self.origin))
+    }
+}
+
+// the labels of a name with their escapes undone, and whether it ended in a dot that wasnt escaped.
+// Names are dotted strings everywhere else, so a label cant have a dot of its own
+fn labels(name: &str) -> anyhow::Result<(Vec<String>, bool)> {
+    let bad = || anyhow::anyhow!("zone error: bad name {:?}", name);
+    let mut raw = Vec::new();
+    let mut label = Vec::new();
+    let mut chars = name.chars().peekable();
+    let mut absolute = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescape(&mut chars, &mut label).ok_or_else(bad)?,
+            '.' if label.is_empty() => return Err(bad()),
+            '.' => {
+                raw.push(std::mem::take(&mut label));
+                absolute = chars.peek().is_none();
+            }
+            c => label.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    if !label.is_empty() {
+        raw.push(label);
+    }
+    if raw.is_empty() {
+        return Err(bad());
+    }
+
+    let mut labels = Vec::new();
+    for label in raw {
+        if label.contains(&b'.') {
+            return Err(anyhow::anyhow!("zone error: {:?} has a dot inside a label, those arent supported", name));
+        }
+        labels.push(String::from_utf8(label).map_err(|_| anyhow::anyhow!("zone error: {:?} isnt utf8 once its escapes are undone", name))?);
+    }
+    Ok((labels, absolute))
+}
+
+// seconds, or the usual 1h30m style
+fn parse_ttl(s: &str) -> anyhow::Result<u32> {
+    if let Ok(secs) = s.parse() {
+        return Ok(secs);
+    }
+    let bad = || anyhow::anyhow!("zone error: bad ttl {:?}", s);
+    let mut total: u32 = 0;
+    let mut num = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            num.push(c);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return Err(bad()),
+        };
+        let n: u32 = num.parse().map_err(|_| bad())?;
+        total = n.checked_mul(unit).and_then(|n| total.checked_add(n)).ok_or_else(bad)?;
+        num.clear();
+    }
+    if !num.is_empty() {
+        return Err(bad());
+    }
+    Ok(total)
+}
+
+fn parse_class(s: &str) -> Option<RClass> {
+    match s {
+        "IN" => Some(RClass::IN),
+        "CS" => Some(RClass::SOMETHING(2)),
+        "CH" => Some(RClass::SOMETHING(3)),
+        "HS" => Some(RClass::SOMETHING(4)),
+        _ => None,
+    }
+}
+
+fn parse_type(s: &str) -> anyhow::Result<RDataType> {
+    let num = type_from_name(s).ok_or_else(|| anyhow::anyhow!("zone error: unknown type {:?}", s))?;
+    Ok(RDataType::from_num(num))
+}
+
+// the type list of NSEC and NSEC3
+fn parse_types(words: &[&str]) -> anyhow::Result<Vec<u16>> {
+    let mut types = words.iter().map(|w| parse_type(w).map(|t| t.to_num())).collect::<anyhow::Result<Vec<u16>>>()?;
+    types.sort_unstable();
+    types.dedup();
+    Ok(types)
+}
+
+// keys and signatures are often split over several words
+fn base64(words: &[&str]) -> anyhow::Result<Vec<u8>> {
+    encoding::base64_decode(&words.concat()).ok_or_else(|| anyhow::anyhow!("zone error: bad base64 in {:?}", words.concat()))
+}
+
+fn hex(text: &str) -> anyhow::Result<Vec<u8>> {
+    encoding::hex_decode(text).ok_or_else(|| anyhow::anyhow!("zone error: bad hex in {:?}", text))
+}
+
+fn salt(text: &str) -> anyhow::Result<Vec<u8>> {
+    if text == "-" {
+        return Ok(Vec::new());
+    }
+    hex(text)
+}
+
+fn timestamp(text: &str) -> anyhow::Result<u32> {
+    encoding::timestamp_decode(text).ok_or_else(|| anyhow::anyhow!("zone error: bad timestamp {:?}", text))
+}
+
+// RFC 3597 rdata, \# then the length and the bytes in hex
+fn parse_generic(tokens: &[Token]) -> anyhow::Result<Vec<u8>> {
+    let (len, hex) = tokens.split_first().ok_or_else(|| anyhow::anyhow!("zone error: \\# needs a length"))?;
+    let len: usize = len.text().parse()?;
+    let bytes = self::hex(&hex.iter().map(Token::text).collect::<String>())?;
+    if bytes.len() != len {
+        return Err(anyhow::anyhow!("zone error: \\# said {} bytes but has {}", len, bytes.len()));
+    }
+    Ok(bytes)
+}
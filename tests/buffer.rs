@@ -0,0 +1,90 @@
+use deez_ns::buffer::{self, DnsBuffer};
+use deez_ns::error::ParseError;
+use deez_ns::packet::DnsPacket;
+use deez_ns::record::RDataType;
+
+mod common;
+use common::{a, question, record};
+
+// a response for q.example.com with `filler` bytes of TXT in front of the answers that matter
+fn padded(filler: usize, answers: &[&str]) -> Vec<u8> {
+    let mut pack = DnsPacket::new();
+    pack.header.response = true;
+    pack.questions.push(question("q.example.com", RDataType::A(None)));
+    pack.answers.push(record("q.example.com", RDataType::TXT(Some(vec![b'x'; filler]))));
+    for name in answers {
+        pack.answers.push(a(name, [10, 0, 0, 1]));
+    }
+
+    let mut buf = DnsBuffer::from_bytes(&[]);
+    pack.write(&mut buf).unwrap();
+    buf.buf.truncate(buf.pos);
+    buf.buf
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Vec<usize> {
+    haystack.windows(needle.len()).enumerate().filter(|(_, w)| *w == needle).map(|(i, _)| i).collect()
+}
+
+fn names(bytes: &[u8]) -> Vec<String> {
+    let pack = DnsPacket::from_buf(&mut DnsBuffer::from_bytes(bytes)).unwrap();
+    pack.answers.iter().map(|r| r.domain.name().unwrap().to_owned()).collect()
+}
+
+#[test]
+fn pointers_reach_past_offset_255() {
+    let bytes = padded(300, &["far.example.net", "www.far.example.net"]);
+    assert!(bytes.len() > 256);
+
+    let far = find(&bytes, b"\x03far\x07example\x03net\x00");
+    assert_eq!(far.len(), 1);
+    assert!(far[0] > 255);
+
+    // the second name is its own label and then a pointer back to the first, all 14 bits of it
+    let www = find(&bytes, b"\x03www");
+    assert_eq!(www.len(), 1);
+    let pointer = u16::from_be_bytes([bytes[www[0] + 4], bytes[www[0] + 5]]);
+    assert_eq!(pointer, 0xC000 | far[0] as u16);
+
+    assert_eq!(names(&bytes), vec!["q.example.com", "far.example.net", "www.far.example.net"]);
+}
+
+#[test]
+fn names_past_the_last_pointer_offset_go_out_in_full() {
+    let bytes = padded(buffer::MAX_JUMP_OFFSET, &["late.example.org", "late.example.org"]);
+
+    let late = find(&bytes, b"\x04late\x07example\x03org\x00");
+    assert_eq!(late.len(), 2);
+    assert!(late[0] > buffer::MAX_JUMP_OFFSET);
+
+    assert_eq!(names(&bytes), vec!["q.example.com", "late.example.org", "late.example.org"]);
+}
+
+#[test]
+fn buffers_grow_up_to_their_max_size() {
+    // from_bytes starts empty and can go all the way
+    let mut buf = DnsBuffer::from_bytes(&[]);
+    for i in 0..1000 {
+        buf.write(i as u8).unwrap();
+    }
+    assert_eq!(buf.pos, 1000);
+    assert_eq!(buf.buf.len(), 1000);
+    assert_eq!(buf.buf[600], (600 % 256) as u8);
+
+    // with_size stops where it was told to
+    let mut buf = DnsBuffer::with_size(600);
+    for _ in 0..600 {
+        buf.write(1).unwrap();
+    }
+    assert_eq!(buf.write(1).unwrap_err(), ParseError::BufferFull { offset: 600 });
+    assert_eq!(buf.write_u16(1).unwrap_err(), ParseError::BufferFull { offset: 600 });
+
+    // and a bigger max lets it carry on, but never past what a tcp frame holds
+    buf.set_max_size(1 << 20);
+    assert_eq!(buf.max_size, buffer::MAX_SIZE);
+    buf.write_u32(1).unwrap();
+    assert_eq!(buf.pos, 604);
+
+    assert_eq!(DnsBuffer::new().max_size, buffer::UDP_MAX_SIZE);
+    assert_eq!(DnsBuffer::with_size(100_000).max_size, buffer::MAX_SIZE);
+}
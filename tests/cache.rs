@@ -0,0 +1,257 @@
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use deez_ns::cache::{Cache, CacheKey};
+use deez_ns::header::ResultCode;
+use deez_ns::packet::DnsPacket;
+use deez_ns::record::{DnsRecord, RDataType, NsecData, RrsigData, SoaData};
+
+mod common;
+use common::{a, question, record};
+
+// a response to `qname`, only the answers about it and its cnames get cached
+fn response(qname: &str, qtype: RDataType) -> DnsPacket {
+    let mut pack = DnsPacket::new();
+    pack.header.response = true;
+    pack.questions.push(question(qname, qtype));
+    pack
+}
+
+#[test]
+fn keyed_by_name_type_and_class() {
+    let cache = Cache::new(1 << 20);
+    let mut pack = response("example.com", RDataType::A(None));
+    pack.answers.push(a("Example.com", [10, 0, 0, 1]));
+    pack.answers.push(DnsRecord { ttl: Some(100), ..a("example.com", [10, 0, 0, 2]) });
+    pack.answers.push(record("example.com", RDataType::TXT(Some(b"hi".to_vec()))));
+    cache.insert_answers(&pack);
+
+    let rrset = cache.get(&CacheKey::new("example.com", 1, 1)).unwrap();
+    assert_eq!(rrset.len(), 2);
+    // the whole set goes by its smallest ttl
+    assert!(rrset.iter().all(|r| r.ttl.unwrap() <= 100 && r.ttl.unwrap() > 90));
+
+    assert_eq!(cache.get(&CacheKey::new("example.com", 16, 1)).unwrap().len(), 1);
+    assert!(cache.get(&CacheKey::new("example.com", 28, 1)).is_none());
+    assert!(cache.get(&CacheKey::new("example.com", 1, 3)).is_none());
+}
+
+#[test]
+fn entries_expire() {
+    let cache = Cache::new(1 << 20);
+    cache.insert(CacheKey::new("short.example.com", 1, 1), vec![DnsRecord { ttl: Some(1), ..a("short.example.com", [10, 0, 0, 1]) }]);
+    cache.insert(CacheKey::new("zero.example.com", 1, 1), vec![DnsRecord { ttl: Some(0), ..a("zero.example.com", [10, 0, 0, 1]) }]);
+    assert_eq!(cache.len(), 1);
+    assert!(cache.get(&CacheKey::new("short.example.com", 1, 1)).is_some());
+
+    thread::sleep(Duration::from_millis(1100));
+    assert!(cache.get(&CacheKey::new("short.example.com", 1, 1)).is_none());
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn least_recently_used_goes_first() {
+    let one = CacheKey::new("one.example.com", 1, 1);
+    let probe = Cache::new(1 << 20);
+    probe.insert(one.clone(), vec![a("one.example.com", [10, 0, 0, 1])]);
+    let size = probe.bytes();
+
+    // room for two sets of about that size
+    let cache = Cache::new(size * 2 + size / 2);
+    let two = CacheKey::new("two.example.com", 1, 1);
+    let six = CacheKey::new("six.example.com", 1, 1);
+    cache.insert(one.clone(), vec![a("one.example.com", [10, 0, 0, 1])]);
+    cache.insert(two.clone(), vec![a("two.example.com", [10, 0, 0, 2])]);
+    assert!(cache.get(&one).is_some());
+    cache.insert(six.clone(), vec![a("six.example.com", [10, 0, 0, 3])]);
+
+    assert!(cache.get(&one).is_some());
+    assert!(cache.get(&two).is_none());
+    assert!(cache.get(&six).is_some());
+    assert!(cache.bytes() <= size * 2 + size / 2);
+}
+
+#[test]
+fn lookup_follows_cached_cnames() {
+    let cache = Arc::new(Cache::new(1 << 20));
+    let mut pack = response("www.example.com", RDataType::A(None));
+    pack.answers.push(record("www.example.com", RDataType::CNAME(Some("cdn.example.net".to_owned()))));
+    pack.answers.push(a("cdn.example.net", [10, 0, 0, 7]));
+
+    // filled from another thread, the cache is shared between workers
+    let writer = cache.clone();
+    thread::spawn(move || writer.insert_answers(&pack)).join().unwrap();
+
+    let cached = cache.lookup("www.example.com", 1, 1).unwrap();
+    assert_eq!(cached.rescode, ResultCode::NOERROR);
+    let chain = cached.answers;
+    assert_eq!(chain.len(), 2);
+    assert!(matches!(chain[1].rtype, RDataType::A(Some(ip)) if ip == Ipv4Addr::new(10, 0, 0, 7)));
+    assert!(cache.lookup("www.example.com", 28, 1).is_none());
+}
+
+fn negative(qname: &str, qtype: RDataType, rescode: ResultCode, soa_ttl: u32, minimum: u32) -> DnsPacket {
+    let mut pack = DnsPacket::new();
+    pack.header.response = true;
+    pack.header.rescode = rescode;
+    pack.questions.push(question(qname, qtype));
+    pack.authorities.push(DnsRecord {
+        ttl: Some(soa_ttl),
+        ..record("example.com", RDataType::SOA(Some(SoaData {
+            mname: "ns1.example.com".to_owned(),
+            rname: "hostmaster.example.com".to_owned(),
+            serial: 1,
+            refresh: 7200,
+            retry: 900,
+            expire: 1209600,
+            minimum,
+        })))
+    });
+    pack
+}
+
+#[test]
+fn nxdomain_is_cached_for_every_type() {
+    let cache = Cache::new(1 << 20);
+    cache.insert_negative(&negative("nope.example.com", RDataType::A(None), ResultCode::NXDOMAIN, 3600, 60));
+
+    for rtype in [1, 28, 16] {
+        let cached = cache.lookup("NOPE.example.com", rtype, 1).unwrap();
+        assert_eq!(cached.rescode, ResultCode::NXDOMAIN);
+        assert!(cached.answers.is_empty());
+        assert_eq!(cached.authorities.len(), 1);
+        assert!(matches!(cached.authorities[0].rtype, RDataType::SOA(Some(_))));
+        // the smaller of the SOA ttl and its minimum
+        assert!(cached.authorities[0].ttl.unwrap() <= 60);
+    }
+    assert!(cache.lookup("nope.example.com", 1, 3).is_none());
+}
+
+#[test]
+fn nodata_is_cached_for_its_type_only() {
+    let cache = Cache::new(1 << 20);
+    cache.insert_negative(&negative("example.com", RDataType::AAAA(None), ResultCode::NOERROR, 30, 3600));
+
+    let cached = cache.lookup("example.com", 28, 1).unwrap();
+    assert_eq!(cached.rescode, ResultCode::NOERROR);
+    assert!(cached.answers.is_empty());
+    assert!(cached.authorities[0].ttl.unwrap() <= 30);
+    assert!(cache.lookup("example.com", 1, 1).is_none());
+
+    // real records for the same type replace it
+    cache.insert(CacheKey::new("example.com", 28, 1), vec![record("example.com", RDataType::AAAA(Some("::1".parse().unwrap())))]);
+    assert_eq!(cache.lookup("example.com", 28, 1).unwrap().answers.len(), 1);
+}
+
+#[test]
+fn negative_answers_need_a_soa() {
+    let cache = Cache::new(1 << 20);
+    let mut pack = negative("nope.example.com", RDataType::A(None), ResultCode::NXDOMAIN, 3600, 60);
+    pack.authorities.clear();
+    cache.insert_negative(&pack);
+    assert!(cache.is_empty());
+
+    // and nothing negative about a response that answered
+    let mut pack = negative("www.example.com", RDataType::A(None), ResultCode::NOERROR, 3600, 60);
+    pack.answers.push(a("www.example.com", [10, 0, 0, 1]));
+    cache.insert_negative(&pack);
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn nxdomain_at_the_end_of_a_cname_chain() {
+    let cache = Cache::new(1 << 20);
+    let mut pack = negative("www.example.com", RDataType::A(None), ResultCode::NXDOMAIN, 3600, 60);
+    pack.answers.push(record("www.example.com", RDataType::CNAME(Some("gone.example.com".to_owned()))));
+    cache.insert_answers(&pack);
+    cache.insert_negative(&pack);
+
+    let cached = cache.lookup("www.example.com", 1, 1).unwrap();
+    assert_eq!(cached.rescode, ResultCode::NXDOMAIN);
+    assert_eq!(cached.answers.len(), 1);
+    assert_eq!(cached.authorities.len(), 1);
+    assert_eq!(cache.lookup("gone.example.com", 28, 1).unwrap().rescode, ResultCode::NXDOMAIN);
+}
+
+fn rrsig(domain: &str, type_covered: u16, ttl: u32) -> DnsRecord {
+    DnsRecord {
+        ttl: Some(ttl),
+        ..record(domain, RDataType::RRSIG(Some(RrsigData {
+            type_covered,
+            algorithm: 13,
+            labels: 2,
+            original_ttl: ttl,
+            expiration: 0,
+            inception: 0,
+            key_tag: 1,
+            signer_name: "example.com".to_owned(),
+            signature: vec![1, 2, 3],
+        })))
+    }
+}
+
+#[test]
+fn signatures_stay_with_their_set() {
+    let cache = Cache::new(1 << 20);
+    let mut pack = response("www.example.com", RDataType::A(None));
+    pack.header.authed_data = true;
+    pack.answers.push(record("www.example.com", RDataType::CNAME(Some("web.example.com".to_owned()))));
+    pack.answers.push(rrsig("www.example.com", 5, 300));
+    pack.answers.push(a("web.example.com", [10, 0, 0, 1]));
+    pack.answers.push(rrsig("web.example.com", 1, 300));
+    cache.insert_answers(&pack);
+
+    // the whole signed chain comes back, stripping is up to whoever answers the client
+    let cached = cache.lookup("www.example.com", 1, 1).unwrap();
+    assert!(cached.authed_data);
+    let types: Vec<u16> = cached.answers.iter().map(|r| r.rtype.to_num()).collect();
+    assert_eq!(types, vec![5, 46, 1, 46]);
+    assert!(cache.get(&CacheKey::new("www.example.com", 46, 1)).is_none());
+
+    // a signature asked for by itself has nothing to go with
+    let mut pack = response("mail.example.com", RDataType::RRSIG(None));
+    pack.answers.push(rrsig("mail.example.com", 1, 300));
+    cache.insert_answers(&pack);
+    assert!(cache.lookup("mail.example.com", 1, 1).is_none());
+    assert_eq!(cache.lookup("mail.example.com", 46, 1).unwrap().answers.len(), 1);
+
+    // and the proof of a negative answer stays with the SOA
+    let mut pack = negative("nope.example.com", RDataType::A(None), ResultCode::NXDOMAIN, 3600, 60);
+    pack.authorities.push(rrsig("example.com", 6, 3600));
+    pack.authorities.push(DnsRecord {
+        ttl: Some(60),
+        ..record("example.com", RDataType::NSEC(Some(NsecData {
+            next_domain: "www.example.com".to_owned(),
+            types: vec![2, 6, 46, 47],
+        })))
+    });
+    pack.authorities.push(rrsig("example.com", 47, 60));
+    cache.insert_negative(&pack);
+    let types: Vec<u16> = cache.lookup("nope.example.com", 1, 1).unwrap().authorities.iter().map(|r| r.rtype.to_num()).collect();
+    assert_eq!(types, vec![6, 46, 47, 46]);
+}
+
+#[test]
+fn only_the_question_and_its_cnames_are_cached() {
+    let cache = Cache::new(1 << 20);
+    let mut pack = response("www.example.com", RDataType::A(None));
+    pack.answers.push(record("www.example.com", RDataType::CNAME(Some("web.example.com".to_owned()))));
+    pack.answers.push(a("web.example.com", [10, 0, 0, 1]));
+    // thrown in by the upstream, nobody asked
+    pack.answers.push(a("www.bank.example", [10, 0, 0, 66]));
+    pack.answers.push(record("example.com", RDataType::NS(Some("ns.evil.example".to_owned()))));
+    cache.insert_answers(&pack);
+
+    assert_eq!(cache.lookup("www.example.com", 1, 1).unwrap().answers.len(), 2);
+    assert!(cache.lookup("web.example.com", 1, 1).is_some());
+    assert!(cache.lookup("www.bank.example", 1, 1).is_none());
+    assert!(cache.get(&CacheKey::new("example.com", 2, 1)).is_none());
+    assert_eq!(cache.len(), 2);
+
+    // without a question nothing is about anything
+    let mut pack = DnsPacket::new();
+    pack.answers.push(a("www.bank.example", [10, 0, 0, 66]));
+    cache.insert_answers(&pack);
+    assert!(cache.lookup("www.bank.example", 1, 1).is_none());
+}
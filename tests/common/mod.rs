@@ -0,0 +1,76 @@
+// fixtures shared by the integration tests, not every test file uses all of them
+#![allow(dead_code)]
+
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::thread;
+use deez_ns::buffer::DnsBuffer;
+use deez_ns::header::ResultCode;
+use deez_ns::packet::DnsPacket;
+use deez_ns::record::{DnsRecord, Domain, RClass, RDataType};
+use deez_ns::zone::Zone;
+
+/// An IN record with a ttl of 300
+pub fn record(name: &str, rtype: RDataType) -> DnsRecord {
+    DnsRecord {
+        domain: Domain::Domain(name.to_owned()),
+        rtype,
+        rclass: RClass::IN,
+        ttl: Some(300),
+        data_len: None,
+    }
+}
+
+/// Same as `record` without the ttl, which is what makes it a question
+pub fn question(name: &str, rtype: RDataType) -> DnsRecord {
+    DnsRecord { ttl: None, ..record(name, rtype) }
+}
+
+pub fn a(name: &str, ip: [u8; 4]) -> DnsRecord {
+    record(name, RDataType::A(Some(Ipv4Addr::from(ip))))
+}
+
+/// A recursive query for the A records of `domain`
+pub fn query(id: u16, domain: &str) -> DnsPacket {
+    let mut pack = DnsPacket::new();
+    pack.header.id = id;
+    pack.header.recursion_desired = true;
+    pack.questions.push(question(domain, RDataType::A(None)));
+    pack
+}
+
+/// What the zone answers, straight from it without a server in between
+pub fn ask(zone: &Zone, name: &str, rtype: RDataType) -> DnsPacket {
+    zone.answer(&question(name, rtype))
+}
+
+/// Fake server on `sock`, `respond` gets the qname and fills in the NOERROR response it starts with
+pub fn fake_server<F>(sock: UdpSocket, respond: F)
+where
+    F: Fn(&str, &mut DnsPacket) + Send + 'static,
+{
+    thread::spawn(move || loop {
+        let mut buf = DnsBuffer::with_size(4096);
+        let (len, from) = sock.recv_from(&mut buf.buf).unwrap();
+        buf.buf.truncate(len);
+        let query = DnsPacket::from_buf(&mut buf).unwrap();
+
+        let mut res = query.response_to(ResultCode::NOERROR);
+        let qname = query.questions[0].domain.name().unwrap().to_owned();
+        respond(&qname, &mut res);
+
+        let mut out = DnsBuffer::with_size(4096);
+        res.write(&mut out).unwrap();
+        sock.send_to(&out.buf[..out.pos], from).unwrap();
+    });
+}
+
+/// A `fake_server` on a free port of 127.0.0.1, for when there is only one upstream
+pub fn stub_upstream<F>(respond: F) -> SocketAddr
+where
+    F: Fn(&str, &mut DnsPacket) + Send + 'static,
+{
+    let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = sock.local_addr().unwrap();
+    fake_server(sock, respond);
+    addr
+}
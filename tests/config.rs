@@ -0,0 +1,150 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use deez_ns::config::ServerConfig;
+
+fn args(list: &[&str]) -> Vec<String> {
+    list.iter().map(|a| a.to_string()).collect()
+}
+
+/// A config file in the temp dir, gone again when dropped
+struct ConfigFile {
+    path: PathBuf,
+}
+
+impl ConfigFile {
+    fn new(test: &str, text: &str) -> ConfigFile {
+        let path = std::env::temp_dir().join(format!("deez_ns_config_{}_{}.toml", test, std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        ConfigFile { path }
+    }
+
+    fn arg(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+}
+
+impl Drop for ConfigFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+const FILE: &str = r#"
+listen = ["127.0.0.1:5300", "[::1]:5300"]
+upstreams = ["127.0.0.1:5353", "8.8.8.8:53"]
+upstream_timeout_ms = 500
+"#;
+
+#[test]
+fn toml_and_flags() {
+    let file = ConfigFile::new("toml_and_flags", FILE);
+
+    let config = ServerConfig::from_file(&file.path).unwrap();
+    assert_eq!(config.listen, vec!["127.0.0.1:5300".parse::<SocketAddr>().unwrap(), "[::1]:5300".parse().unwrap()]);
+    assert_eq!(config.upstreams[0], "127.0.0.1:5353".parse().unwrap());
+    assert_eq!(config.upstream_timeout_ms, 500);
+    assert_eq!(config.tcp_idle_timeout_ms, ServerConfig::default().tcp_idle_timeout_ms);
+
+    let config = ServerConfig::from_args(args(&[
+        "--upstream", "127.0.0.1:9999",
+        "--config", file.arg(),
+        "--tcp-idle-timeout-ms", "100",
+        "--zone", "internal.example=zones/internal.zone",
+    ])).unwrap();
+    assert_eq!(config.listen.len(), 2);
+    assert_eq!(config.upstreams, vec!["127.0.0.1:9999".parse::<SocketAddr>().unwrap()]);
+    assert_eq!(config.upstream_timeout_ms, 500);
+    assert_eq!(config.tcp_idle_timeout_ms, 100);
+    assert_eq!(config.zones[0].origin, "internal.example");
+    assert_eq!(config.zones[0].file, PathBuf::from("zones/internal.zone"));
+}
+
+#[test]
+fn dnssec_flags() {
+    let config = ServerConfig::from_args(Vec::new()).unwrap();
+    assert!(!config.dnssec);
+    assert_eq!(config.trust_anchors.len(), 2);
+
+    let config = ServerConfig::from_args(args(&[
+        "--dnssec", "true",
+        "--trust-anchor", "example. 12345 13 2 49FD46E6C4B45C55D4AC69CBD3CD34AC1AFE51DE",
+    ])).unwrap();
+    assert!(config.dnssec);
+    assert_eq!(config.trust_anchors.len(), 1);
+    assert_eq!(config.trust_anchors[0].zone, "example");
+    assert_eq!(config.trust_anchors[0].ds.key_tag, 12345);
+}
+
+#[test]
+fn zone_keys_go_with_their_zone() {
+    let config = ServerConfig::from_args(args(&["--zone", "internal.example=zones/internal.zone"])).unwrap();
+    assert!(config.zones[0].keys.is_empty());
+
+    let config = ServerConfig::from_args(args(&[
+        "--zone", "internal.example=zones/internal.zone",
+        "--zone-key", "internal.example.=zones/internal.key",
+    ])).unwrap();
+    assert_eq!(config.zones[0].keys, vec![PathBuf::from("zones/internal.key")]);
+}
+
+#[test]
+fn tls_upstreams_replace_plain_ones() {
+    let file = ConfigFile::new("tls", FILE);
+    // only tls upstreams on the command line means only tls upstreams
+    let config = ServerConfig::from_args(args(&[
+        "--config", file.arg(),
+        "--tls-upstream", "1.1.1.1#one.one.one.one",
+        "--tls-cert", "tls/cert.pem",
+        "--tls-key", "tls/key.pem",
+    ])).unwrap();
+    assert!(config.upstreams.is_empty());
+    assert_eq!(config.tls_upstreams[0].addr, "1.1.1.1:853".parse().unwrap());
+    assert_eq!(config.tls_listen, vec!["0.0.0.0:853".parse::<SocketAddr>().unwrap()]);
+    assert_eq!(config.tls_cert, Some(PathBuf::from("tls/cert.pem")));
+}
+
+#[test]
+fn https_upstreams_replace_the_others() {
+    let config = ServerConfig::from_toml(r#"
+tls_upstreams = ["1.1.1.1#one.one.one.one"]
+https_upstreams = ["9.9.9.9#https://dns.quad9.net/dns-query"]
+"#).unwrap();
+    assert_eq!(config.https_upstreams[0].host, "dns.quad9.net");
+    assert!(config.https_listen.is_empty());
+
+    // and those push out tls ones from the file too
+    let file = ConfigFile::new("https", FILE);
+    let config = ServerConfig::from_args(args(&[
+        "--config", file.arg(),
+        "--https-upstream", "8.8.8.8#https://dns.google/dns-query",
+        "--https-listen", "127.0.0.1:8443",
+        "--tls-cert", "tls/cert.pem",
+        "--tls-key", "tls/key.pem",
+    ])).unwrap();
+    assert!(config.upstreams.is_empty());
+    assert!(config.tls_upstreams.is_empty());
+    assert_eq!(config.https_upstreams[0].addr, "8.8.8.8:443".parse().unwrap());
+    assert_eq!(config.https_listen, vec!["127.0.0.1:8443".parse::<SocketAddr>().unwrap()]);
+}
+
+#[test]
+fn bad_configs_are_errors() {
+    assert!(ServerConfig::from_toml("listen = []").is_err());
+    assert!(ServerConfig::from_toml("listen = [\"not an address\"]").is_err());
+    assert!(ServerConfig::from_toml("what = 1").is_err());
+    assert!(ServerConfig::from_args(args(&["--listen"])).is_err());
+    assert!(ServerConfig::from_args(args(&["--bogus", "1"])).is_err());
+    assert!(ServerConfig::from_args(args(&["--upstream-timeout-ms", "0"])).is_err());
+    assert!(ServerConfig::from_args(args(&["--zone", "example.com"])).is_err());
+    assert!(ServerConfig::from_args(args(&["--workers", "0"])).is_err());
+    assert!(ServerConfig::from_args(args(&["--max-connections", "0"])).is_err());
+    assert!(ServerConfig::from_args(args(&["--trust-anchor", "example. 12345 13"])).is_err());
+    assert!(ServerConfig::from_args(args(&["--zone-key", "nowhere.example=a.key"])).is_err());
+    assert!(ServerConfig::from_args(args(&["--tls-cert", "cert.pem"])).is_err());
+    assert!(ServerConfig::from_args(args(&["--tls-upstream", "1.1.1.1:853"])).is_err());
+    assert!(ServerConfig::from_args(args(&["--https-upstream", "8.8.8.8#http://dns.google/dns-query"])).is_err());
+    assert!(ServerConfig::from_args(args(&["--https-listen", "127.0.0.1:8443"])).is_err());
+    assert!(ServerConfig::from_toml("upstreams = []").is_err());
+    assert!(ServerConfig::from_args(args(&["--zone", "a.example=a.zone", "--zone-key", "a.example"])).is_err());
+    assert!(ServerConfig::from_toml("dnssec = true\ntrust_anchors = []").is_err());
+}
@@ -0,0 +1,463 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use ring::rand::SystemRandom;
+use ring::signature::{self, EcdsaKeyPair, Ed25519KeyPair, KeyPair, RsaKeyPair};
+use deez_ns::config::ServerConfig;
+use deez_ns::dnssec::{self, Security, TrustAnchor, Validator};
+use deez_ns::edns::Edns;
+use deez_ns::encoding;
+use deez_ns::header::ResultCode;
+use deez_ns::packet::DnsPacket;
+use deez_ns::record::{DnsRecord, Domain, RDataType, DnskeyData, DsData, RrsigData, NsecData, Nsec3Data, SoaData};
+use deez_ns::resolver::{self, Resolver};
+use deez_ns::server::Server;
+
+mod common;
+use common::{a, fake_server, question, record, stub_upstream};
+
+const A: u16 = 1;
+const NS: u16 = 2;
+const SOA: u16 = 6;
+const AAAA: u16 = 28;
+const DS: u16 = 43;
+const RRSIG: u16 = 46;
+const NSEC: u16 = 47;
+const DNSKEY: u16 = 48;
+const NSEC3PARAM: u16 = 51;
+
+type Signer = Box<dyn Fn(&[u8]) -> Vec<u8> + Send + Sync>;
+
+struct Key {
+    zone: String,
+    dnskey: DnskeyData,
+    sign: Signer,
+}
+
+impl Key {
+    fn new(zone: &str, algorithm: u8, public_key: Vec<u8>, sign: Signer) -> Key {
+        Key {
+            zone: zone.to_owned(),
+            dnskey: DnskeyData { flags: 257, protocol: 3, algorithm, public_key },
+            sign,
+        }
+    }
+
+    fn ecdsa(zone: &str, algorithm: u8) -> Key {
+        let rng = SystemRandom::new();
+        let alg = match algorithm {
+            dnssec::ECDSAP256SHA256 => &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+            _ => &signature::ECDSA_P384_SHA384_FIXED_SIGNING,
+        };
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(alg, &rng).unwrap();
+        let pair = EcdsaKeyPair::from_pkcs8(alg, pkcs8.as_ref(), &rng).unwrap();
+        // without the 0x04 in front
+        let public_key = pair.public_key().as_ref()[1..].to_vec();
+        Key::new(zone, algorithm, public_key, Box::new(move |data| pair.sign(&rng, data).unwrap().as_ref().to_vec()))
+    }
+
+    fn ed25519(zone: &str) -> Key {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let public_key = pair.public_key().as_ref().to_vec();
+        Key::new(zone, dnssec::ED25519, public_key, Box::new(move |data| pair.sign(data).as_ref().to_vec()))
+    }
+
+    // ring cant make rsa keys, this one was made with openssl
+    fn rsa(zone: &str) -> Key {
+        let pkcs8 = std::fs::read(format!("{}/mock_packets/dnssec/rsa_key.pk8", env!("CARGO_MANIFEST_DIR"))).unwrap();
+        let pair = RsaKeyPair::from_pkcs8(&pkcs8).unwrap();
+        let (n, e) = rsa_public(pair.public().as_ref());
+        let mut public_key = vec![e.len() as u8];
+        public_key.extend(e);
+        public_key.extend(n);
+        Key::new(zone, dnssec::RSASHA256, public_key, Box::new(move |data| {
+            let mut sig = vec![0; pair.public().modulus_len()];
+            pair.sign(&signature::RSA_PKCS1_SHA256, &SystemRandom::new(), data, &mut sig).unwrap();
+            sig
+        }))
+    }
+
+    fn ds(&self) -> DsData {
+        DsData {
+            key_tag: dnssec::key_tag(&self.dnskey),
+            algorithm: self.dnskey.algorithm,
+            digest_type: dnssec::DIGEST_SHA256,
+            digest: dnssec::ds_digest(&self.zone, &self.dnskey, dnssec::DIGEST_SHA256).unwrap(),
+        }
+    }
+
+    // the set and its RRSIG
+    fn signed(&self, rrset: Vec<DnsRecord>) -> Vec<DnsRecord> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+        let owner = rrset[0].domain.name().unwrap().to_owned();
+        let mut sig = RrsigData {
+            type_covered: rrset[0].rtype.to_num(),
+            algorithm: self.dnskey.algorithm,
+            labels: dnssec::label_count(&owner) as u8,
+            original_ttl: 300,
+            expiration: now + 86400,
+            inception: now - 3600,
+            key_tag: dnssec::key_tag(&self.dnskey),
+            signer_name: self.zone.clone(),
+            signature: Vec::new(),
+        };
+        sig.signature = (self.sign)(&dnssec::signed_data(&rrset, &sig).unwrap());
+        let mut out = rrset;
+        out.push(record(&owner, RDataType::RRSIG(Some(sig))));
+        out
+    }
+
+    fn key_set(&self) -> Vec<DnsRecord> {
+        self.signed(vec![record(&self.zone, RDataType::DNSKEY(Some(self.dnskey.clone())))])
+    }
+
+    fn soa(&self) -> Vec<DnsRecord> {
+        self.signed(vec![record(&self.zone, RDataType::SOA(Some(SoaData {
+            mname: format!("ns.{}", self.zone),
+            rname: format!("hostmaster.{}", self.zone),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+        })))])
+    }
+
+    fn nsec(&self, owner: &str, next: &str, types: &[u16]) -> Vec<DnsRecord> {
+        let mut types = types.to_vec();
+        types.extend([RRSIG, NSEC]);
+        types.sort();
+        self.signed(vec![record(owner, RDataType::NSEC(Some(NsecData { next_domain: next.to_owned(), types })))])
+    }
+}
+
+// n and e out of a DER RSAPublicKey, SEQUENCE { INTEGER, INTEGER }
+fn rsa_public(der: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    fn item(der: &[u8]) -> (&[u8], &[u8]) {
+        let (len, at) = match der[1] {
+            l if l < 0x80 => (l as usize, 2),
+            l => {
+                let n = (l & 0x7F) as usize;
+                (der[2..2 + n].iter().fold(0, |acc, b| acc << 8 | *b as usize), 2 + n)
+            }
+        };
+        (&der[at..at + len], &der[at + len..])
+    }
+    let (seq, _) = item(der);
+    let (n, rest) = item(seq);
+    let (e, _) = item(rest);
+    let n = n.strip_prefix(&[0]).unwrap_or(n);
+    (n.to_vec(), e.to_vec())
+}
+
+type Responses = HashMap<(String, u16), (ResultCode, Vec<DnsRecord>, Vec<DnsRecord>)>;
+
+/// Everything the stub upstream knows, a signed root over test., which delegates to a zone
+/// signed with each algorithm and one that isnt signed at all
+fn fixture() -> (Responses, TrustAnchor) {
+    let root = Key::rsa("");
+    let test = Key::ecdsa("test", dnssec::ECDSAP256SHA256);
+    let ed = Key::ed25519("ed.test");
+    let p384 = Key::ecdsa("p384.test", dnssec::ECDSAP384SHA384);
+
+    let mut r = Responses::new();
+    let mut add = |name: &str, rtype: u16, rescode: ResultCode, answers: Vec<DnsRecord>, authorities: Vec<DnsRecord>| {
+        r.insert((name.to_owned(), rtype), (rescode, answers, authorities));
+    };
+    let ok = ResultCode::NOERROR;
+
+    add("", DNSKEY, ok, root.key_set(), vec![]);
+    add("test", DS, ok, root.signed(vec![record("test", RDataType::DS(Some(test.ds())))]), vec![]);
+
+    // test. with NSEC, in canonical order: test alias bad ed insecure p384 www
+    add("test", DNSKEY, ok, test.key_set(), vec![]);
+    add("www.test", A, ok, test.signed(vec![a("www.test", [192, 0, 2, 1])]), vec![]);
+    add("alias.test", A, ok, test.signed(vec![record("alias.test", RDataType::CNAME(Some("www.ed.test".to_owned())))]), vec![]);
+    add("ed.test", DS, ok, test.signed(vec![record("ed.test", RDataType::DS(Some(ed.ds())))]), vec![]);
+    add("p384.test", DS, ok, test.signed(vec![record("p384.test", RDataType::DS(Some(p384.ds())))]), vec![]);
+    add("insecure.test", DS, ok, vec![], [test.soa(), test.nsec("insecure.test", "p384.test", &[NS])].concat());
+    add("nope.test", A, ResultCode::NXDOMAIN, vec![], [
+        test.soa(),
+        test.nsec("insecure.test", "p384.test", &[NS]),
+        test.nsec("test", "alias.test", &[SOA, NS, DNSKEY]),
+    ].concat());
+    add("www.test", AAAA, ok, vec![], [test.soa(), test.nsec("www.test", "test", &[A])].concat());
+    add("nsecless.test", A, ResultCode::NXDOMAIN, vec![], test.soa());
+    add("stripped.test", A, ok, vec![a("stripped.test", [192, 0, 2, 9])], vec![]);
+    let mut bad = test.signed(vec![a("bad.test", [192, 0, 2, 66])]);
+    bad[0] = a("bad.test", [192, 0, 2, 67]);
+    add("bad.test", A, ok, bad, vec![]);
+
+    // ed.test. has a wildcard
+    add("ed.test", DNSKEY, ok, ed.key_set(), vec![]);
+    add("www.ed.test", A, ok, ed.signed(vec![a("www.ed.test", [192, 0, 2, 2])]), vec![]);
+    let mut wildcard = ed.signed(vec![a("*.ed.test", [192, 0, 2, 3])]);
+    for rec in wildcard.iter_mut() {
+        rec.domain = Domain::Domain("anything.ed.test".to_owned());
+    }
+    add("anything.ed.test", A, ok, wildcard, ed.nsec("*.ed.test", "www.ed.test", &[A]));
+
+    // p384.test. uses NSEC3
+    add("p384.test", DNSKEY, ok, p384.key_set(), vec![]);
+    add("www.p384.test", A, ok, p384.signed(vec![a("www.p384.test", [192, 0, 2, 4])]), vec![]);
+    let salt = vec![0xAA, 0xBB];
+    let mut hashed: Vec<(Vec<u8>, Vec<u16>)> = vec![
+        (dnssec::nsec3_hash("p384.test", 1, &salt), vec![NS, SOA, RRSIG, DNSKEY, NSEC3PARAM]),
+        (dnssec::nsec3_hash("www.p384.test", 1, &salt), vec![A, RRSIG]),
+    ];
+    hashed.sort();
+    let mut nsec3s = Vec::new();
+    for (i, (hash, types)) in hashed.iter().enumerate() {
+        let owner = format!("{}.p384.test", encoding::base32hex_encode(hash).to_lowercase());
+        nsec3s.extend(p384.signed(vec![record(&owner, RDataType::NSEC3(Some(Nsec3Data {
+            hash_algorithm: dnssec::NSEC3_SHA1,
+            flags: 0,
+            iterations: 1,
+            salt: salt.clone(),
+            next_hashed: hashed[(i + 1) % hashed.len()].0.clone(),
+            types: types.clone(),
+        })))]));
+    }
+    add("nope.p384.test", A, ResultCode::NXDOMAIN, vec![], [p384.soa(), nsec3s].concat());
+
+    // insecure.test. isnt signed at all
+    add("www.insecure.test", A, ok, vec![a("www.insecure.test", [192, 0, 2, 5])], vec![]);
+
+    (r, TrustAnchor { zone: String::new(), ds: root.ds() })
+}
+
+// the fixture's answer to the question in `res`, REFUSED for anything it doesnt know
+fn answer_from(responses: &Responses, qname: &str, res: &mut DnsPacket) {
+    match responses.get(&(qname.to_owned(), res.questions[0].rtype.to_num())) {
+        Some((rescode, answers, authorities)) => {
+            res.header.rescode = *rescode;
+            res.header.authoritative_answer = true;
+            res.answers = answers.clone();
+            res.authorities = authorities.clone();
+        }
+        None => res.header.rescode = ResultCode::REFUSED,
+    }
+}
+
+/// Authoritative server for `zone` on `sock`, answering from the fixture. Names under `child` get
+/// referred to it, except for the child's DS which is kept here like a real parent does. Its own
+/// DS it doesnt have, that is a NODATA
+fn authority(sock: UdpSocket, zone: &'static str, child: Option<(&'static str, [u8; 4])>, responses: Arc<Responses>) {
+    fake_server(sock, move |qname, res| {
+        let qtype = res.questions[0].rtype.to_num();
+        match child {
+            Some((child, ip)) if resolver::in_zone(qname, child) && !(qname == child && qtype == DS) => {
+                let ns = format!("ns.{}", child);
+                res.authorities.push(record(child, RDataType::NS(Some(ns.clone()))));
+                res.resources.push(a(&ns, ip));
+            }
+            _ if qname == zone && qtype == DS => res.header.authoritative_answer = true,
+            _ => answer_from(&responses, qname, res),
+        }
+    });
+}
+
+fn validating_server() -> Arc<Server> {
+    let (responses, anchor) = fixture();
+    let upstream = stub_upstream(move |qname, res| answer_from(&responses, qname, res));
+    Arc::new(Server::new(ServerConfig {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        upstreams: vec![upstream],
+        upstream_timeout_ms: 1000,
+        dnssec: true,
+        trust_anchors: vec![anchor],
+        ..ServerConfig::default()
+    }).unwrap())
+}
+
+fn ask(server: &Server, name: &str, rtype: RDataType, checking_disabled: bool, dnssec_ok: bool) -> DnsPacket {
+    let mut pack = DnsPacket::new();
+    pack.header.id = 0x5EC;
+    pack.header.recursion_desired = true;
+    pack.header.checking_disabled = checking_disabled;
+    pack.questions.push(question(name, rtype));
+    let mut edns = Edns::new(1232);
+    edns.dnssec_ok = dnssec_ok;
+    pack.set_edns(Some(edns));
+    server.resolve(&pack).unwrap()
+}
+
+fn has_rrsig(pack: &DnsPacket) -> bool {
+    pack.answers.iter().chain(pack.authorities.iter()).any(|r| r.rtype.to_num() == RRSIG)
+}
+
+#[test]
+fn chain_of_trust_for_every_algorithm() {
+    let server = validating_server();
+    for (name, ip) in [("www.test", [192, 0, 2, 1]), ("www.ed.test", [192, 0, 2, 2]), ("www.p384.test", [192, 0, 2, 4])] {
+        let res = ask(&server, name, RDataType::A(None), false, true);
+        assert_eq!(res.header.rescode, ResultCode::NOERROR, "{}", name);
+        assert!(res.header.authed_data, "{}", name);
+        assert!(res.answers.iter().any(|r| r.rtype == RDataType::A(Some(Ipv4Addr::from(ip)))), "{}", name);
+        assert!(has_rrsig(&res), "{}", name);
+    }
+
+    // without DO the signatures stay out, AD is still there
+    let res = ask(&server, "www.test", RDataType::A(None), false, false);
+    assert!(res.header.authed_data);
+    assert!(!has_rrsig(&res));
+}
+
+#[test]
+fn cnames_and_wildcards() {
+    let server = validating_server();
+    let res = ask(&server, "alias.test", RDataType::A(None), false, true);
+    assert!(res.header.authed_data);
+    assert!(res.answers.iter().any(|r| r.rtype == RDataType::A(Some(Ipv4Addr::new(192, 0, 2, 2)))));
+
+    let res = ask(&server, "anything.ed.test", RDataType::A(None), false, true);
+    assert_eq!(res.header.rescode, ResultCode::NOERROR);
+    assert!(res.header.authed_data);
+}
+
+#[test]
+fn proven_nonexistence() {
+    let server = validating_server();
+    let res = ask(&server, "nope.test", RDataType::A(None), false, true);
+    assert_eq!(res.header.rescode, ResultCode::NXDOMAIN);
+    assert!(res.header.authed_data);
+
+    let res = ask(&server, "www.test", RDataType::AAAA(None), false, true);
+    assert_eq!(res.header.rescode, ResultCode::NOERROR);
+    assert!(res.answers.is_empty());
+    assert!(res.header.authed_data);
+
+    let res = ask(&server, "nope.p384.test", RDataType::A(None), false, true);
+    assert_eq!(res.header.rescode, ResultCode::NXDOMAIN);
+    assert!(res.header.authed_data);
+}
+
+#[test]
+fn insecure_delegations_pass_without_ad() {
+    let server = validating_server();
+    let res = ask(&server, "www.insecure.test", RDataType::A(None), false, true);
+    assert_eq!(res.header.rescode, ResultCode::NOERROR);
+    assert!(!res.header.authed_data);
+    assert_eq!(res.answers.len(), 1);
+}
+
+#[test]
+fn bogus_answers_are_servfail() {
+    let server = validating_server();
+    for name in ["bad.test", "stripped.test", "nsecless.test"] {
+        let res = ask(&server, name, RDataType::A(None), false, true);
+        assert_eq!(res.header.rescode, ResultCode::SERVFAIL, "{}", name);
+        assert!(res.answers.is_empty(), "{}", name);
+    }
+
+    // unless the client says it checks for itself
+    let res = ask(&server, "bad.test", RDataType::A(None), true, true);
+    assert_eq!(res.header.rescode, ResultCode::NOERROR);
+    assert!(!res.header.authed_data);
+    assert!(res.answers.iter().any(|r| r.rtype == RDataType::A(Some(Ipv4Addr::new(192, 0, 2, 67)))));
+}
+
+#[test]
+fn validated_keys_expire_with_their_ttl() {
+    let (mut responses, anchor) = fixture();
+    // the signature is over the original ttl, so the keys of test. still check out with a shorter one
+    for rec in responses.get_mut(&("test".to_owned(), DNSKEY)).unwrap().1.iter_mut() {
+        rec.ttl = Some(1);
+    }
+    let fetched = RefCell::new(Vec::new());
+    let fetch = |q: &DnsRecord| {
+        let key = (q.domain.name().unwrap().to_owned(), q.rtype.to_num());
+        fetched.borrow_mut().push(key.clone());
+        let (rescode, answers, authorities) = responses.get(&key).ok_or_else(|| anyhow::anyhow!("no such thing"))?;
+        let mut res = DnsPacket::new().response_to(*rescode);
+        res.answers = answers.clone();
+        res.authorities = authorities.clone();
+        Ok(res)
+    };
+    let mut res = DnsPacket::new().response_to(ResultCode::NOERROR);
+    res.questions.push(question("www.test", RDataType::A(None)));
+    res.answers = responses[&("www.test".to_owned(), A)].1.clone();
+
+    let validator = Validator::new(vec![anchor]);
+    assert_eq!(validator.validate(&res, &fetch), Security::Secure);
+    assert_eq!(validator.len(), 2);
+    assert!(!fetched.take().is_empty());
+
+    // known keys arent fetched again
+    assert_eq!(validator.validate(&res, &fetch), Security::Secure);
+    assert!(fetched.take().is_empty());
+
+    // until their ttl is up, the root's are still good
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(validator.validate(&res, &fetch), Security::Secure);
+    assert_eq!(fetched.take(), vec![("test".to_owned(), DS), ("test".to_owned(), DNSKEY)]);
+    assert_eq!(validator.len(), 2);
+}
+
+#[test]
+fn ds_is_asked_of_the_parent_when_recursing() {
+    let (responses, anchor) = fixture();
+    let responses = Arc::new(responses);
+    let root = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let port = root.local_addr().unwrap().port();
+    authority(root, "", Some(("test", [127, 0, 0, 2])), responses.clone());
+    authority(UdpSocket::bind(("127.0.0.2", port)).unwrap(), "test", Some(("ed.test", [127, 0, 0, 3])), responses.clone());
+    authority(UdpSocket::bind(("127.0.0.3", port)).unwrap(), "ed.test", None, responses);
+
+    let mut resolver = Resolver::new(vec![SocketAddr::from(([127, 0, 0, 1], port))], Duration::from_secs(2));
+    resolver.port = port;
+    resolver.dnssec_ok = true;
+    let fetch = |q: &DnsRecord| resolver.resolve(q);
+
+    // the second time round the delegation to ed.test is known, its DS still has to come from test
+    for round in 0..2 {
+        let validator = Validator::new(vec![anchor.clone()]);
+        let res = resolver.resolve(&question("www.ed.test", RDataType::A(None))).unwrap();
+        assert_eq!(validator.validate(&res, &fetch), Security::Secure, "{}", round);
+
+        let res = resolver.resolve(&question("ed.test", RDataType::DS(None))).unwrap();
+        assert!(res.answers.iter().any(|r| matches!(r.rtype, RDataType::DS(Some(_)))), "{}", round);
+        assert_eq!(validator.validate(&res, &fetch), Security::Secure, "{}", round);
+    }
+}
+
+#[test]
+fn rfc8080_example() {
+    // section 6, the first example
+    let key = DnskeyData {
+        flags: 257,
+        protocol: 3,
+        algorithm: dnssec::ED25519,
+        public_key: encoding::base64_decode("l02Woi0iS8Aa25FQkUd9RMzZHJpBoRQwAQEX1SxZJA4=").unwrap(),
+    };
+    assert_eq!(dnssec::key_tag(&key), 3613);
+    assert_eq!(
+        dnssec::ds_digest("example.com", &key, dnssec::DIGEST_SHA256).unwrap(),
+        encoding::hex_decode("3aa5ab37efce57f737fc1627013fee07bdf241bd10f3b1964ab55c78e79a304b").unwrap(),
+    );
+
+    let mx = DnsRecord {
+        ttl: Some(3600),
+        ..record("example.com", RDataType::MX(Some(deez_ns::record::MxData { priority: 10, exchange: "mail.example.com".to_owned() })))
+    };
+    let sig = RrsigData {
+        type_covered: 15,
+        algorithm: dnssec::ED25519,
+        labels: 2,
+        original_ttl: 3600,
+        expiration: 1440021600,
+        inception: 1438207200,
+        key_tag: 3613,
+        signer_name: "example.com".to_owned(),
+        signature: encoding::base64_decode(
+            "oL9krJun7xfBOIWcGHi7mag5/hdZrKWw15jPGrHpjQeRAvTdszaPD+QLs3fx8A4M3e23mRZ9VrbpMngwcrqNAg==",
+        ).unwrap(),
+    };
+    let data = dnssec::signed_data(&[mx], &sig).unwrap();
+    assert!(dnssec::verify_signature(&key, &data, &sig.signature));
+    assert!(!dnssec::verify_signature(&key, &data[1..], &sig.signature));
+}
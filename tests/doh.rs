@@ -0,0 +1,280 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use http_body_util::{BodyExt, Full};
+use hyper::{body::Bytes, header, Method, Request, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+use tokio_rustls::TlsConnector;
+use deez_ns::buffer::DnsBuffer;
+use deez_ns::config::ServerConfig;
+use deez_ns::doh::{self, HttpsUpstream};
+use deez_ns::encoding;
+use deez_ns::header::ResultCode;
+use deez_ns::packet::DnsPacket;
+use deez_ns::record::{DnsRecord, Domain, RDataType, SoaData};
+use deez_ns::server::{Handler, Server};
+use deez_ns::tls;
+
+mod common;
+use common::query;
+
+struct Pki {
+    dir: PathBuf,
+}
+
+impl Pki {
+    /// A CA and a certificate from it for dns.test, as PEM files in a temp dir
+    fn new(test: &str) -> Pki {
+        let dir = std::env::temp_dir().join(format!("deez_ns_doh_{}_{}", test, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["dns.test".to_owned()]).unwrap()
+            .signed_by(&key, &ca, &ca_key)
+            .unwrap();
+
+        std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), key.serialize_pem()).unwrap();
+        Pki { dir }
+    }
+
+    fn file(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+}
+
+impl Drop for Pki {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn wire(pack: &DnsPacket) -> Vec<u8> {
+    let mut buf = DnsBuffer::from_bytes(&[]);
+    pack.write(&mut buf).unwrap();
+    buf.buf.truncate(buf.pos);
+    buf.buf
+}
+
+/// A DoH server answering 192.0.2.53 with a ttl of 60 for everything, except names under nx
+/// which dont exist with an SOA ttl of 300
+fn doh_server(pki: &Pki) -> (Arc<Server>, SocketAddr) {
+    let server = Arc::new(Server::new(ServerConfig {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        tls_listen: Vec::new(),
+        https_listen: vec!["127.0.0.1:0".parse().unwrap()],
+        tls_cert: Some(pki.file("cert.pem")),
+        tls_key: Some(pki.file("key.pem")),
+        ..ServerConfig::default()
+    }).unwrap());
+    let handler: Arc<Handler> = Arc::new(|pack: DnsPacket, r_buf: &mut DnsBuffer| {
+        let question = pack.questions[0].clone();
+        if question.domain.name().is_some_and(|n| n.starts_with("nx.")) {
+            let mut res = pack.response_to(ResultCode::NXDOMAIN);
+            res.authorities.push(DnsRecord {
+                domain: Domain::Domain("test".to_owned()),
+                rtype: RDataType::SOA(Some(SoaData {
+                    mname: "ns.test".to_owned(),
+                    rname: "hostmaster.test".to_owned(),
+                    serial: 1,
+                    refresh: 3600,
+                    retry: 600,
+                    expire: 86400,
+                    minimum: 300,
+                })),
+                ttl: Some(300),
+                ..question
+            });
+            return res.write(r_buf);
+        }
+        let mut res = pack.response_to(ResultCode::NOERROR);
+        res.answers.push(DnsRecord {
+            ttl: Some(60),
+            rtype: RDataType::A(Some(Ipv4Addr::new(192, 0, 2, 53))),
+            ..question
+        });
+        res.write(r_buf)
+    });
+    server.serve_https(handler).unwrap();
+    let addr = server.https_addrs().unwrap()[0];
+    (server, addr)
+}
+
+struct Reply {
+    status: StatusCode,
+    cache_control: Option<String>,
+    body: Bytes,
+}
+
+// a plain HTTP/2 client, to see what comes back besides the DNS message
+fn request(pki: &Pki, addr: SocketAddr, req: Request<Full<Bytes>>) -> Reply {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    runtime.block_on(async {
+        let config = tls::client_config(&pki.file("ca.pem"), doh::DOH_ALPN).unwrap();
+        let sock = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let stream = TlsConnector::from(config).connect("dns.test".try_into().unwrap(), sock).await.unwrap();
+        let (mut sender, conn) = hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream)).await.unwrap();
+        tokio::spawn(conn);
+
+        let res = sender.send_request(req).await.unwrap();
+        Reply {
+            status: res.status(),
+            cache_control: res.headers().get(header::CACHE_CONTROL).map(|v| v.to_str().unwrap().to_owned()),
+            body: res.into_body().collect().await.unwrap().to_bytes(),
+        }
+    })
+}
+
+fn get(query: &str) -> Request<Full<Bytes>> {
+    Request::get(format!("https://dns.test{}", query)).body(Full::new(Bytes::new())).unwrap()
+}
+
+fn post(path: &str, content_type: &str, body: Vec<u8>) -> Request<Full<Bytes>> {
+    Request::post(format!("https://dns.test{}", path))
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Full::new(Bytes::from(body)))
+        .unwrap()
+}
+
+fn parse(body: &Bytes) -> DnsPacket {
+    DnsPacket::from_buf(&mut DnsBuffer::from_bytes(body)).unwrap()
+}
+
+#[test]
+fn get_and_post_answer() {
+    let pki = Pki::new("answer");
+    let (_server, addr) = doh_server(&pki);
+
+    let dns = encoding::base64url_encode(&wire(&query(443, "www.example.com")));
+    let reply = request(&pki, addr, get(&format!("/dns-query?ct&dns={}", dns)));
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.cache_control.as_deref(), Some("max-age=60"));
+    let res = parse(&reply.body);
+    assert_eq!(res.header.id, 443);
+    assert!(matches!(res.answers[0].rtype, RDataType::A(Some(ip)) if ip == Ipv4Addr::new(192, 0, 2, 53)));
+
+    let reply = request(&pki, addr, post("/dns-query", doh::DNS_MESSAGE, wire(&query(443, "www.example.com"))));
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(reply.cache_control.as_deref(), Some("max-age=60"));
+    assert_eq!(parse(&reply.body).answers.len(), 1);
+
+    // negative answers are cached for as long as the SOA says
+    let reply = request(&pki, addr, post("/dns-query", doh::DNS_MESSAGE, wire(&query(443, "nx.example.com"))));
+    assert_eq!(parse(&reply.body).header.rescode, ResultCode::NXDOMAIN);
+    assert_eq!(reply.cache_control.as_deref(), Some("max-age=300"));
+
+    // and the client's own
+    let config = tls::client_config(&pki.file("ca.pem"), doh::DOH_ALPN).unwrap();
+    let client = doh::Client::new(config).unwrap();
+    let upstream: HttpsUpstream = format!("{}#https://dns.test/dns-query", addr).parse().unwrap();
+    let res = client.exchange(&query(443, "www.example.com"), &upstream, Duration::from_secs(2)).unwrap();
+    assert_eq!(res.header.id, 443);
+    assert_eq!(res.answers.len(), 1);
+}
+
+#[test]
+fn bad_requests_get_http_errors() {
+    let pki = Pki::new("errors");
+    let (_server, addr) = doh_server(&pki);
+    let query = wire(&query(443, "www.example.com"));
+
+    let cases = [
+        (get("/dns-query"), StatusCode::BAD_REQUEST),
+        (get("/dns-query?dns=not+base64"), StatusCode::BAD_REQUEST),
+        (get(&format!("/other?dns={}", encoding::base64url_encode(&query))), StatusCode::NOT_FOUND),
+        (post("/dns-query", "text/plain", query.clone()), StatusCode::UNSUPPORTED_MEDIA_TYPE),
+        (post("/dns-query", doh::DNS_MESSAGE, vec![0; doh::MAX_BODY_SIZE + 1]), StatusCode::PAYLOAD_TOO_LARGE),
+        // too short for even a header, so no FORMERR either
+        (post("/dns-query", doh::DNS_MESSAGE, vec![0; 5]), StatusCode::BAD_REQUEST),
+        (Request::builder().method(Method::PUT).uri("https://dns.test/dns-query").body(Full::new(Bytes::from(query.clone()))).unwrap(),
+            StatusCode::METHOD_NOT_ALLOWED),
+    ];
+    for (req, status) in cases {
+        let uri = req.uri().clone();
+        assert_eq!(request(&pki, addr, req).status, status, "{}", uri);
+    }
+
+    // a query that is there but wont parse is still answered, in DNS
+    let mut garbled = query.clone();
+    garbled.truncate(14);
+    let reply = request(&pki, addr, post("/dns-query", doh::DNS_MESSAGE, garbled));
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(parse(&reply.body).header.rescode, ResultCode::FORMERR);
+    assert_eq!(reply.cache_control.as_deref(), Some("max-age=0"));
+}
+
+#[test]
+fn forwarding_over_doh() {
+    let pki = Pki::new("forwarding");
+    let (_upstream, addr) = doh_server(&pki);
+
+    // answers over DoH itself, so the upstream client gets used from inside the server's runtime
+    let server = Arc::new(Server::new(ServerConfig {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        upstreams: Vec::new(),
+        https_upstreams: vec![format!("{}#https://dns.test/dns-query", addr).parse().unwrap()],
+        tls_ca_file: pki.file("ca.pem"),
+        tls_listen: Vec::new(),
+        https_listen: vec!["127.0.0.1:0".parse().unwrap()],
+        tls_cert: Some(pki.file("cert.pem")),
+        tls_key: Some(pki.file("key.pem")),
+        upstream_timeout_ms: 2000,
+        ..ServerConfig::default()
+    }).unwrap());
+    let handler: Arc<Handler> = {
+        let server = server.clone();
+        Arc::new(move |pack: DnsPacket, r_buf: &mut DnsBuffer| server.resolve(&pack)?.write(r_buf))
+    };
+    server.serve_https(handler).unwrap();
+
+    let res = server.resolve(&query(443, "www.example.com")).unwrap();
+    assert_eq!(res.header.rescode, ResultCode::NOERROR);
+    assert!(matches!(res.answers[0].rtype, RDataType::A(Some(ip)) if ip == Ipv4Addr::new(192, 0, 2, 53)));
+
+    let front = server.https_addrs().unwrap()[0];
+    let reply = request(&pki, front, post("/dns-query", doh::DNS_MESSAGE, wire(&query(443, "www.example.com"))));
+    assert_eq!(reply.status, StatusCode::OK);
+    assert_eq!(parse(&reply.body).answers.len(), 1);
+
+    // an upstream that isnt who it says it is gets nothing out of us
+    let server = Server::new(ServerConfig {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        upstreams: Vec::new(),
+        https_upstreams: vec![format!("{}#https://impostor.test/dns-query", addr).parse().unwrap()],
+        tls_ca_file: pki.file("ca.pem"),
+        upstream_timeout_ms: 500,
+        upstream_retries: 0,
+        ..ServerConfig::default()
+    }).unwrap();
+    assert_eq!(server.resolve(&query(443, "www.example.com")).unwrap().header.rescode, ResultCode::SERVFAIL);
+}
+
+#[test]
+fn https_upstreams_parse() {
+    let upstream: HttpsUpstream = "8.8.8.8#https://dns.google/dns-query".parse().unwrap();
+    assert_eq!(upstream.addr, "8.8.8.8:443".parse::<SocketAddr>().unwrap());
+    assert_eq!(upstream.host, "dns.google");
+    let upstream: HttpsUpstream = "[2001:4860:4860::8888]:8443#https://dns.google:8443/dns-query".parse().unwrap();
+    assert_eq!(upstream.addr, "[2001:4860:4860::8888]:8443".parse::<SocketAddr>().unwrap());
+    assert_eq!(upstream.host, "dns.google");
+    assert_eq!(upstream.url, "https://dns.google:8443/dns-query");
+    assert!("8.8.8.8#http://dns.google/dns-query".parse::<HttpsUpstream>().is_err());
+    assert!("8.8.8.8".parse::<HttpsUpstream>().is_err());
+    assert!("8.8.8.8#https:///dns-query".parse::<HttpsUpstream>().is_err());
+}
+
+#[test]
+fn base64url_has_no_padding() {
+    assert_eq!(encoding::base64url_encode(&[0xfb, 0xff]), "-_8");
+    assert_eq!(encoding::base64url_decode("-_8"), Some(vec![0xfb, 0xff]));
+    assert_eq!(encoding::base64url_decode("-_8="), Some(vec![0xfb, 0xff]));
+    assert_eq!(encoding::base64url_decode("+/8"), None);
+}
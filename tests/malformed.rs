@@ -0,0 +1,219 @@
+use std::net::{Ipv4Addr, TcpStream, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+use deez_ns::buffer::{self, DnsBuffer};
+use deez_ns::config::ServerConfig;
+use deez_ns::edns::{self, Edns};
+use deez_ns::error::ParseError;
+use deez_ns::header::ResultCode;
+use deez_ns::packet::DnsPacket;
+use deez_ns::record::{DnsRecord, RDataType};
+use deez_ns::server::{self, Handler, Server};
+use deez_ns::tcp;
+use deez_ns::view::PacketView;
+
+mod common;
+use common::query;
+
+/// Every file in mock_packets/malformed, named after what it should get back
+fn corpus() -> Vec<(String, Vec<u8>)> {
+    let dir = format!("{}/mock_packets/malformed", env!("CARGO_MANIFEST_DIR"));
+    let mut files: Vec<(String, Vec<u8>)> = std::fs::read_dir(dir).unwrap()
+        .map(|e| e.unwrap().path())
+        .map(|p| (p.file_stem().unwrap().to_string_lossy().into_owned(), std::fs::read(&p).unwrap()))
+        .collect();
+    files.sort();
+    assert!(files.len() > 10);
+    files
+}
+
+fn answer_everything(mut pack: DnsPacket, r_buf: &mut DnsBuffer) -> anyhow::Result<()> {
+    pack.header.response = true;
+    pack.answers.push(DnsRecord {
+        rtype: RDataType::A(Some(Ipv4Addr::new(10, 0, 0, 1))),
+        ttl: Some(60),
+        ..pack.questions[0].clone()
+    });
+    pack.write_truncated(r_buf)
+}
+
+fn respond(bytes: &[u8], handler: &Handler) -> Option<DnsPacket> {
+    let mut r_buf = DnsBuffer::new();
+    if !server::respond(&mut DnsBuffer::from_bytes(bytes), &mut r_buf, handler) {
+        return None;
+    }
+    r_buf.buf.truncate(r_buf.pos);
+    r_buf.pos = 0;
+    Some(DnsPacket::from_buf(&mut r_buf).unwrap())
+}
+
+#[test]
+fn corpus_gets_the_right_errors() {
+    for (name, bytes) in corpus() {
+        let res = respond(&bytes, &answer_everything);
+        let expected = match name.split('_').next().unwrap() {
+            "noreply" => {
+                assert!(res.is_none(), "{} got a reply", name);
+                continue;
+            }
+            "formerr" => ResultCode::FORMERR,
+            "notimp" => ResultCode::NOTIMP,
+            other => panic!("{}: no idea what {} means", name, other),
+        };
+        let res = res.unwrap_or_else(|| panic!("{} got no reply", name));
+        assert_eq!(res.header.rescode, expected, "{}", name);
+        assert_eq!(res.header.id, 0x1234, "{}", name);
+        assert!(res.header.response, "{}", name);
+        assert!(res.answers.is_empty(), "{}", name);
+    }
+}
+
+#[test]
+fn failing_handlers_give_servfail() {
+    let mut buf = DnsBuffer::new();
+    query(0xBEEF, "example.com").write(&mut buf).unwrap();
+    let bytes = buf.buf[..buf.pos].to_vec();
+
+    let res = respond(&bytes, &|_: DnsPacket, _: &mut DnsBuffer| Err(anyhow::anyhow!("nope"))).unwrap();
+    assert_eq!(res.header.rescode, ResultCode::SERVFAIL);
+    assert_eq!(res.header.id, 0xBEEF);
+    assert_eq!(res.questions.len(), 1);
+
+    // a half written response doesnt leak into the SERVFAIL
+    let res = respond(&bytes, &|pack: DnsPacket, r_buf: &mut DnsBuffer| {
+        pack.write(r_buf)?;
+        panic!("handler blew up");
+    }).unwrap();
+    assert_eq!(res.header.rescode, ResultCode::SERVFAIL);
+    assert_eq!(res.header.id, 0xBEEF);
+}
+
+#[test]
+fn error_replies_keep_edns() {
+    let fails = |_: DnsPacket, _: &mut DnsBuffer| Err(anyhow::anyhow!("nope"));
+    let mut opt = Edns::new(4096);
+    opt.dnssec_ok = true;
+
+    let servfail = query(0xBEEF, "example.com");
+    let mut notimp = query(0xBEEF, "example.com");
+    notimp.header.opcode = 2;
+    let mut formerr = query(0xBEEF, "example.com");
+    formerr.questions.push(formerr.questions[0].clone());
+
+    for (mut pack, rescode) in [(servfail, ResultCode::SERVFAIL), (notimp, ResultCode::NOTIMP), (formerr, ResultCode::FORMERR)] {
+        for with_edns in [false, true] {
+            pack.set_edns(with_edns.then(|| opt.clone()));
+            let mut buf = DnsBuffer::new();
+            pack.write(&mut buf).unwrap();
+
+            let res = respond(&buf.buf[..buf.pos], &fails).unwrap();
+            assert_eq!(res.header.rescode, rescode);
+            // our own OPT, not an echo of the client's
+            let reply = res.edns();
+            assert_eq!(reply.is_some(), with_edns, "{:?}", rescode);
+            if let Some(reply) = reply {
+                assert_eq!(reply.udp_payload_size, edns::SERVER_UDP_PAYLOAD);
+                assert!(reply.dnssec_ok);
+            }
+        }
+    }
+}
+
+#[test]
+fn server_survives_the_corpus() {
+    let server = Arc::new(Server::new(ServerConfig {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        workers: 2,
+        ..ServerConfig::default()
+    }).unwrap());
+    let addr = server.local_addrs().unwrap()[0];
+    let handler: Arc<Handler> = Arc::new(|pack: DnsPacket, r_buf: &mut DnsBuffer| {
+        if pack.questions[0].domain.name() == Some("panic.example.com") {
+            panic!("handler blew up");
+        }
+        answer_everything(pack, r_buf)
+    });
+    server.serve_tcp(handler.clone()).unwrap();
+    server.serve_udp(handler).unwrap();
+
+    let mut good = DnsBuffer::new();
+    query(0xBEEF, "example.com").write(&mut good).unwrap();
+    let mut boom = DnsBuffer::new();
+    query(0xBEEF, "panic.example.com").write(&mut boom).unwrap();
+
+    // udp, garbage first, then something that still has to be answered
+    let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    for (name, bytes) in corpus() {
+        sock.send_to(&bytes, addr).unwrap();
+        if !name.starts_with("noreply") {
+            let mut buf = DnsBuffer::with_size(4096);
+            let len = sock.recv(&mut buf.buf).unwrap();
+            buf.buf.truncate(len);
+            assert_ne!(DnsPacket::from_buf(&mut buf).unwrap().header.rescode, ResultCode::NOERROR, "{}", name);
+        }
+    }
+    for (bytes, rescode) in [(&boom, ResultCode::SERVFAIL), (&good, ResultCode::NOERROR), (&boom, ResultCode::SERVFAIL), (&good, ResultCode::NOERROR)] {
+        sock.send_to(&bytes.buf[..bytes.pos], addr).unwrap();
+        let mut buf = DnsBuffer::with_size(4096);
+        let len = sock.recv(&mut buf.buf).unwrap();
+        buf.buf.truncate(len);
+        assert_eq!(DnsPacket::from_buf(&mut buf).unwrap().header.rescode, rescode);
+    }
+
+    // tcp, all on the same connection
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    for (name, bytes) in corpus() {
+        tcp::write_frame(&mut stream, &DnsBuffer { pos: bytes.len(), ..DnsBuffer::from_bytes(&bytes) }).unwrap();
+        if !name.starts_with("noreply") {
+            let mut res = tcp::read_frame(&mut stream).unwrap().unwrap();
+            assert_ne!(DnsPacket::from_buf(&mut res).unwrap().header.rescode, ResultCode::NOERROR, "{}", name);
+        }
+    }
+    tcp::write_frame(&mut stream, &good).unwrap();
+    let mut res = tcp::read_frame(&mut stream).unwrap().unwrap();
+    assert_eq!(DnsPacket::from_buf(&mut res).unwrap().answers.len(), 1);
+}
+
+#[test]
+fn parse_errors_say_what_and_where() {
+    let expected = [
+        ("formerr_header_only", ParseError::EndOfBuffer { offset: 12 }),
+        ("formerr_label_past_end", ParseError::EndOfBuffer { offset: 16 }),
+        ("formerr_pointer_loop", ParseError::PointerLoop { offset: 12, jumps: buffer::MAX_JUMPS }),
+        ("formerr_pointer_out_of_range", ParseError::BadPointer { offset: 12, target: 0x3FFF }),
+        ("formerr_reserved_label_type", ParseError::BadLabelType { offset: 12, label: 0x41 }),
+        ("formerr_name_too_long", ParseError::NameTooLong { offset: 12 }),
+        ("formerr_question_missing_class", ParseError::EndOfBuffer { offset: 27 }),
+        ("formerr_rdlength_past_end", ParseError::BadRdataLength { offset: 40, len: 500 }),
+        ("formerr_opt_option_past_rdata", ParseError::BadOptionLength { offset: 40, len: 40 }),
+    ];
+    let corpus = corpus();
+    for (name, err) in expected {
+        let bytes = &corpus.iter().find(|(n, _)| n == name).unwrap().1;
+        let res = DnsPacket::from_buf(&mut DnsBuffer::from_bytes(bytes));
+        assert_eq!(res.unwrap_err(), err, "{}", name);
+    }
+
+    // the zero copy view tells the same story
+    let bytes = &corpus.iter().find(|(n, _)| n == "formerr_reserved_label_type").unwrap().1;
+    let view = PacketView::new(bytes).unwrap();
+    let err = view.questions().next().unwrap().unwrap_err();
+    assert_eq!(err, ParseError::BadLabelType { offset: 12, label: 0x41 });
+    assert_eq!(err.offset(), 12);
+
+    // and so does writing, for names that cant be put on the wire
+    let mut buf = DnsBuffer::from_bytes(&[]);
+    let err = buf.write_plain_domain(&format!("{}.com", "a".repeat(64))).unwrap_err();
+    assert_eq!(err, ParseError::LabelTooLong { offset: 0, len: 64 });
+
+    // whole packets too, the header is 12 bytes and the question has to go after it
+    let mut small = DnsBuffer::with_size(16);
+    let err = query(0xBEEF, "example.com").write(&mut small).unwrap_err().downcast::<ParseError>().unwrap();
+    assert_eq!(err, ParseError::BufferFull { offset: 16 });
+    assert_eq!(err.offset(), 16);
+    let mut buf = DnsBuffer::from_bytes(&[]);
+    let err = query(0xBEEF, &format!("{}.com", "a".repeat(64))).write_truncated(&mut buf).unwrap_err().downcast::<ParseError>().unwrap();
+    assert_eq!(err, ParseError::LabelTooLong { offset: 12, len: 64 });
+}
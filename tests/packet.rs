@@ -0,0 +1,306 @@
+use std::net::Ipv4Addr;
+use deez_ns::buffer::DnsBuffer;
+use deez_ns::edns::{Edns, EdnsOption};
+use deez_ns::packet::DnsPacket;
+use deez_ns::record::{DnsRecord, Domain, MxData, RClass, RDataType, SoaData, SrvData, NsecData, RrsigData};
+
+mod common;
+use common::{question, record};
+
+fn read_mock(name: &str) -> Vec<u8> {
+    std::fs::read(format!("{}/mock_packets/{}.txt", env!("CARGO_MANIFEST_DIR"), name)).unwrap()
+}
+
+fn round_trip(bytes: &[u8]) -> (DnsPacket, Vec<u8>) {
+    let pack = DnsPacket::from_buf(&mut DnsBuffer::from_bytes(bytes)).unwrap();
+    let mut out = DnsBuffer::from_bytes(&[]);
+    pack.write(&mut out).unwrap();
+    (pack, out.buf[..out.pos].to_vec())
+}
+
+#[test]
+fn mock_responses_round_trip() {
+    for name in ["r2", "r3", "r4"] {
+        let bytes = read_mock(name);
+        let (pack, out) = round_trip(&bytes);
+
+        assert_eq!(pack.questions.len(), pack.header.questions as usize, "{}", name);
+        assert_eq!(pack.answers.len(), pack.header.answers as usize, "{}", name);
+        assert_eq!(pack.authorities.len(), pack.header.authoritative_entries as usize, "{}", name);
+        assert_eq!(pack.resources.len(), pack.header.resource_entries as usize, "{}", name);
+        assert_eq!(out, bytes, "{}", name);
+    }
+}
+
+#[test]
+fn additional_section_goes_to_resources() {
+    let bytes = read_mock("trace_q");
+    let pack = DnsPacket::from_buf(&mut DnsBuffer::from_bytes(&bytes)).unwrap();
+
+    assert_eq!(pack.questions.len(), 1);
+    assert_eq!(pack.resources.len(), 1);
+    assert_eq!(pack.resources[0].rtype.to_num(), 41);
+}
+
+#[test]
+fn glue_survives_round_trip() {
+    let bytes = read_mock("r4");
+    let mut pack = DnsPacket::from_buf(&mut DnsBuffer::from_bytes(&bytes)).unwrap();
+    pack.resources.push(DnsRecord {
+        domain: Domain::Domain("ns1.google.com".to_owned()),
+        rtype: RDataType::A(Some(Ipv4Addr::new(216, 239, 32, 10))),
+        rclass: RClass::IN,
+        ttl: Some(300),
+        data_len: None,
+    });
+
+    let mut out = DnsBuffer::new();
+    pack.write(&mut out).unwrap();
+    let (again, again_bytes) = round_trip(&out.buf[..out.pos]);
+
+    assert_eq!(again.header.resource_entries, 1);
+    assert_eq!(again.resources.len(), 1);
+    assert_eq!(again.resources[0].domain.get_string(&mut out).unwrap(), "ns1.google.com");
+    assert!(matches!(again.resources[0].rtype, RDataType::A(Some(ip)) if ip == Ipv4Addr::new(216, 239, 32, 10)));
+    assert_eq!(again_bytes, out.buf[..out.pos].to_vec());
+}
+
+#[test]
+fn cname_chain_round_trip() {
+    let mut pack = DnsPacket::new();
+    pack.answers.push(record("www.example.com", RDataType::CNAME(Some("cdn.example.net".to_owned()))));
+    pack.answers.push(record("cdn.example.net", RDataType::CNAME(Some("edge.cdn.example.net".to_owned()))));
+    assert_eq!(pack.unresolved_cname("www.example.com", 1), Some("edge.cdn.example.net".to_owned()));
+
+    pack.answers.push(record("edge.cdn.example.net", RDataType::A(Some(Ipv4Addr::new(10, 0, 0, 1)))));
+    assert_eq!(pack.unresolved_cname("www.example.com", 1), None);
+
+    let mut out = DnsBuffer::new();
+    pack.write(&mut out).unwrap();
+    let (again, again_bytes) = round_trip(&out.buf[..out.pos]);
+    assert_eq!(again_bytes, out.buf[..out.pos].to_vec());
+    assert!(matches!(&again.answers[1].rtype, RDataType::CNAME(Some(t)) if t == "edge.cdn.example.net"));
+}
+
+#[test]
+fn mx_soa_ptr_srv_round_trip() {
+    let mut pack = DnsPacket::new();
+    pack.answers.push(record("example.com", RDataType::MX(Some(MxData {
+        priority: 10,
+        exchange: "mail.example.com".to_owned(),
+    }))));
+    pack.answers.push(record("_sip._tcp.example.com", RDataType::SRV(Some(SrvData {
+        priority: 1,
+        weight: 5,
+        port: 5060,
+        target: "sip.example.com".to_owned(),
+    }))));
+    pack.answers.push(record("1.0.0.10.in-addr.arpa", RDataType::PTR(Some("host.example.com".to_owned()))));
+    pack.authorities.push(record("example.com", RDataType::SOA(Some(SoaData {
+        mname: "ns1.example.com".to_owned(),
+        rname: "hostmaster.example.com".to_owned(),
+        serial: 2024010101,
+        refresh: 7200,
+        retry: 3600,
+        expire: 1209600,
+        minimum: 300,
+    }))));
+
+    let mut out = DnsBuffer::new();
+    pack.write(&mut out).unwrap();
+    let (again, again_bytes) = round_trip(&out.buf[..out.pos]);
+    assert_eq!(again_bytes, out.buf[..out.pos].to_vec());
+
+    assert!(matches!(&again.answers[0].rtype, RDataType::MX(Some(mx)) if mx.priority == 10 && mx.exchange == "mail.example.com"));
+    assert!(matches!(&again.answers[1].rtype, RDataType::SRV(Some(srv)) if srv.port == 5060 && srv.target == "sip.example.com"));
+    assert!(matches!(&again.answers[2].rtype, RDataType::PTR(Some(ptr)) if ptr == "host.example.com"));
+    assert!(matches!(&again.authorities[0].rtype, RDataType::SOA(Some(soa)) if soa.serial == 2024010101 && soa.minimum == 300 && soa.rname == "hostmaster.example.com"));
+}
+
+#[test]
+fn unknown_types_pass_through() {
+    let mut pack = DnsPacket::new();
+    // CAA 0 issue "ca.example"
+    let caa = b"\x00\x05issueca.example".to_vec();
+    pack.answers.push(record("example.com", RDataType::UNKNOWN(257, Some(caa.clone()))));
+    pack.answers.push(record("example.com", RDataType::A(Some(Ipv4Addr::new(10, 0, 0, 2)))));
+
+    let mut out = DnsBuffer::new();
+    pack.write(&mut out).unwrap();
+    let (again, again_bytes) = round_trip(&out.buf[..out.pos]);
+
+    assert_eq!(again_bytes, out.buf[..out.pos].to_vec());
+    assert!(matches!(&again.answers[0].rtype, RDataType::UNKNOWN(257, Some(data)) if *data == caa));
+    assert!(matches!(again.answers[1].rtype, RDataType::A(Some(ip)) if ip == Ipv4Addr::new(10, 0, 0, 2)));
+
+    let bytes = read_mock("trace_q");
+    let (_, out) = round_trip(&bytes);
+    assert_eq!(out, bytes);
+}
+
+#[test]
+fn dnssec_names_stay_uncompressed() {
+    let mut pack = DnsPacket::new();
+    pack.answers.push(record("alfa.example.com", RDataType::NSEC(Some(NsecData {
+        next_domain: "host.example.com".to_owned(),
+        types: vec![1234, 46, 1, 15, 47],
+    }))));
+    pack.answers.push(record("host.example.com", RDataType::RRSIG(Some(RrsigData {
+        type_covered: 1,
+        algorithm: 13,
+        labels: 3,
+        original_ttl: 300,
+        expiration: 1700000000,
+        inception: 1690000000,
+        key_tag: 2642,
+        signer_name: "example.com".to_owned(),
+        signature: vec![0xAB; 64],
+    }))));
+
+    let mut out = DnsBuffer::new();
+    pack.write(&mut out).unwrap();
+    let bytes = &out.buf[..out.pos];
+
+    // the NSEC rdata from RFC 4034 4.3, names spelled out and the bitmap in two windows
+    let mut nsec = b"\x04host\x07example\x03com\x00\x00\x06\x40\x01\x00\x00\x00\x03\x04\x1b".to_vec();
+    nsec.extend_from_slice(&[0; 26]);
+    nsec.push(0x20);
+    assert!(bytes.windows(nsec.len()).any(|w| w == nsec));
+    // the signer is written out even though example.com is already in the packet
+    assert!(bytes.windows(15).any(|w| w == b"\x0a\x52\x07example\x03com\x00"));
+
+    let (again, again_bytes) = round_trip(bytes);
+    assert_eq!(again_bytes, bytes);
+    assert!(matches!(&again.answers[0].rtype, RDataType::NSEC(Some(nsec)) if nsec.types == vec![1, 15, 46, 47, 1234]));
+    assert_eq!(again.answers[1].rtype, pack.answers[1].rtype);
+}
+
+#[test]
+fn edns_opt_record() {
+    let bytes = read_mock("trace_q");
+    let pack = DnsPacket::from_buf(&mut DnsBuffer::from_bytes(&bytes)).unwrap();
+
+    let edns = pack.edns().unwrap();
+    assert_eq!(edns.udp_payload_size, 1232);
+    assert_eq!(edns.version, 0);
+    assert!(edns.dnssec_ok);
+    assert!(matches!(&edns.options[..], [EdnsOption::Cookie(c)] if c.len() == 8));
+    assert_eq!(pack.max_response_size(), 1232);
+
+    let mut reply = DnsPacket::new();
+    let mut ours = Edns::new(4096);
+    ours.extended_rcode = 1;
+    ours.options.push(EdnsOption::ClientSubnet { family: 1, source_prefix: 24, scope_prefix: 0, address: vec![10, 0, 0] });
+    reply.set_edns(Some(ours.clone()));
+    reply.set_edns(Some(ours.clone()));
+    assert_eq!(reply.resources.len(), 1);
+
+    let mut out = DnsBuffer::new();
+    reply.write(&mut out).unwrap();
+    let (again, _) = round_trip(&out.buf[..out.pos]);
+    assert_eq!(again.edns(), Some(ours));
+}
+
+#[test]
+fn truncation_keeps_question_and_opt() {
+    let mut pack = DnsPacket::new();
+    pack.questions.push(DnsRecord {
+        domain: Domain::Domain("big.example.com".to_owned()),
+        rtype: RDataType::TXT(None),
+        rclass: RClass::IN,
+        ttl: None,
+        data_len: None,
+    });
+    for _ in 0..10 {
+        pack.answers.push(record("big.example.com", RDataType::TXT(Some(vec![b'x'; 100]))));
+    }
+    pack.set_edns(Some(Edns::new(512)));
+
+    let mut out = DnsBuffer::new();
+    pack.write_truncated(&mut out).unwrap();
+    let (again, _) = round_trip(&out.buf[..out.pos]);
+    assert!(again.header.truncated_message);
+    assert_eq!(again.questions.len(), 1);
+    assert!(again.answers.is_empty());
+    assert!(again.edns().is_some());
+
+    let mut big = DnsBuffer::new();
+    big.set_max_size(4096);
+    pack.write_truncated(&mut big).unwrap();
+    assert!(big.pos > 512);
+    let (again, _) = round_trip(&big.buf[..big.pos]);
+    assert!(!again.header.truncated_message);
+    assert_eq!(again.answers.len(), 10);
+}
+
+#[test]
+fn write_counts_the_sections_and_compresses_across_them() {
+    let mut pack = DnsPacket::new();
+    pack.header.id = 0x0102;
+    // stale counts, what goes out is what the vecs hold
+    pack.header.questions = 7;
+    pack.header.answers = 9;
+    pack.questions.push(question("www.example.com", RDataType::A(None)));
+    pack.answers.push(record("www.example.com", RDataType::A(Some(Ipv4Addr::new(10, 0, 0, 1)))));
+    pack.authorities.push(record("EXAMPLE.com", RDataType::NS(Some("ns1.Example.COM".to_owned()))));
+    pack.resources.push(record("ns1.example.com", RDataType::A(Some(Ipv4Addr::new(10, 0, 0, 53)))));
+
+    let mut out = DnsBuffer::new();
+    pack.write(&mut out).unwrap();
+    let bytes = &out.buf[..out.pos];
+
+    assert_eq!(&bytes[..2], &[0x01, 0x02]);
+    assert_eq!(&bytes[4..12], &[0, 1, 0, 1, 0, 1, 0, 1]);
+    // the question has the only full name, at 12 and example.com at 16
+    assert_eq!(&bytes[12..29], b"\x03www\x07example\x03com\x00");
+    // answer owner is all pointer
+    assert_eq!(&bytes[33..35], &[0xC0, 12]);
+    // the authority owner and the end of its rdata go back to example.com, whatever the case
+    assert_eq!(&bytes[49..51], &[0xC0, 16]);
+    assert_eq!(&bytes[59..61], &[0, 6]);
+    assert_eq!(&bytes[61..67], b"\x03ns1\xC0\x10");
+    // and the glue owner to the nameserver name inside that rdata
+    assert_eq!(&bytes[67..69], &[0xC0, 61]);
+    assert_eq!(bytes.len(), 69 + 14);
+
+    let (again, _) = round_trip(bytes);
+    assert_eq!(again.header.questions, 1);
+    assert_eq!(again.header.answers, 1);
+    assert_eq!(again.authorities[0].domain.name(), Some("example.com"));
+    assert_eq!(again.authorities[0].rtype, RDataType::NS(Some("ns1.example.com".to_owned())));
+    assert_eq!(again.resources[0].domain.name(), Some("ns1.example.com"));
+}
+
+// a response that comes out at exactly `len` bytes, with an OPT for `payload` if there is one
+fn sized(len: usize, payload: Option<u16>) -> DnsPacket {
+    let build = |filler: usize| {
+        let mut pack = DnsPacket::new();
+        pack.header.response = true;
+        pack.questions.push(question("big.example.com", RDataType::TXT(None)));
+        pack.answers.push(record("big.example.com", RDataType::TXT(Some(vec![b'x'; filler]))));
+        pack.set_edns(payload.map(Edns::new));
+        pack
+    };
+    let mut out = DnsBuffer::from_bytes(&[]);
+    build(0).write(&mut out).unwrap();
+    build(len - out.pos)
+}
+
+#[test]
+fn truncation_starts_one_byte_past_the_limit() {
+    for (payload, limit) in [(None, 512), (Some(512), 512), (Some(1232), 1232)] {
+        let query_size = payload.map_or(512, |p| p as usize);
+        for (len, truncated) in [(limit, false), (limit + 1, true)] {
+            let pack = sized(len, payload);
+            let mut out = DnsBuffer::with_size(query_size);
+            pack.write_truncated(&mut out).unwrap();
+            assert!(out.pos <= limit);
+
+            let (again, _) = round_trip(&out.buf[..out.pos]);
+            assert_eq!(again.header.truncated_message, truncated, "{} bytes with {:?}", len, payload);
+            assert_eq!(again.answers.is_empty(), truncated);
+            assert_eq!(again.questions.len(), 1);
+            assert_eq!(again.edns().map(|e| e.udp_payload_size), payload);
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use deez_ns::cache::Cache;
+use deez_ns::header::ResultCode;
+use deez_ns::packet::DnsPacket;
+use deez_ns::record::{DnsRecord, RDataType, SoaData};
+use deez_ns::resolver::{self, Resolver};
+
+mod common;
+use common::{a, fake_server, question, record};
+
+fn ns(zone: &str, ns: &str) -> DnsRecord {
+    record(zone, RDataType::NS(Some(ns.to_owned())))
+}
+
+/// Binds the same free port on 127.0.0.1 to 127.0.0.4, the resolver only knows one port
+fn sockets() -> (u16, Vec<UdpSocket>) {
+    let first = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let port = first.local_addr().unwrap().port();
+    let mut socks = vec![first];
+    for i in 2..=4 {
+        socks.push(UdpSocket::bind(SocketAddr::from(([127, 0, 0, i], port))).unwrap());
+    }
+    (port, socks)
+}
+
+#[test]
+fn follows_referrals_from_the_root() {
+    let (port, mut socks) = sockets();
+    let lame = socks.pop().unwrap();
+    let example = socks.pop().unwrap();
+    let tld = socks.pop().unwrap();
+    let root = socks.pop().unwrap();
+
+    // refuses everything, like a server that was never told about the zone
+    fake_server(lame, |_, res| res.header.rescode = ResultCode::REFUSED);
+
+    fake_server(root, |_, res| {
+        res.authorities.push(ns("test", "ns1.test"));
+        res.resources.push(a("ns1.test", [127, 0, 0, 2]));
+    });
+
+    // example.test is delegated to a nameserver with no glue, its address comes from here too
+    fake_server(tld, |qname, res| {
+        if qname == "ns.other.test" {
+            res.header.authoritative_answer = true;
+            res.answers.push(a("ns.other.test", [127, 0, 0, 3]));
+        } else {
+            res.authorities.push(ns("example.test", "ns.other.test"));
+        }
+    });
+
+    fake_server(example, |qname, res| {
+        res.header.authoritative_answer = true;
+        if qname == "www.example.test" {
+            res.answers.push(a("www.example.test", [10, 9, 9, 9]));
+        } else {
+            res.header.rescode = ResultCode::NXDOMAIN;
+            res.authorities.push(record("example.test", RDataType::SOA(Some(SoaData {
+                mname: "ns.other.test".to_owned(),
+                rname: "admin.example.test".to_owned(),
+                serial: 1,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum: 60,
+            }))));
+        }
+    });
+
+    let mut resolver = Resolver::new(
+        vec![SocketAddr::from(([127, 0, 0, 4], port)), SocketAddr::from(([127, 0, 0, 1], port))],
+        Duration::from_secs(2),
+        );
+    resolver.port = port;
+
+    let res = resolver.resolve(&question("www.example.test", RDataType::A(None))).unwrap();
+    assert!(matches!(res.answers[0].rtype, RDataType::A(Some(ip)) if ip == Ipv4Addr::new(10, 9, 9, 9)));
+
+    let res = resolver.resolve(&question("nope.example.test", RDataType::A(None))).unwrap();
+    assert_eq!(res.header.rescode, ResultCode::NXDOMAIN);
+    assert!(matches!(res.authorities[0].rtype, RDataType::SOA(_)));
+}
+
+#[test]
+fn upward_referrals_are_lame() {
+    let (port, mut socks) = sockets();
+    let root = socks.remove(0);
+    // keeps pointing back at the root instead of getting closer
+    fake_server(root, |_, res| {
+        res.authorities.push(ns("", "a.root-servers.test"));
+        res.resources.push(a("a.root-servers.test", [127, 0, 0, 1]));
+    });
+
+    let mut resolver = Resolver::new(vec![SocketAddr::from(([127, 0, 0, 1], port))], Duration::from_millis(500));
+    resolver.port = port;
+    assert!(resolver.resolve(&question("www.example.test", RDataType::A(None))).is_err());
+}
+
+#[test]
+fn delegations_are_remembered() {
+    let (port, mut socks) = sockets();
+    socks.pop();
+    let example = socks.pop().unwrap();
+    let tld = socks.pop().unwrap();
+    let root = socks.pop().unwrap();
+    let asked = Arc::new([AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)]);
+
+    let count = asked.clone();
+    fake_server(root, move |_, res| {
+        count[0].fetch_add(1, Ordering::SeqCst);
+        res.authorities.push(ns("test", "ns1.test"));
+        res.resources.push(a("ns1.test", [127, 0, 0, 2]));
+    });
+    // example.test. is only good for a second
+    let count = asked.clone();
+    fake_server(tld, move |_, res| {
+        count[1].fetch_add(1, Ordering::SeqCst);
+        res.authorities.push(DnsRecord { ttl: Some(1), ..ns("example.test", "ns.example.test") });
+        res.resources.push(a("ns.example.test", [127, 0, 0, 3]));
+    });
+    let count = asked.clone();
+    fake_server(example, move |qname, res| {
+        count[2].fetch_add(1, Ordering::SeqCst);
+        res.header.authoritative_answer = true;
+        res.answers.push(a(qname, [10, 9, 9, 9]));
+    });
+    let asked = move || asked.iter().map(|c| c.load(Ordering::SeqCst)).collect::<Vec<_>>();
+
+    let mut resolver = Resolver::new(vec![SocketAddr::from(([127, 0, 0, 1], port))], Duration::from_secs(2));
+    resolver.port = port;
+    resolver.resolve(&question("www.example.test", RDataType::A(None))).unwrap();
+    assert_eq!(asked(), vec![1, 1, 1]);
+    assert_eq!(resolver.delegations(), 2);
+
+    // straight to the zone's own server
+    resolver.resolve(&question("mail.example.test", RDataType::A(None))).unwrap();
+    assert_eq!(asked(), vec![1, 1, 2]);
+
+    // once example.test. is gone, test. is the closest
+    thread::sleep(Duration::from_millis(1100));
+    resolver.resolve(&question("ftp.example.test", RDataType::A(None))).unwrap();
+    assert_eq!(asked(), vec![1, 2, 3]);
+}
+
+#[test]
+fn out_of_zone_answers_are_dropped() {
+    let (port, mut socks) = sockets();
+    socks.truncate(2);
+    let tld = socks.pop().unwrap();
+    let root = socks.pop().unwrap();
+
+    fake_server(root, |_, res| {
+        res.authorities.push(ns("test", "ns1.test"));
+        res.resources.push(a("ns1.test", [127, 0, 0, 2]));
+    });
+    // only has a say about test., but answers for other names too
+    fake_server(tld, |qname, res| {
+        res.header.authoritative_answer = true;
+        res.answers.push(a(qname, [10, 9, 9, 9]));
+        res.answers.push(a("www.bank.example", [6, 6, 6, 6]));
+        res.authorities.push(ns("bank.example", "ns.evil.test"));
+        res.resources.push(a("ns.evil.test", [6, 6, 6, 7]));
+    });
+
+    let mut resolver = Resolver::new(vec![SocketAddr::from(([127, 0, 0, 1], port))], Duration::from_secs(2));
+    resolver.port = port;
+    let res = resolver.resolve(&question("www.example.test", RDataType::A(None))).unwrap();
+    assert_eq!(res.answers.len(), 1);
+    assert!(res.authorities.is_empty());
+    // that one is in test. and gets to stay
+    assert_eq!(res.resources.iter().filter(|r| r.domain.name() == Some("ns.evil.test")).count(), 1);
+
+    // a forwarder's upstream isnt held to a zone, there the cache leaves out what wasnt asked
+    let cache = Cache::new(1 << 20);
+    let mut pack = DnsPacket::new();
+    pack.questions.push(question("www.example.test", RDataType::A(None)));
+    pack.answers = res.answers;
+    pack.answers.push(a("www.bank.example", [6, 6, 6, 6]));
+    cache.insert_answers(&pack);
+    assert!(cache.lookup("www.example.test", 1, 1).is_some());
+    assert!(cache.lookup("www.bank.example", 1, 1).is_none());
+}
+
+#[test]
+fn zone_membership() {
+    assert!(resolver::in_zone("www.example.com", ""));
+    assert!(resolver::in_zone("www.example.com", "example.com"));
+    assert!(resolver::in_zone("Example.COM", "example.com"));
+    assert!(!resolver::in_zone("badexample.com", "example.com"));
+    assert!(!resolver::in_zone("com", "example.com"));
+}
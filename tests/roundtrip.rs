@@ -0,0 +1,220 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+use proptest::prelude::*;
+use deez_ns::buffer::DnsBuffer;
+use deez_ns::edns::EdnsOption;
+use deez_ns::encoding;
+use deez_ns::header::{DnsHeader, ResultCode};
+use deez_ns::packet::DnsPacket;
+use deez_ns::record::{DnsRecord, Domain, MxData, RClass, RDataType, SoaData, SrvData,
+    DnskeyData, DsData, RrsigData, NsecData, Nsec3Data, Nsec3ParamData};
+
+// lowercase, since that is how names come out of the parser
+fn name() -> impl Strategy<Value = String> {
+    prop::collection::vec("[a-z0-9-]{1,63}", 0..6)
+        .prop_map(|labels| labels.join("."))
+        .prop_filter("name too long", |n| n.len() <= 253)
+}
+
+fn class() -> impl Strategy<Value = RClass> {
+    prop_oneof![
+        Just(RClass::IN),
+        (2u16..).prop_map(RClass::SOMETHING),
+        Just(RClass::SOMETHING(0)),
+    ]
+}
+
+fn rescode() -> impl Strategy<Value = ResultCode> {
+    prop_oneof![
+        Just(ResultCode::NOERROR),
+        Just(ResultCode::FORMERR),
+        Just(ResultCode::SERVFAIL),
+        Just(ResultCode::NXDOMAIN),
+        Just(ResultCode::NOTIMP),
+        Just(ResultCode::REFUSED),
+    ]
+}
+
+fn header() -> impl Strategy<Value = DnsHeader> {
+    (any::<u16>(), any::<[bool; 8]>(), 0u8..16, rescode()).prop_map(|(id, flags, opcode, rescode)| DnsHeader {
+        id,
+        recursion_desired: flags[0],
+        truncated_message: flags[1],
+        authoritative_answer: flags[2],
+        opcode,
+        response: flags[3],
+        rescode,
+        checking_disabled: flags[4],
+        authed_data: flags[5],
+        z: flags[6],
+        recursion_available: flags[7],
+        ..DnsHeader::new()
+    })
+}
+
+fn option() -> impl Strategy<Value = EdnsOption> {
+    let bytes = || prop::collection::vec(any::<u8>(), 0..40);
+    prop_oneof![
+        bytes().prop_map(EdnsOption::Nsid),
+        (any::<u16>(), any::<u8>(), any::<u8>(), bytes()).prop_map(|(family, source_prefix, scope_prefix, address)| {
+            EdnsOption::ClientSubnet { family, source_prefix, scope_prefix, address }
+        }),
+        bytes().prop_map(EdnsOption::Cookie),
+        (0u16..64).prop_map(EdnsOption::Padding),
+        (any::<u16>().prop_filter("known option", |c| ![3, 8, 10, 12].contains(c)), bytes())
+            .prop_map(|(code, data)| EdnsOption::Unknown(code, data)),
+    ]
+}
+
+fn bytes(max: usize) -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec(any::<u8>(), 0..max)
+}
+
+// sorted without duplicates, the way they come out of a bitmap
+fn types() -> impl Strategy<Value = Vec<u16>> {
+    prop::collection::btree_set(any::<u16>(), 0..20).prop_map(|t| t.into_iter().collect())
+}
+
+fn dnssec_rdata() -> impl Strategy<Value = RDataType> {
+    prop_oneof![
+        (any::<(u16, u8, u8)>(), bytes(300)).prop_map(|((flags, protocol, algorithm), public_key)| {
+            RDataType::DNSKEY(Some(DnskeyData { flags, protocol, algorithm, public_key }))
+        }),
+        (any::<(u16, u8, u8)>(), bytes(64)).prop_map(|((key_tag, algorithm, digest_type), digest)| {
+            RDataType::DS(Some(DsData { key_tag, algorithm, digest_type, digest }))
+        }),
+        (any::<(u16, u8, u8, u32, u32, u32, u16)>(), name(), bytes(300)).prop_map(|(n, signer_name, signature)| {
+            RDataType::RRSIG(Some(RrsigData {
+                type_covered: n.0,
+                algorithm: n.1,
+                labels: n.2,
+                original_ttl: n.3,
+                expiration: n.4,
+                inception: n.5,
+                key_tag: n.6,
+                signer_name,
+                signature,
+            }))
+        }),
+        (name(), types()).prop_map(|(next_domain, types)| RDataType::NSEC(Some(NsecData { next_domain, types }))),
+        (any::<(u8, u8, u16)>(), bytes(255), bytes(255), types()).prop_map(|(n, salt, next_hashed, types)| {
+            RDataType::NSEC3(Some(Nsec3Data { hash_algorithm: n.0, flags: n.1, iterations: n.2, salt, next_hashed, types }))
+        }),
+        (any::<(u8, u8, u16)>(), bytes(255)).prop_map(|(n, salt)| {
+            RDataType::NSEC3PARAM(Some(Nsec3ParamData { hash_algorithm: n.0, flags: n.1, iterations: n.2, salt }))
+        }),
+    ]
+}
+
+fn rdata() -> impl Strategy<Value = RDataType> {
+    prop_oneof![
+        dnssec_rdata(),
+        any::<u32>().prop_map(|ip| RDataType::A(Some(Ipv4Addr::from(ip)))),
+        any::<u128>().prop_map(|ip| RDataType::AAAA(Some(Ipv6Addr::from(ip)))),
+        name().prop_map(|n| RDataType::NS(Some(n))),
+        name().prop_map(|n| RDataType::CNAME(Some(n))),
+        name().prop_map(|n| RDataType::PTR(Some(n))),
+        (name(), name(), any::<[u32; 5]>()).prop_map(|(mname, rname, n)| RDataType::SOA(Some(SoaData {
+            mname,
+            rname,
+            serial: n[0],
+            refresh: n[1],
+            retry: n[2],
+            expire: n[3],
+            minimum: n[4],
+        }))),
+        (any::<u16>(), name()).prop_map(|(priority, exchange)| RDataType::MX(Some(MxData { priority, exchange }))),
+        (any::<[u16; 3]>(), name()).prop_map(|(n, target)| RDataType::SRV(Some(SrvData {
+            priority: n[0],
+            weight: n[1],
+            port: n[2],
+            target,
+        }))),
+        bytes(300).prop_map(|t| RDataType::TXT(Some(t))),
+        prop::collection::vec(option(), 0..4).prop_map(|o| RDataType::OPT(Some(o))),
+        (any::<u16>(), prop::collection::vec(any::<u8>(), 0..100))
+            .prop_filter("known type", |(t, _)| matches!(RDataType::from_num(*t), RDataType::UNKNOWN(..)))
+            .prop_map(|(t, data)| RDataType::UNKNOWN(t, Some(data))),
+    ]
+}
+
+fn question() -> impl Strategy<Value = DnsRecord> {
+    (name(), any::<u16>(), class()).prop_map(|(n, rtype, rclass)| DnsRecord {
+        domain: Domain::Domain(n),
+        rtype: RDataType::from_num(rtype),
+        rclass,
+        ttl: None,
+        data_len: None,
+    })
+}
+
+fn record() -> impl Strategy<Value = DnsRecord> {
+    (name(), rdata(), class(), any::<u32>()).prop_map(|(n, rtype, rclass, ttl)| DnsRecord {
+        domain: Domain::Domain(n),
+        rtype,
+        rclass,
+        ttl: Some(ttl),
+        data_len: None,
+    })
+}
+
+fn packet() -> impl Strategy<Value = DnsPacket> {
+    let records = || prop::collection::vec(record(), 0..6);
+    (header(), prop::collection::vec(question(), 0..3), records(), records(), records())
+        .prop_map(|(header, questions, answers, authorities, resources)| DnsPacket {
+            header,
+            questions,
+            answers,
+            authorities,
+            resources,
+            ..DnsPacket::new()
+        })
+}
+
+// what the parser fills in on its own, the counts and rdata lengths
+fn normalized(mut pack: DnsPacket) -> (DnsHeader, Vec<Vec<DnsRecord>>) {
+    pack.header.questions = pack.questions.len() as u16;
+    pack.header.answers = pack.answers.len() as u16;
+    pack.header.authoritative_entries = pack.authorities.len() as u16;
+    pack.header.resource_entries = pack.resources.len() as u16;
+    let sections = [pack.questions, pack.answers, pack.authorities, pack.resources]
+        .into_iter()
+        .map(|s| s.into_iter().map(|r| DnsRecord { data_len: None, ..r }).collect())
+        .collect();
+    (pack.header, sections)
+}
+
+proptest! {
+    #[test]
+    fn written_packets_parse_back_the_same(pack in packet()) {
+        let mut buf = DnsBuffer::from_bytes(&[]);
+        pack.write(&mut buf).unwrap();
+        buf.buf.truncate(buf.pos);
+        buf.pos = 0;
+
+        let parsed = DnsPacket::from_buf(&mut buf).unwrap();
+        prop_assert_eq!(buf.pos, buf.buf.len());
+        prop_assert_eq!(normalized(parsed), normalized(pack));
+    }
+
+    #[test]
+    fn encodings_round_trip(data in bytes(100), secs in any::<u32>()) {
+        prop_assert_eq!(encoding::base64_decode(&encoding::base64_encode(&data)), Some(data.clone()));
+        prop_assert_eq!(encoding::base64url_decode(&encoding::base64url_encode(&data)), Some(data.clone()));
+        prop_assert_eq!(encoding::base32hex_decode(&encoding::base32hex_encode(&data)), Some(data.clone()));
+        prop_assert_eq!(encoding::hex_decode(&encoding::hex_encode(&data)), Some(data));
+        prop_assert_eq!(encoding::timestamp_decode(&encoding::timestamp_encode(secs)), Some(secs));
+    }
+
+    #[test]
+    fn random_bytes_never_panic(bytes in prop::collection::vec(any::<u8>(), 0..600)) {
+        if let Ok(pack) = DnsPacket::from_buf(&mut DnsBuffer::from_bytes(&bytes)) {
+            // whatever parsed has to be writable and parse again
+            let mut buf = DnsBuffer::from_bytes(&[]);
+            if pack.write(&mut buf).is_ok() {
+                buf.buf.truncate(buf.pos);
+                buf.pos = 0;
+                prop_assert!(DnsPacket::from_buf(&mut buf).is_ok());
+            }
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use std::net::{Ipv4Addr, SocketAddr, TcpStream, UdpSocket};
+use std::sync::Arc;
+use std::io::Write;
+use std::thread;
+use std::time::{Duration, Instant};
+use deez_ns::buffer::DnsBuffer;
+use deez_ns::config::ServerConfig;
+use deez_ns::header::ResultCode;
+use deez_ns::packet::DnsPacket;
+use deez_ns::record::{DnsRecord, RDataType};
+use deez_ns::server::{Handler, Server};
+use deez_ns::tcp;
+
+mod common;
+use common::a;
+
+fn reply(query: &DnsPacket, ip: Ipv4Addr) -> Vec<u8> {
+    let mut pack = DnsPacket::new();
+    pack.header = query.header.clone();
+    pack.header.response = true;
+    pack.questions = query.questions.clone();
+    pack.answers.push(DnsRecord {
+        rtype: RDataType::A(Some(ip)),
+        ttl: Some(60),
+        ..query.questions[0].clone()
+    });
+    let mut out = DnsBuffer::new();
+    pack.write(&mut out).unwrap();
+    out.buf[..out.pos].to_vec()
+}
+
+/// Upstream stub answering every A query with `ip`
+fn stub_upstream(ip: Ipv4Addr) -> SocketAddr {
+    common::stub_upstream(move |qname, res| res.answers.push(DnsRecord { ttl: Some(60), ..a(qname, ip.octets()) }))
+}
+
+fn query(domain: &str) -> Vec<u8> {
+    let mut buf = DnsBuffer::new();
+    common::query(0xBEEF, domain).write(&mut buf).unwrap();
+    buf.buf[..buf.pos].to_vec()
+}
+
+fn start(config: ServerConfig) -> SocketAddr {
+    let server = Arc::new(Server::new(config).unwrap());
+    let addr = server.local_addrs().unwrap()[0];
+    let handler: Arc<Handler> = {
+        let server = server.clone();
+        Arc::new(move |pack, r_buf| server.resolve(&pack)?.write_truncated(r_buf))
+    };
+    server.serve_udp(handler).unwrap();
+    addr
+}
+
+fn ask(server: SocketAddr, domain: &str) -> DnsPacket {
+    let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sock.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+    sock.send_to(&query(domain), server).unwrap();
+    let mut buf = DnsBuffer::with_size(4096);
+    let (len, _) = sock.recv_from(&mut buf.buf).unwrap();
+    buf.buf.truncate(len);
+    DnsPacket::from_buf(&mut buf).unwrap()
+}
+
+#[test]
+fn forwards_to_configured_upstream() {
+    let upstream = stub_upstream(Ipv4Addr::new(10, 1, 2, 3));
+    let server = start(ServerConfig {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        upstreams: vec![upstream],
+        ..ServerConfig::default()
+    });
+
+    let res = ask(server, "stub.example.com");
+    assert_eq!(res.header.id, 0xBEEF);
+    assert!(matches!(res.answers[0].rtype, RDataType::A(Some(ip)) if ip == Ipv4Addr::new(10, 1, 2, 3)));
+}
+
+#[test]
+fn falls_over_to_next_upstream() {
+    // bound but never answers
+    let dead = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let upstream = stub_upstream(Ipv4Addr::new(10, 4, 5, 6));
+    let server = start(ServerConfig {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        upstreams: vec![dead.local_addr().unwrap(), upstream],
+        upstream_timeout_ms: 200,
+        ..ServerConfig::default()
+    });
+
+    let res = ask(server, "stub.example.com");
+    assert!(matches!(res.answers[0].rtype, RDataType::A(Some(ip)) if ip == Ipv4Addr::new(10, 4, 5, 6)));
+}
+
+#[test]
+fn bind_failure_is_an_error() {
+    let taken = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let res = Server::new(ServerConfig {
+        listen: vec![taken.local_addr().unwrap()],
+        ..ServerConfig::default()
+    });
+    assert!(res.is_err());
+}
+
+#[test]
+fn v4_and_v6_on_the_same_port() {
+    let upstream = stub_upstream(Ipv4Addr::new(10, 9, 9, 9));
+    // a port that was free a moment ago
+    let port = UdpSocket::bind("0.0.0.0:0").unwrap().local_addr().unwrap().port();
+    let v4 = start(ServerConfig {
+        listen: vec![SocketAddr::from(([0, 0, 0, 0], port)), format!("[::]:{}", port).parse().unwrap()],
+        upstreams: vec![upstream],
+        ..ServerConfig::default()
+    });
+    assert!(ask(SocketAddr::from(([127, 0, 0, 1], v4.port())), "v4.example.com").header.response);
+
+    let sock = UdpSocket::bind("[::1]:0").unwrap();
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    sock.send_to(&query("v6.example.com"), format!("[::1]:{}", port)).unwrap();
+    let mut buf = DnsBuffer::with_size(4096);
+    let len = sock.recv(&mut buf.buf).unwrap();
+    buf.buf.truncate(len);
+    assert!(matches!(DnsPacket::from_buf(&mut buf).unwrap().answers[0].rtype, RDataType::A(Some(ip)) if ip == Ipv4Addr::new(10, 9, 9, 9)));
+}
+
+#[test]
+fn only_matching_replies_count() {
+    // sends a reply with the wrong id and one for another question before the real one
+    let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let upstream = sock.local_addr().unwrap();
+    thread::spawn(move || loop {
+        let mut buf = DnsBuffer::with_size(4096);
+        let (len, from) = sock.recv_from(&mut buf.buf).unwrap();
+        buf.buf.truncate(len);
+        let query = DnsPacket::from_buf(&mut buf).unwrap();
+
+        let mut wrong_id = DnsPacket::from_buf(&mut DnsBuffer::from_bytes(&reply(&query, Ipv4Addr::new(6, 6, 6, 6)))).unwrap();
+        wrong_id.header.id = query.header.id.wrapping_add(1);
+        let mut out = DnsBuffer::new();
+        wrong_id.write(&mut out).unwrap();
+        sock.send_to(&out.buf[..out.pos], from).unwrap();
+
+        let mut other = DnsPacket::from_buf(&mut DnsBuffer::from_bytes(&crate::query("evil.example.com"))).unwrap();
+        other.header.id = query.header.id;
+        sock.send_to(&reply(&other, Ipv4Addr::new(6, 6, 6, 6)), from).unwrap();
+
+        sock.send_to(&reply(&query, Ipv4Addr::new(10, 7, 7, 7)), from).unwrap();
+    });
+    let server = start(ServerConfig {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        upstreams: vec![upstream],
+        ..ServerConfig::default()
+    });
+
+    let res = ask(server, "stub.example.com");
+    assert_eq!(res.header.id, 0xBEEF);
+    assert_eq!(res.answers.len(), 1);
+    assert!(matches!(res.answers[0].rtype, RDataType::A(Some(ip)) if ip == Ipv4Addr::new(10, 7, 7, 7)));
+}
+
+#[test]
+fn slow_queries_dont_hold_up_the_rest() {
+    // answers everything, but takes its time for slow.example.com
+    let sock = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+    let upstream = sock.local_addr().unwrap();
+    thread::spawn(move || loop {
+        let mut buf = DnsBuffer::with_size(4096);
+        let (len, from) = sock.recv_from(&mut buf.buf).unwrap();
+        buf.buf.truncate(len);
+        let query = DnsPacket::from_buf(&mut buf).unwrap();
+        let sock = sock.clone();
+        thread::spawn(move || {
+            if query.questions[0].domain.name() == Some("slow.example.com") {
+                thread::sleep(Duration::from_millis(1500));
+            }
+            sock.send_to(&reply(&query, Ipv4Addr::new(10, 8, 8, 8)), from).unwrap();
+        });
+    });
+    let server = start(ServerConfig {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        upstreams: vec![upstream],
+        upstream_timeout_ms: 5000,
+        workers: 4,
+        ..ServerConfig::default()
+    });
+
+    let slow = thread::spawn(move || ask(server, "slow.example.com"));
+    thread::sleep(Duration::from_millis(100));
+    let started = Instant::now();
+    let res = ask(server, "fast.example.com");
+    assert!(started.elapsed() < Duration::from_millis(1000));
+    assert_eq!(res.answers.len(), 1);
+    assert_eq!(slow.join().unwrap().answers.len(), 1);
+}
+
+#[test]
+fn servfail_when_every_upstream_fails() {
+    let dead = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server = start(ServerConfig {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        upstreams: vec![dead.local_addr().unwrap()],
+        upstream_timeout_ms: 100,
+        upstream_retries: 1,
+        ..ServerConfig::default()
+    });
+
+    let res = ask(server, "stub.example.com");
+    assert_eq!(res.header.id, 0xBEEF);
+    assert!(res.header.response);
+    assert_eq!(res.header.rescode, ResultCode::SERVFAIL);
+    assert_eq!(res.questions.len(), 1);
+    assert!(res.answers.is_empty());
+}
+
+#[test]
+fn connections_past_the_limit_are_closed() {
+    let upstream = stub_upstream(Ipv4Addr::new(10, 5, 5, 5));
+    let server = Arc::new(Server::new(ServerConfig {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        upstreams: vec![upstream],
+        max_connections: 1,
+        ..ServerConfig::default()
+    }).unwrap());
+    let addr = server.local_addrs().unwrap()[0];
+    let handler: Arc<Handler> = {
+        let server = server.clone();
+        Arc::new(move |pack, r_buf| server.resolve(&pack)?.write_truncated(r_buf))
+    };
+    server.serve_tcp(handler).unwrap();
+
+    let pack = DnsPacket::from_buf(&mut DnsBuffer::from_bytes(&query("tcp.example.com"))).unwrap();
+    let connect = || {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream
+    };
+    let mut first = connect();
+    assert_eq!(tcp::query(&mut first, &pack).unwrap().answers.len(), 1);
+
+    // the only slot is taken, so the next one is closed without an answer
+    assert!(tcp::query(&mut connect(), &pack).is_err());
+
+    // until the first one goes away
+    drop(first);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(tcp::query(&mut connect(), &pack).unwrap().answers.len(), 1);
+}
+
+#[test]
+fn clients_that_dont_read_lose_their_slot() {
+    let server = Arc::new(Server::new(ServerConfig {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        tcp_idle_timeout_ms: 200,
+        max_connections: 1,
+        ..ServerConfig::default()
+    }).unwrap());
+    let addr = server.local_addrs().unwrap()[0];
+    // big answers, so the socket buffers fill up quickly
+    let handler: Arc<Handler> = Arc::new(|mut pack: DnsPacket, r_buf: &mut DnsBuffer| {
+        pack.header.response = true;
+        pack.answers.push(DnsRecord {
+            rtype: RDataType::TXT(Some(vec![b'x'; 60_000])),
+            ttl: Some(60),
+            ..pack.questions[0].clone()
+        });
+        pack.write(r_buf)
+    });
+    server.serve_tcp(handler).unwrap();
+
+    // sends a lot and never reads a thing
+    let mut greedy = TcpStream::connect(addr).unwrap();
+    let mut frames = Vec::new();
+    for _ in 0..1000 {
+        let bytes = query("big.example.com");
+        frames.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+        frames.extend_from_slice(&bytes);
+    }
+    greedy.write_all(&frames).unwrap();
+
+    // once the server gives up writing to it there is room for someone else
+    let pack = DnsPacket::from_buf(&mut DnsBuffer::from_bytes(&query("tcp.example.com"))).unwrap();
+    let started = Instant::now();
+    let answered = loop {
+        assert!(started.elapsed() < Duration::from_secs(10), "the slot never came free");
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        match tcp::query(&mut stream, &pack) {
+            Ok(res) => break res,
+            Err(_) => thread::sleep(Duration::from_millis(100)),
+        }
+    };
+    assert_eq!(answered.answers.len(), 1);
+    drop(greedy);
+}
@@ -0,0 +1,44 @@
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use deez_ns::buffer::DnsBuffer;
+use deez_ns::packet::DnsPacket;
+use deez_ns::tcp;
+
+mod common;
+use common::query;
+
+#[test]
+fn pipelined_queries_on_one_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        tcp::serve_connection(&mut stream, &|mut pack: DnsPacket, r_buf: &mut DnsBuffer| {
+            pack.header.response = true;
+            pack.write(r_buf)
+        }).unwrap();
+    });
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    // both queries go out before reading anything back
+    for (id, domain) in [(1, "a.example.com"), (2, "b.example.com")] {
+        let mut buf = DnsBuffer::from_bytes(&[]);
+        query(id, domain).write(&mut buf).unwrap();
+        tcp::write_frame(&mut stream, &buf).unwrap();
+    }
+    stream.flush().unwrap();
+
+    for (id, domain) in [(1, "a.example.com"), (2, "b.example.com")] {
+        let mut buf = tcp::read_frame(&mut stream).unwrap().unwrap();
+        let res = DnsPacket::from_buf(&mut buf).unwrap();
+        assert!(res.header.response);
+        assert_eq!(res.header.id, id);
+        assert_eq!(res.questions[0].domain.name(), Some(domain));
+    }
+
+    let res = tcp::query(&mut stream, &query(3, "c.example.com")).unwrap();
+    assert_eq!(res.header.id, 3);
+
+    drop(stream);
+}
@@ -0,0 +1,141 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+use deez_ns::buffer::DnsBuffer;
+use deez_ns::config::ServerConfig;
+use deez_ns::header::ResultCode;
+use deez_ns::packet::DnsPacket;
+use deez_ns::record::{DnsRecord, RDataType};
+use deez_ns::server::{Handler, Server};
+use deez_ns::tls::{self, TlsUpstream};
+
+mod common;
+use common::query;
+
+struct Pki {
+    dir: PathBuf,
+}
+
+impl Pki {
+    /// A CA and a certificate from it for dns.test, as PEM files in a temp dir
+    fn new(test: &str) -> Pki {
+        let dir = std::env::temp_dir().join(format!("deez_ns_tls_{}_{}", test, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["dns.test".to_owned()]).unwrap()
+            .signed_by(&key, &ca, &ca_key)
+            .unwrap();
+
+        std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), key.serialize_pem()).unwrap();
+        Pki { dir }
+    }
+
+    fn file(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+}
+
+impl Drop for Pki {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// A DoT server answering 192.0.2.53 for everything
+fn dot_server(pki: &Pki) -> (Arc<Server>, SocketAddr) {
+    let server = Arc::new(Server::new(ServerConfig {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        tls_listen: vec!["127.0.0.1:0".parse().unwrap()],
+        tls_cert: Some(pki.file("cert.pem")),
+        tls_key: Some(pki.file("key.pem")),
+        ..ServerConfig::default()
+    }).unwrap());
+    let handler: Arc<Handler> = Arc::new(|pack: DnsPacket, r_buf: &mut DnsBuffer| {
+        let mut res = pack.response_to(ResultCode::NOERROR);
+        res.answers.push(DnsRecord {
+            ttl: Some(60),
+            rtype: RDataType::A(Some(Ipv4Addr::new(192, 0, 2, 53))),
+            ..pack.questions[0].clone()
+        });
+        res.write(r_buf)
+    });
+    server.serve_tls(handler).unwrap();
+    let addr = server.tls_addrs().unwrap()[0];
+    // DoH only comes with an https_listen of its own
+    assert!(server.https_addrs().unwrap().is_empty());
+    (server, addr)
+}
+
+#[test]
+fn dot_listener_checks_out() {
+    let pki = Pki::new("listener");
+    let (_server, addr) = dot_server(&pki);
+    let config = tls::client_config(&pki.file("ca.pem"), tls::DOT_ALPN).unwrap();
+    let timeout = Duration::from_secs(2);
+
+    let upstream = TlsUpstream { addr, name: "dns.test".to_owned() };
+    let res = tls::exchange(&query(853, "www.example.com"), &upstream, &config, timeout).unwrap();
+    assert_eq!(res.header.id, 853);
+    assert!(matches!(res.answers[0].rtype, RDataType::A(Some(ip)) if ip == Ipv4Addr::new(192, 0, 2, 53)));
+
+    // a certificate for some other name doesnt do
+    let wrong_name = TlsUpstream { addr, name: "other.test".to_owned() };
+    assert!(tls::exchange(&query(853, "www.example.com"), &wrong_name, &config, timeout).is_err());
+
+    // neither does one from a CA nobody trusts
+    let stranger = Pki::new("stranger");
+    let config = tls::client_config(&stranger.file("ca.pem"), tls::DOT_ALPN).unwrap();
+    assert!(tls::exchange(&query(853, "www.example.com"), &upstream, &config, timeout).is_err());
+}
+
+#[test]
+fn forwarding_over_dot() {
+    let pki = Pki::new("forwarding");
+    let (_upstream, addr) = dot_server(&pki);
+
+    let server = Server::new(ServerConfig {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        upstreams: Vec::new(),
+        tls_upstreams: vec![format!("{}#dns.test", addr).parse().unwrap()],
+        tls_ca_file: pki.file("ca.pem"),
+        upstream_timeout_ms: 2000,
+        ..ServerConfig::default()
+    }).unwrap();
+    let res = server.resolve(&query(853, "www.example.com")).unwrap();
+    assert_eq!(res.header.rescode, ResultCode::NOERROR);
+    assert!(matches!(res.answers[0].rtype, RDataType::A(Some(ip)) if ip == Ipv4Addr::new(192, 0, 2, 53)));
+
+    // an upstream that isnt who it says it is gets nothing out of us
+    let server = Server::new(ServerConfig {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        upstreams: Vec::new(),
+        tls_upstreams: vec![format!("{}#impostor.test", addr).parse().unwrap()],
+        tls_ca_file: pki.file("ca.pem"),
+        upstream_timeout_ms: 500,
+        upstream_retries: 0,
+        ..ServerConfig::default()
+    }).unwrap();
+    assert_eq!(server.resolve(&query(853, "www.example.com")).unwrap().header.rescode, ResultCode::SERVFAIL);
+}
+
+#[test]
+fn tls_upstreams_parse() {
+    let upstream: TlsUpstream = "1.1.1.1#one.one.one.one".parse().unwrap();
+    assert_eq!(upstream.addr, "1.1.1.1:853".parse::<SocketAddr>().unwrap());
+    assert_eq!(upstream.name, "one.one.one.one");
+    let upstream: TlsUpstream = "[2606:4700::1111]:8853#one.one.one.one".parse().unwrap();
+    assert_eq!(upstream.addr, "[2606:4700::1111]:8853".parse::<SocketAddr>().unwrap());
+    assert!("1.1.1.1:853".parse::<TlsUpstream>().is_err());
+    assert!("not an address#name".parse::<TlsUpstream>().is_err());
+    assert!("1.1.1.1#".parse::<TlsUpstream>().is_err());
+}
@@ -0,0 +1,72 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+use deez_ns::header::ResultCode;
+use deez_ns::record::DnsRecord;
+use deez_ns::upstream::{self, Upstreams};
+
+mod common;
+use common::{a, query, stub_upstream};
+
+/// Answers every query with `rescode`, and an A record when that is NOERROR
+fn stub(rescode: ResultCode) -> SocketAddr {
+    stub_upstream(move |qname, res| {
+        res.header.rescode = rescode;
+        if rescode == ResultCode::NOERROR {
+            res.answers.push(DnsRecord { ttl: Some(60), ..a(qname, [10, 0, 0, 1]) });
+        }
+    })
+}
+
+#[test]
+fn failing_upstreams_get_pushed_back() {
+    // bound but never answers
+    let dead = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let dead_addr = dead.local_addr().unwrap();
+    let broken = stub(ResultCode::SERVFAIL);
+    let live = stub(ResultCode::NOERROR);
+    let upstreams = Upstreams::new(&[dead_addr, broken, live], Duration::from_millis(200), 0);
+
+    for _ in 0..3 {
+        let res = upstreams.exchange(&query(upstream::random_id(), "example.com")).unwrap();
+        assert_eq!(res.header.rescode, ResultCode::NOERROR);
+    }
+    assert_eq!(upstreams.order()[0], live);
+
+    let health = upstreams.health();
+    let live_health = health.iter().find(|h| h.addr == live).unwrap();
+    assert_eq!(live_health.failures, 0);
+    assert!(live_health.srtt < Duration::from_millis(100));
+    assert!(health.iter().filter(|h| h.addr != live).all(|h| h.failures >= 1 && h.srtt > live_health.srtt));
+
+    // now it goes straight to the live one
+    let started = Instant::now();
+    upstreams.exchange(&query(upstream::random_id(), "example.com")).unwrap();
+    assert!(started.elapsed() < Duration::from_millis(150));
+}
+
+#[test]
+fn upstreams_that_keep_failing_are_skipped() {
+    let dead = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let dead_addr = dead.local_addr().unwrap();
+    let upstreams = Upstreams::new(&[dead_addr], Duration::from_millis(50), upstream::FAILURES_TILL_DOWN as usize - 1);
+
+    // every round timed out, with a longer timeout each time
+    let started = Instant::now();
+    assert!(upstreams.exchange(&query(upstream::random_id(), "example.com")).is_err());
+    assert!(started.elapsed() >= Duration::from_millis(50 + 100 + 200));
+
+    let health = upstreams.health();
+    assert_eq!(health[0].failures, upstream::FAILURES_TILL_DOWN);
+    assert!(health[0].down_until.is_some_and(|t| t > Instant::now()));
+    // still asked when there is nobody else
+    assert_eq!(upstreams.order(), vec![dead_addr]);
+}
+
+#[test]
+fn ties_are_rotated() {
+    let a: SocketAddr = "127.0.0.1:1".parse().unwrap();
+    let b: SocketAddr = "127.0.0.1:2".parse().unwrap();
+    let upstreams = Upstreams::new(&[a, b], Duration::from_millis(50), 0);
+    let firsts: Vec<SocketAddr> = (0..4).map(|_| upstreams.order()[0]).collect();
+    assert!(firsts.contains(&a) && firsts.contains(&b));
+}
@@ -0,0 +1,143 @@
+use deez_ns::buffer::DnsBuffer;
+use deez_ns::error::ParseError;
+use deez_ns::packet::DnsPacket;
+use deez_ns::record::{DnsRecord, RDataType};
+use deez_ns::view::PacketView;
+
+mod common;
+use common::{a, record};
+
+// a response with something in every section, and names that compress against each other
+fn packet() -> Vec<u8> {
+    let mut pack = DnsPacket::new();
+    pack.header.id = 0x1234;
+    pack.header.response = true;
+    pack.questions.push(common::question("www.Example.com", RDataType::A(None)));
+    pack.answers.push(record("www.example.com", RDataType::CNAME(Some("web.example.com".to_owned()))));
+    pack.answers.push(DnsRecord { ttl: Some(60), ..a("web.example.com", [10, 0, 0, 1]) });
+    pack.authorities.push(DnsRecord { ttl: Some(3600), ..record("example.com", RDataType::NS(Some("ns1.example.com".to_owned()))) });
+    pack.resources.push(DnsRecord { ttl: Some(3600), ..a("ns1.example.com", [10, 0, 0, 53]) });
+
+    let mut buf = DnsBuffer::from_bytes(&[]);
+    pack.write(&mut buf).unwrap();
+    buf.buf.truncate(buf.pos);
+    buf.buf
+}
+
+// header with one question and nothing else, then `name` and a type and class
+fn question(name: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+    bytes.extend_from_slice(name);
+    bytes.extend_from_slice(&[0x00, 0x01, 0x00, 0x01]);
+    bytes
+}
+
+#[test]
+fn records_are_found_in_every_section() {
+    let bytes = packet();
+    let view = PacketView::new(&bytes).unwrap();
+    assert_eq!(view.header.id, 0x1234);
+
+    let questions: Vec<_> = view.questions().collect::<Result<_, _>>().unwrap();
+    assert_eq!(questions.len(), 1);
+    assert_eq!(questions[0].name.decode().unwrap(), "www.example.com");
+    assert_eq!(questions[0].qtype, 1);
+    assert_eq!(questions[0].name.offset(), 12);
+
+    let answers: Vec<_> = view.answers().collect::<Result<_, _>>().unwrap();
+    assert_eq!(answers.iter().map(|a| (a.rtype, a.ttl)).collect::<Vec<_>>(), vec![(5, 300), (1, 60)]);
+    assert_eq!(answers[1].rdata, &[10, 0, 0, 1]);
+
+    let authorities: Vec<_> = view.authorities().collect::<Result<_, _>>().unwrap();
+    assert_eq!(authorities.len(), 1);
+    assert!(authorities[0].name.eq_domain("example.com"));
+
+    let resources: Vec<_> = view.resources().collect::<Result<_, _>>().unwrap();
+    assert_eq!(resources.len(), 1);
+    assert_eq!(resources[0].name.decode().unwrap(), "ns1.example.com");
+
+    // and the owned parse agrees with the view
+    let parsed = DnsPacket::from_buf(&mut DnsBuffer::from_bytes(&bytes)).unwrap();
+    for (view, rec) in answers.iter().zip(parsed.answers.iter()) {
+        assert_eq!(&view.to_record().unwrap(), rec);
+    }
+}
+
+#[test]
+fn compressed_names_are_followed() {
+    let bytes = packet();
+    let view = PacketView::new(&bytes).unwrap();
+    let answers: Vec<_> = view.answers().collect::<Result<_, _>>().unwrap();
+
+    // the first answer is nothing but a pointer back to the question
+    assert_eq!(&bytes[answers[0].name.offset()..answers[0].name.offset() + 2], &[0xC0, 12]);
+    assert_eq!(answers[0].name.decode().unwrap(), "www.example.com");
+    assert!(answers[0].name.eq_domain("WWW.example.com."));
+    assert!(!answers[0].name.eq_domain("example.com"));
+    assert!(!answers[0].name.eq_domain("www.example.com.au"));
+
+    // labels come raw, the rest of the name is the question's as it was written
+    let labels: Vec<_> = answers[1].name.labels().collect::<Result<_, _>>().unwrap();
+    assert_eq!(labels, vec![&b"web"[..], b"Example", b"com"]);
+}
+
+// the view and get_domain should agree on every name, whether it reads or not
+fn decode_both(bytes: &[u8]) -> Result<String, ParseError> {
+    let view = PacketView::new(bytes).unwrap();
+    let viewed = view.questions().next().unwrap().and_then(|q| q.name.decode());
+
+    let mut buf = DnsBuffer::from_bytes(bytes);
+    buf.seek(12).unwrap();
+    assert_eq!(buf.get_domain(), viewed);
+    viewed
+}
+
+#[test]
+fn bad_pointers_fail() {
+    let cases: [(&str, &[u8], ParseError); 4] = [
+        // pointing at itself
+        ("self", &[0xC0, 12], ParseError::PointerLoop { offset: 12, jumps: 32 }),
+        // a label and then back to it, round and round
+        ("loop", &[1, b'a', 0xC0, 12], ParseError::PointerLoop { offset: 12, jumps: 32 }),
+        ("out of bounds", &[0xFF, 0xFF], ParseError::BadPointer { offset: 12, target: 0x3FFF }),
+        ("cut off", &[1, b'a', 0xC0], ParseError::EndOfBuffer { offset: 15 }),
+    ];
+    for (case, name, err) in cases {
+        let mut bytes = question(name);
+        if case == "cut off" {
+            bytes.truncate(12 + name.len());
+        }
+        assert_eq!(decode_both(&bytes).unwrap_err(), err, "{}", case);
+    }
+}
+
+#[test]
+fn forward_pointers_are_followed() {
+    // jumps over two zeros to a name further on, and then back again to the label in front
+    assert_eq!(decode_both(&question(&[0xC0, 16, 0, 0, 1, b'a', 0])).unwrap(), "a");
+    assert_eq!(decode_both(&question(&[1, b'b', 0xC0, 18, 0, 0, 1, b'a', 0])).unwrap(), "b.a");
+
+    // two pointers at each other never get anywhere
+    let err = decode_both(&question(&[0xC0, 14, 0xC0, 12])).unwrap_err();
+    assert_eq!(err, ParseError::PointerLoop { offset: 12, jumps: 32 });
+}
+
+#[test]
+fn names_stop_at_255_bytes() {
+    // 4 labels of 63 is 257 on the wire
+    let mut name = Vec::new();
+    for _ in 0..4 {
+        name.push(63);
+        name.extend_from_slice(&[b'a'; 63]);
+    }
+    name.push(0);
+    let bytes = question(&name);
+    let view = PacketView::new(&bytes).unwrap();
+    let question = view.questions().next().unwrap().unwrap();
+    assert_eq!(question.name.decode().unwrap_err(), ParseError::NameTooLong { offset: 12 });
+
+    // one label less is fine
+    let bytes = self::question(&name[64..]);
+    let view = PacketView::new(&bytes).unwrap();
+    assert_eq!(view.questions().next().unwrap().unwrap().name.decode().unwrap().len(), 3 * 63 + 2);
+}
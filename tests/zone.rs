@@ -0,0 +1,308 @@
+use std::net::Ipv4Addr;
+use deez_ns::buffer::DnsBuffer;
+use deez_ns::header::ResultCode;
+use deez_ns::packet::DnsPacket;
+use deez_ns::record::RDataType;
+use deez_ns::zone::Zone;
+
+mod common;
+use common::ask;
+
+const ZONE: &str = r#"
+$TTL 1h
+$ORIGIN internal.example.
+@       IN  SOA ns1 hostmaster (
+                2024010101 ; serial
+                2h         ; refresh
+                15m        ; retry
+                2w         ; expire
+                300 )      ; minimum
+        IN  NS  ns1
+        IN  MX  10 mail
+ns1         A   10.0.0.1
+mail    60  A   10.0.0.2
+            AAAA 2001:db8::2
+www     IN  CNAME web.internal.example.
+web         A   10.0.0.3
+outside     CNAME www.example.com.
+txt         TXT "hello world" "with \"quotes\"" ; and a comment
+_sip._tcp   SRV 10 5 5060 sip
+sip         A   10.0.0.4
+raw         TYPE65280 \# 3 abcdef
+*.apps      A   10.0.0.5
+deep.empty  A   10.0.0.6
+
+$ORIGIN sub.internal.example.
+@           NS  ns
+ns          A   10.0.1.1
+"#;
+
+fn zone() -> Zone {
+    Zone::parse(ZONE, "internal.example.").unwrap()
+}
+
+#[test]
+fn parses_master_files() {
+    let zone = zone();
+    assert_eq!(zone.origin, "internal.example");
+
+    match &zone.soa().rtype {
+        RDataType::SOA(Some(soa)) => {
+            assert_eq!(soa.mname, "ns1.internal.example");
+            assert_eq!(soa.rname, "hostmaster.internal.example");
+            assert_eq!((soa.serial, soa.refresh, soa.retry, soa.expire, soa.minimum), (2024010101, 7200, 900, 1209600, 300));
+        }
+        other => panic!("not a soa {:?}", other),
+    }
+    assert_eq!(zone.soa().ttl, Some(3600));
+
+    // blank owners reuse the previous one, and an explicit ttl only counts for its record
+    let mail = zone.records("mail.internal.example");
+    assert_eq!(mail.len(), 2);
+    assert_eq!(mail[0].ttl, Some(60));
+    assert_eq!(mail[1].ttl, Some(3600));
+
+    match &zone.records("_sip._tcp.internal.example")[0].rtype {
+        RDataType::SRV(Some(srv)) => assert_eq!((srv.port, srv.target.as_str()), (5060, "sip.internal.example")),
+        other => panic!("not a srv {:?}", other),
+    }
+    match &zone.records("txt.internal.example")[0].rtype {
+        RDataType::TXT(Some(txt)) => assert_eq!(txt, b"\x0bhello world\x0dwith \"quotes\""),
+        other => panic!("not a txt {:?}", other),
+    }
+    assert!(matches!(&zone.records("raw.internal.example")[0].rtype, RDataType::UNKNOWN(65280, Some(b)) if b == &[0xab, 0xcd, 0xef]));
+    assert_eq!(zone.records("ns.sub.internal.example").len(), 1);
+
+    // and everything written back out parses the same
+    let mut pack = ask(&zone, "internal.example", RDataType::UNKNOWN(255, None));
+    assert_eq!(pack.answers.len(), 3);
+    pack.answers.extend(zone.records("txt.internal.example").iter().cloned());
+    let mut buf = DnsBuffer::from_bytes(&[]);
+    pack.write(&mut buf).unwrap();
+    buf.pos = 0;
+    assert_eq!(DnsPacket::from_buf(&mut buf).unwrap().answers.len(), 4);
+}
+
+#[test]
+fn bad_zones_are_errors() {
+    assert!(Zone::parse("www 60 A 10.0.0.1", "example.com").is_err()); // no soa
+    let soa = "@ 60 SOA ns hostmaster 1 2 3 4 5\n";
+    assert!(Zone::parse(soa, "example.com").is_ok());
+    assert!(Zone::parse(&format!("{}www A 10.0.0.1", soa), "example.com").is_ok());
+    assert!(Zone::parse(&format!("{}www A 10.0.0.300", soa), "example.com").is_err());
+    assert!(Zone::parse(&format!("{}www A 10.0.0.1 (", soa), "example.com").is_err());
+    assert!(Zone::parse(&format!("{}www.other.com. A 10.0.0.1", soa), "example.com").is_err());
+    assert!(Zone::parse(&format!("{}www CNAME a\nwww A 10.0.0.1", soa), "example.com").is_err());
+    assert!(Zone::parse(&format!("{}www CNAME a\nwww CNAME b", soa), "example.com").is_err());
+    assert!(Zone::parse(&format!("{}www BOGUS 1", soa), "example.com").is_err());
+    assert!(Zone::parse("@ SOA ns hostmaster 1 2 3 4 5", "example.com").is_err()); // no ttl anywhere
+    assert!(Zone::parse(&format!("{}$INCLUDE other.zone", soa), "example.com").is_err());
+}
+
+#[test]
+fn escapes_outside_of_quotes() {
+    let zone = Zone::parse(r#"
+@           3600 SOA ns1 hostmaster 1 2h 15m 2w 300
+\@          3600 A 10.0.0.1
+\119ww      3600 CNAME \@
+semi\;colon 3600 TXT hello\032there \"hi\" a\;b
+"#, "example.com.").unwrap();
+
+    // an escaped @ is just a label called @, not the origin
+    assert!(matches!(zone.records("@.example.com")[0].rtype, RDataType::A(_)));
+    assert!(matches!(&zone.records("www.example.com")[0].rtype, RDataType::CNAME(Some(t)) if t == "@.example.com"));
+
+    let txt = match &zone.records("semi;colon.example.com")[0].rtype {
+        RDataType::TXT(Some(txt)) => txt.clone(),
+        other => panic!("not a txt {:?}", other),
+    };
+    assert_eq!(txt, b"\x0bhello there\x04\"hi\"\x03a;b");
+
+    // dots inside a label have nowhere to go, and broken escapes are broken
+    let soa = "@ 60 SOA ns hostmaster 1 2 3 4 5\n";
+    for bad in [r"a\046b A 10.0.0.1", r"www CNAME a\.b", r"www A\ 10.0.0.1", r"a\1 A 10.0.0.1", r"a\999 A 10.0.0.1", r"www TXT a\12"] {
+        assert!(Zone::parse(&format!("{}{}", soa, bad), "example.com").is_err(), "{}", bad);
+    }
+    assert!(Zone::parse(&format!("{}{}", soa, r"a\046b.example.com\. A 10.0.0.1"), "example.com").is_err());
+}
+
+#[test]
+fn includes_are_refused_with_the_line() {
+    let err = Zone::parse("@ 60 SOA ns hostmaster 1 2 3 4 5\n\n$INCLUDE other.zone sub\n", "example.com").unwrap_err();
+    let err = format!("{:#}", err);
+    assert!(err.contains("line 3"), "{}", err);
+    assert!(err.contains("$INCLUDE"), "{}", err);
+}
+
+#[test]
+fn exact_answers_and_cnames() {
+    let zone = zone();
+    let res = ask(&zone, "WEB.internal.example", RDataType::A(None));
+    assert!(res.header.authoritative_answer);
+    assert_eq!(res.header.rescode, ResultCode::NOERROR);
+    assert!(matches!(res.answers[0].rtype, RDataType::A(Some(ip)) if ip == Ipv4Addr::new(10, 0, 0, 3)));
+
+    // in zone cnames get followed
+    let res = ask(&zone, "www.internal.example", RDataType::A(None));
+    assert_eq!(res.answers.len(), 2);
+    assert!(matches!(res.answers[0].rtype, RDataType::CNAME(_)));
+    assert!(matches!(res.answers[1].rtype, RDataType::A(Some(_))));
+
+    // out of zone ones are left to the client
+    let res = ask(&zone, "outside.internal.example", RDataType::A(None));
+    assert_eq!(res.answers.len(), 1);
+    assert_eq!(res.header.rescode, ResultCode::NOERROR);
+
+    // asking for the cname itself doesnt follow it
+    let res = ask(&zone, "www.internal.example", RDataType::CNAME(None));
+    assert_eq!(res.answers.len(), 1);
+}
+
+#[test]
+fn nxdomain_and_nodata() {
+    let zone = zone();
+    let res = ask(&zone, "nope.internal.example", RDataType::A(None));
+    assert_eq!(res.header.rescode, ResultCode::NXDOMAIN);
+    assert!(res.header.authoritative_answer);
+    assert!(res.answers.is_empty());
+    assert!(matches!(res.authorities[0].rtype, RDataType::SOA(_)));
+    assert_eq!(res.authorities[0].ttl, Some(300));
+
+    let res = ask(&zone, "web.internal.example", RDataType::AAAA(None));
+    assert_eq!(res.header.rescode, ResultCode::NOERROR);
+    assert!(res.answers.is_empty());
+    assert!(matches!(res.authorities[0].rtype, RDataType::SOA(_)));
+
+    // names with only children exist, they just have no data
+    let res = ask(&zone, "empty.internal.example", RDataType::A(None));
+    assert_eq!(res.header.rescode, ResultCode::NOERROR);
+    assert!(res.answers.is_empty());
+}
+
+#[test]
+fn wildcards() {
+    let zone = zone();
+    let res = ask(&zone, "thing.apps.internal.example", RDataType::A(None));
+    assert_eq!(res.header.rescode, ResultCode::NOERROR);
+    assert_eq!(res.answers[0].domain.name(), Some("thing.apps.internal.example"));
+    assert!(matches!(res.answers[0].rtype, RDataType::A(Some(ip)) if ip == Ipv4Addr::new(10, 0, 0, 5)));
+
+    let res = ask(&zone, "thing.apps.internal.example", RDataType::MX(None));
+    assert_eq!(res.header.rescode, ResultCode::NOERROR);
+    assert!(res.answers.is_empty());
+
+    // the wildcard only covers names right under its parent's closest encloser
+    let res = ask(&zone, "a.b.deep.empty.internal.example", RDataType::A(None));
+    assert_eq!(res.header.rescode, ResultCode::NXDOMAIN);
+}
+
+#[test]
+fn delegations_get_referrals_with_glue() {
+    let zone = zone();
+    let res = ask(&zone, "host.sub.internal.example", RDataType::A(None));
+    assert!(!res.header.authoritative_answer);
+    assert_eq!(res.header.rescode, ResultCode::NOERROR);
+    assert!(res.answers.is_empty());
+    assert!(matches!(&res.authorities[0].rtype, RDataType::NS(Some(ns)) if ns == "ns.sub.internal.example"));
+    assert!(matches!(res.resources[0].rtype, RDataType::A(Some(ip)) if ip == Ipv4Addr::new(10, 0, 1, 1)));
+}
+
+#[test]
+fn dnssec_records_in_master_files() {
+    // the examples from RFC 4034 and RFC 5155
+    let zone = Zone::parse(r#"
+$TTL 86400
+$ORIGIN example.com.
+@       SOA ns1 hostmaster 1 2h 15m 2w 300
+        DNSKEY 256 3 5 ( AQPSKmynfzW4kyBv015MUG2DeIQ3
+                         Cbl+BBZH4b/0PY1kxkmvHjcZc8no
+                         kfzj31GajIQKY+5CptLr3buXA10h
+                         WqTkF7H6RfoRqXQeogmMHfpftf6z
+                         Mv1LyBUgia7za6ZEzOJBOztyvhjL
+                         742iU/TpPSEDhm2SNKLijfUppn1U
+                         aNvv4w== )
+        NSEC3PARAM 1 0 12 aabbccdd
+alfa    NSEC host.example.com. ( A MX RRSIG NSEC TYPE1234 )
+host    RRSIG A 5 3 86400 20030322173103 (
+                 20030220173103 2642 example.com.
+                 oJB1W6WNGv+ldvQ3WDG0MQkg5IEhjRip8WTr
+                 PYGv07h108dUKGMeDPKijVCHX3DDKdfb+v6o
+                 B9wfuh3DTJXUAfI/M0zmO/zz8bW0Rznl8O3t
+                 GNazPwQKkRN20XPXV6nwwfoXmJQbsLNrLfkG
+                 J5D6fwFm8nN+6pBzeDQfsS3Ap3o= )
+dskey   DS 60485 5 1 ( 2BB183AF5F22588179A53B0A98631FAD1A292118 )
+0p9mhaveqvm6t7vbl5lop2u3t2rp3tom NSEC3 1 1 12 aabbccdd (
+                 2t7b4g4vsa5smi47k61mv5bv1a22bojr MX DNSKEY NS SOA NSEC3PARAM RRSIG )
+"#, "example.com.").unwrap();
+
+    let text = |name: &str, num: u16| {
+        match &zone.records(name).iter().find(|r| r.rtype.to_num() == num).unwrap().rtype {
+            RDataType::DNSKEY(Some(d)) => d.to_string(),
+            RDataType::DS(Some(d)) => d.to_string(),
+            RDataType::RRSIG(Some(d)) => d.to_string(),
+            RDataType::NSEC(Some(d)) => d.to_string(),
+            RDataType::NSEC3(Some(d)) => d.to_string(),
+            RDataType::NSEC3PARAM(Some(d)) => d.to_string(),
+            other => panic!("{:?}", other),
+        }
+    };
+
+    match &zone.records("example.com").iter().find(|r| r.rtype.to_num() == 48).unwrap().rtype {
+        RDataType::DNSKEY(Some(key)) => {
+            assert_eq!((key.flags, key.protocol, key.algorithm), (256, 3, 5));
+            assert_eq!(key.public_key.len(), 130);
+        }
+        other => panic!("{:?}", other),
+    }
+    assert!(text("example.com", 48).ends_with("742iU/TpPSEDhm2SNKLijfUppn1UaNvv4w=="));
+    assert_eq!(text("example.com", 51), "1 0 12 AABBCCDD");
+    assert_eq!(text("alfa.example.com", 47), "host.example.com. A MX RRSIG NSEC TYPE1234");
+    assert!(text("host.example.com", 46).starts_with("A 5 3 86400 20030322173103 20030220173103 2642 example.com. oJB1W6WNGv+ldvQ3WDG0"));
+    assert_eq!(text("dskey.example.com", 43), "60485 5 1 2BB183AF5F22588179A53B0A98631FAD1A292118");
+    assert_eq!(
+        text("0p9mhaveqvm6t7vbl5lop2u3t2rp3tom.example.com", 50),
+        "1 1 12 AABBCCDD 2T7B4G4VSA5SMI47K61MV5BV1A22BOJR NS SOA MX RRSIG DNSKEY NSEC3PARAM",
+    );
+
+    assert!(Zone::parse("@ 300 SOA ns h 1 1 1 1 1\n@ 300 DS 1 2 3 xyz\n", "example.com.").is_err());
+    assert!(Zone::parse("@ 300 SOA ns h 1 1 1 1 1\n@ 300 RRSIG A 5 3 86400 20031322173103 20030220173103 2642 example.com. AAAA\n", "example.com.").is_err());
+}
+
+#[test]
+fn signed_cnames_keep_their_rrsig_and_nsec() {
+    let zone = Zone::parse(r#"
+$TTL 3600
+$ORIGIN example.com.
+@       SOA ns1 hostmaster 1 2h 15m 2w 300
+www     CNAME web
+        RRSIG CNAME 13 3 3600 20300101000000 20200101000000 12345 example.com. ( AAAA )
+        NSEC web.example.com. CNAME RRSIG NSEC
+        TYPE25 \# 4 01000303
+web     A   10.0.0.3
+"#, "example.com.").unwrap();
+    assert_eq!(zone.records("www.example.com").len(), 4);
+
+    let res = ask(&zone, "www.example.com", RDataType::A(None));
+    assert!(matches!(res.answers[0].rtype, RDataType::CNAME(_)));
+    assert!(matches!(res.answers[1].rtype, RDataType::A(Some(ip)) if ip == Ipv4Addr::new(10, 0, 0, 3)));
+}
+
+#[test]
+fn txt_escapes_are_bytes() {
+    let zone = Zone::parse(r#"
+@       3600 SOA ns1 hostmaster 1 2h 15m 2w 300
+bin     3600 TXT "\200\255" "caf\195\169" "\"\065"
+"#, "example.com.").unwrap();
+    let txt = match &zone.records("bin.example.com")[0].rtype {
+        RDataType::TXT(Some(txt)) => txt.clone(),
+        other => panic!("not a txt {:?}", other),
+    };
+    assert_eq!(txt, b"\x02\xc8\xff\x05caf\xc3\xa9\x02\"A");
+
+    // and they go out on the wire the same
+    let mut buf = DnsBuffer::from_bytes(&[]);
+    ask(&zone, "bin.example.com", RDataType::TXT(None)).write(&mut buf).unwrap();
+    buf.pos = 0;
+    assert!(matches!(&DnsPacket::from_buf(&mut buf).unwrap().answers[0].rtype, RDataType::TXT(Some(t)) if *t == txt));
+    assert!(Zone::parse("@ 60 SOA ns h 1 1 1 1 1\nbin 60 TXT \"\\256\"", "example.com.").is_err());
+}